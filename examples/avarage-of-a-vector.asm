@@ -34,4 +34,6 @@ LOOP:
 END:
     LODD SUM                # ac = *SUM
     DIVD VECTOR_SIZE        # ac = ac / *VECTOR_SIZE
-    PRINTLNAC               # print ac as a number with a newline
\ No newline at end of file
+    .ifndef BINARY
+    PRINTLNAC               # print ac as a number with a newline
+    .endif
\ No newline at end of file
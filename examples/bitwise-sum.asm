@@ -30,5 +30,7 @@ SUM_LOOP:
 
 END:
     LODD RESULT             # ac = *RESULT
+    .ifndef BINARY
     PRINTLNAC               # print ac as a number with a newline
+    .endif
     HALT                    # finishes the program
\ No newline at end of file
@@ -31,7 +31,9 @@ PRINT_AND_END:
     SWAP                    # sp = ac ; ac = sp
     LODL 0                  # ac = STRING[i]
     JZER END                # if ac == \0 goto END
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     LODD STRING_POINTER     # ac = *STRING_POINTER
     SUBD ONE                # ac = ac - 1
     STOD STRING_POINTER     # *STRING_POINTER = ac
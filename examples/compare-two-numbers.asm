@@ -19,5 +19,7 @@ NOT_EQUAL:
     JUMP PRINT_AND_END  # goto PRINT_AND_END
 PRINT_AND_END:
     LODD RESULT         # ac = *RESULT
+    .ifndef BINARY
     PRINTLNAC           # print ac as a number with a newline
+    .endif
     HALT                # finishes the program
\ No newline at end of file
@@ -7,11 +7,15 @@ ONE: .word 1
 .text
     LODD MAX
 LOOP:
+    .ifndef BINARY
     PRINTAC
+    .endif
     SUBD ONE
     STOD MAX
     LODD COMMA
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     LODD MAX
     JNEG END
     JUMP LOOP
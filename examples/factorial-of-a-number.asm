@@ -20,5 +20,7 @@ LOOP:
     JUMP LOOP               # goto LOOP
 END:
     LODD AUX                # ac = *AUX
+    .ifndef BINARY
     PRINTLNAC               # print ac as a number with a newline
+    .endif
     HALT                    # finishes the program
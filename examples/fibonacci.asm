@@ -22,9 +22,13 @@ FIBONACCI_LOOP:
     LODD TEMP           # ac = *TEMP
     STOD B              # *B = ac
      LODD A             # ac = *A
+     .ifndef BINARY
      PRINTAC            # print ac as a number
+     .endif
      LODD COMMA         # ac = *COMMA
+     .ifndef BINARY
      PRINTACCHAR        # print ac as a char
+     .endif
     JUMP FIBONACCI_LOOP # goto FIBONACCI_LOOP
 
 END:
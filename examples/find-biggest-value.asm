@@ -19,5 +19,7 @@ VAL1_IS_BIGGER:
 
 PRINT_AND_END:
     LODD BIGGER
+    .ifndef BINARY
     PRINTLNAC
+    .endif
     HALT
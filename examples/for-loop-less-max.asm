@@ -18,9 +18,13 @@ FOR_LOOP:
     SUBD I                  # ac = ac - *I
     JNEG END JZER END       # if ac < 0 goto END
       LODD I                # ac = *I
+      .ifndef BINARY
       PRINTAC               # print ac as a number
+      .endif
       LODD COMMA            # ac = *COMMA
+      .ifndef BINARY
       PRINTACCHAR           # print ac as a char
+      .endif
     LODD I                  # ac = *I
     ADDD INCREMENT          # ac = ac + *INCREMENT
     STOD I                  # *I = ac
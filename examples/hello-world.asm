@@ -8,7 +8,9 @@ MAIN:
 LOOP:
     LODL 0              # ac = *sp
     JZER END            # if ac == 0 goto END
+    .ifndef BINARY
     PRINTACCHAR         # print ac as a char
+    .endif
     DESP 1              # sp = sp - 1
     JUMP LOOP           # goto LOOP
 END:
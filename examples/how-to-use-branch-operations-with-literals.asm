@@ -12,35 +12,49 @@ NEWLINE: .ascii "\n"
     LOCO STRING     # operation at line 12
     SWAP            # operation at line 13
     LODL 0          # operation at line 14
+    .ifndef BINARY
     PRINTACCHAR     # operation at line 15
+    .endif
     
     LOCO STRING     # operation at line 17
     SUBD ONE        # [...]
     SWAP            
     LODL 0             
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
 
     LOCO STRING
     SUBD ONE SUBD ONE
     SWAP
     LODL 0  
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
 
     LOCO STRING
     SUBD ONE SUBD ONE SUBD ONE
     SWAP
     LODL 0  
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
 
     LOCO STRING
     SUBD ONE SUBD ONE SUBD ONE SUBD ONE
     SWAP
     LODL 0  
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     
     LODD NEWLINE
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
 
+    .ifndef BINARY
     SLEEPI 1000     # sleep thread for 1 second
+    .endif
 
     JUMP 12         # jump to operation to line 12
\ No newline at end of file
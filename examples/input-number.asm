@@ -5,16 +5,22 @@
     COUNTER: .word 0
     MAX: .word 5
 .text
+    .ifndef BINARY
     INPUTAC
+    .endif
     STOD MAX
 LOOP:
     LODD COUNTER
+    .ifndef BINARY
     PRINTAC
+    .endif
     ADDD ONE
     STOD COUNTER
     LODD COMMA
+    .ifndef BINARY
     PRINTACCHAR
     SLEEPD ONE
+    .endif
 
     LODD MAX
     SUBD COUNTER
@@ -7,7 +7,9 @@
     LOCO STR1 PUSH
     CALL PRINT_STRING
 
+    .ifndef BINARY
     INPUTSTRING BUFF
+    .endif
 
     LOCO STR2 PUSH
     CALL PRINT_STRING
@@ -27,7 +29,9 @@ PRINT_STRING:
     #[ ARG, RETN, AUX_ARG, CHAR ]
     LODL 0
     JZER PRINT_STRING_END
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     LOCO 1
     PUSH
     #[ ARG, RETN, AUX_ARG, CHAR, 1 ]
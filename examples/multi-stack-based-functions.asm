@@ -28,8 +28,14 @@
     LOCO RECURSIVE_FUNCTION_STR_3 PUSH
     CALL PRINT_STRING
     INSP 1
-    LODL 0 PRINTLNAC
-    LODD NEW_LINE PRINTACCHAR
+    LODL 0
+    .ifndef BINARY
+    PRINTLNAC
+    .endif
+    LODD NEW_LINE
+    .ifndef BINARY
+    PRINTACCHAR
+    .endif
     #
 
     #=== FIBONACCI ===
@@ -41,7 +47,9 @@
     CALL PRINT_STRING
     INSP 1
     LODL 0
+    .ifndef BINARY
     PRINTLNAC
+    .endif
     #
     
     HALT
@@ -58,7 +66,7 @@ FIBONACCI:
     PUSH # Push trash
     LOCO 3000 PUSH
     #
-  FIBONACCI_LOOP:
+  .loop:
     LODL 3
     ADDL 2
     STOL 1
@@ -71,14 +79,16 @@ FIBONACCI:
     INSP 1
     #
     LODL 2
+    .ifndef BINARY
     PRINTLNAC
+    .endif
 
     LODL 0
     SUBL 2
-    JNEG FIBONACCI_END
+    JNEG .end
 
-    JUMP FIBONACCI_LOOP
-  FIBONACCI_END:
+    JUMP .loop
+  .end:
     LODL 2
     INSP 4
     STOL 1
@@ -102,13 +112,15 @@ RECURSIVE_FUNCTION:
     #
         # [ARG, RTN, ARG+5, 1000, 5]
     LODL 2
+    .ifndef BINARY
     PRINTLNAC
+    .endif
     SUBL 1
     INSP 2
         # [ARG, RTN, ARG+5]
-    JPOS RECURSIVE_FUNCTION_REGRESS
+    JPOS .regress
     CALL RECURSIVE_FUNCTION
-  RECURSIVE_FUNCTION_REGRESS:
+  .regress:
         # [ARG, RTN, LAST_RESULT]
     LODL 0
     STOL 2
@@ -123,13 +135,15 @@ PRINT_STRING:
     DESP 1
     STOL 0
     #[ ARG, RETN, AUX_ARG ]
-  PRINT_STRING_LOOP:
+  .loop:
     LODL 0
     PSHI
     #[ ARG, RETN, AUX_ARG, CHAR ]
     LODL 0
-    JZER PRINT_STRING_END
+    JZER .end
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     LOCO 1
     PUSH
     #[ ARG, RETN, AUX_ARG, CHAR, 1 ]
@@ -137,7 +151,7 @@ PRINT_STRING:
     SUBL 0
     STOL 2
     INSP 2
-    JUMP PRINT_STRING_LOOP
-  PRINT_STRING_END:
+    JUMP .loop
+  .end:
     INSP 2
     RETN
\ No newline at end of file
@@ -18,5 +18,7 @@ ODD:
     JUMP END      # jump to END
 END:
     LODD RESULT   # load the result
+    .ifndef BINARY
     PRINTLNAC     # print the result
+    .endif
     HALT          # halt the program
\ No newline at end of file
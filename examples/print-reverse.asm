@@ -28,7 +28,9 @@ PRINT:
     SWAP                            # sp = ac ; ac = sp
     LODL 0                          # ac = string[i]
 
+    .ifndef BINARY
     PRINTACCHAR                     # print ac as a char
+    .endif
 
     LODD LAST_CHAR_POINTER          # ac = *LAST_CHAR_POINTER
     ADDD ONE                        # ac = ac + 1
@@ -39,7 +41,9 @@ PRINT_FIRST_CHAR:
     LODD STRING_PTR                 # ac = *STRING_PTR
     SWAP                            # sp = ac ; ac = sp
     LODL 0                          # ac = string[0]
+    .ifndef BINARY
     PRINTACCHAR                     # print ac as a char
+    .endif
     JUMP END                        # goto END
 END:
     HALT                            # finishes the program
\ No newline at end of file
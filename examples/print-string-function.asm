@@ -39,7 +39,9 @@ PRINT_STRING:
     LODL 0                          # ac = STRING[i]
 
     JZER PRINT_STRING_END           # if ac == 0 goto PRINT_STRING_END
+    .ifndef BINARY
     PRINTACCHAR                     # print ac as a char
+    .endif
     LODD PRINT_STRING_POINTER       # ac = *PRINT_STRING_POINTER
     SUBD ONE                        # ac = ac - 1
     STOD PRINT_STRING_POINTER       # *PRINT_STRING_POINTER = ac
@@ -23,9 +23,13 @@ LOOP:
     SWAP            # sp = ac ; ac = sp
     LODL 0          # ac = numbers[i]
 
+    .ifndef BINARY
     PRINTAC         # print ac as a number
+    .endif
     LODD COMMA      # ac = *COMMA
+    .ifndef BINARY
     PRINTACCHAR     # print ac as a char
+    .endif
     
     LODD AUX        # ac = *AUX
     SUBD ONE        # ac = ac - 1
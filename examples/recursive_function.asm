@@ -11,9 +11,14 @@
     LOCO 0 # this number is the function argument
     STOL 0 # store the function argument
     CALL RECURSIVE_FUNCTION
-    LODD NEW_LINE PRINTACCHAR
+    LODD NEW_LINE
+    .ifndef BINARY
+    PRINTACCHAR
+    .endif
     LODL -2
+    .ifndef BINARY
     PRINTLNAC
+    .endif
     HALT
 RECURSIVE_FUNCTION:
     DESP 1
@@ -24,7 +29,9 @@ RECURSIVE_FUNCTION:
     STOL 0
     # [ARG, RTN, ARG+INCR]
     LODL 0
+    .ifndef BINARY
     PRINTLNAC SLEEPI 100
+    .endif
     SUBD MAX
     JPOS RECURSIVE_FUNCTION_REGRESS
     CALL RECURSIVE_FUNCTION
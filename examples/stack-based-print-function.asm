@@ -27,7 +27,9 @@ PRINT_STRING:
     #[ ARG, RETN, AUX_ARG, CHAR ]
     LODL 0
     JZER PRINT_STRING_END
+    .ifndef BINARY
     PRINTACCHAR
+    .endif
     LOCO 1
     PUSH
     #[ ARG, RETN, AUX_ARG, CHAR, 1 ]
@@ -15,12 +15,16 @@ LOOP:
     LODD AUX            # ac = *AUX
     SWAP                # sp = ac ; ac = sp
     LODL 0              # ac = VECTOR[i]
+    .ifndef BINARY
     PRINTAC             # print ac as a number
+    .endif
     ADDD SUM            # ac = ac + SUM
     STOD SUM            # *SUM = ac
 
     LODD SPACE          # ac = *SPACE
+    .ifndef BINARY
     PRINTACCHAR         # print ac as a char
+    .endif
 
     LODD AUX            # ac = *AUX
     SUBD ONE            # ac = ac - 1
@@ -34,8 +38,12 @@ LOOP:
     JUMP LOOP           # goto LOOP
 END:
     LODD NEWLINE        # ac = *NEWLINE
+    .ifndef BINARY
     PRINTACCHAR         # print ac as a char
+    .endif
     LODD SUM            # ac = *SUM
+    .ifndef BINARY
     PRINTLNAC           # print ac as a number with a newline
+    .endif
     HALT                # finishes the program
     
@@ -25,7 +25,9 @@ ADD_TWO_NUMBERS:
 PRINT:
     # in this moment the stack is something like this: [ RESULT, RETURN_ADDRESS ]
     LODL 1                  # load the result to the accumulator
+    .ifndef BINARY
     PRINTLNAC               # print the accumulator as a number
+    .endif
     RETN                    # return to the address in the top of the stack
 
 END:
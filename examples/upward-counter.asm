@@ -7,12 +7,16 @@
 .text
 LOOP:
     LODD COUNTER
+    .ifndef BINARY
     PRINTAC
+    .endif
     ADDD ONE
     STOD COUNTER
     LODD COMMA
+    .ifndef BINARY
     PRINTACCHAR
     SLEEPD ONE
+    .endif
 
     LODD MAX
     SUBD COUNTER
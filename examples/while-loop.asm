@@ -11,9 +11,13 @@ WHILE_LOOP:
   LODD CONDITIONAL      # ac = *CONDITIONAL
   JZER BREAK            # if ac == 0 goto BREAK
     LODD I              # ac = *I
+    .ifndef BINARY
     PRINTACCHAR         # print ac as a char
+    .endif
     LODD COMMA          # ac = *COMMA
+    .ifndef BINARY
     PRINTACCHAR         # print ac as a char
+    .endif
   JUMP WHILE_LOOP       # goto WHILE_LOOP
 
 BREAK:
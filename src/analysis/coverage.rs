@@ -0,0 +1,212 @@
+use std::io::{self, Write};
+
+use crate::interpreter::io_device::IoDevice;
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::program::Program;
+use crate::interpreter::virtual_machine::VirtualMachine;
+
+/// Directions taken by a conditional branch instruction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+/// Records which instructions ran and which directions the conditional branches (JPOS, JZER, JNEG, JNZE) took.
+/// Counts add up over many runs of the same program.
+/// Like the `Tracer`, it needs the machine to record at least one instruction of history.
+pub struct Coverage {
+    instruction_hits: Vec<u64>, // indexed by instruction index
+    branches: Vec<BranchCoverage>, // indexed by instruction index, only used by conditional branches
+    last_instruction_count: u64,
+}
+
+impl Coverage {
+    pub fn new(program: &Program) -> Coverage {
+        Coverage {
+            instruction_hits: vec![0; program.instructions.len()],
+            branches: vec![BranchCoverage::default(); program.instructions.len()],
+            last_instruction_count: 0,
+        }
+    }
+
+    /// Must be called before running the program again with the same `Coverage`.
+    pub fn start_run(&mut self) {
+        self.last_instruction_count = 0;
+    }
+
+    /// Records the instruction executed by the last `step` of `vm`, if it executed one.
+    pub fn record<D: IoDevice>(&mut self, vm: &VirtualMachine<D>) {
+        if vm.instruction_count() == self.last_instruction_count {
+            return;
+        }
+        self.last_instruction_count = vm.instruction_count();
+
+        let record = match vm.history().last() {
+            Some(record) => record,
+            None => return,
+        };
+        let instruction = match vm.program().instructions.get(record.pc as usize) {
+            Some(instruction) => *instruction,
+            None => return,
+        };
+        self.instruction_hits[record.pc as usize] += 1;
+
+        let is_taken = match instruction.opcode {
+            Opcode::Jpos => record.ac > 0,
+            Opcode::Jzer => record.ac == 0,
+            Opcode::Jneg => record.ac < 0,
+            Opcode::Jnze => record.ac != 0,
+            _ => return,
+        };
+        let branch = &mut self.branches[record.pc as usize];
+        match is_taken {
+            true => branch.taken += 1,
+            false => branch.not_taken += 1,
+        }
+    }
+
+    pub fn instruction_hits(&self) -> &[u64] {
+        &self.instruction_hits
+    }
+
+    pub fn branch(&self, pc: u32) -> Option<BranchCoverage> {
+        self.branches.get(pc as usize).copied()
+    }
+
+    /// Writes an lcov tracefile: one `DA` record per line with instructions, with the hits of the
+    /// most executed instruction of the line, and two `BRDA` records (taken, not taken) per conditional branch.
+    /// `source_path` is the path of the assembled file, every `.include`d file has its own section.
+    pub fn write_lcov<W: Write>(&self, output: &mut W, source_path: &str, program: &Program) -> io::Result<()> {
+        for file in 0..program.source_map.file_paths().len().max(1) as u32 {
+            let path = match program.source_map.file_path(file) {
+                Some(path) => std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string()),
+                None => source_path.to_string(),
+            };
+            self.write_lcov_file(output, &path, program, file)?;
+        }
+        Ok(())
+    }
+
+    fn write_lcov_file<W: Write>(&self, output: &mut W, source_path: &str, program: &Program, file: u32) -> io::Result<()> {
+        writeln!(output, "TN:")?;
+        writeln!(output, "SF:{}", source_path)?;
+
+        let mut lines: Vec<(u32, u64)> = Vec::new(); // line, hits
+        let mut branches_found = 0;
+        let mut branches_hit = 0;
+        let mut branch_block = 0;
+        for (pc, instruction) in program.instructions.iter().enumerate().filter(|(_, instruction)| instruction.file == file) {
+            let hits = self.instruction_hits[pc];
+            match lines.last_mut() {
+                Some((line, line_hits)) if *line == instruction.line => {
+                    *line_hits = (*line_hits).max(hits);
+                },
+                _ => {
+                    lines.push((instruction.line, hits));
+                    branch_block = 0;
+                },
+            }
+
+            if matches!(instruction.opcode, Opcode::Jpos | Opcode::Jzer | Opcode::Jneg | Opcode::Jnze) {
+                let branch = self.branches[pc];
+                for (branch_number, taken) in [branch.taken, branch.not_taken].iter().enumerate() {
+                    let taken_text = match hits {
+                        0 => "-".to_string(),
+                        _ => taken.to_string(),
+                    };
+                    writeln!(output, "BRDA:{},{},{},{}", instruction.line, branch_block, branch_number, taken_text)?;
+                    branches_found += 1;
+                    if *taken > 0 {
+                        branches_hit += 1;
+                    }
+                }
+                branch_block += 1;
+            }
+        }
+        writeln!(output, "BRF:{}", branches_found)?;
+        writeln!(output, "BRH:{}", branches_hit)?;
+
+        for (line, hits) in lines.iter() {
+            writeln!(output, "DA:{},{}", line, hits)?;
+        }
+        writeln!(output, "LF:{}", lines.len())?;
+        writeln!(output, "LH:{}", lines.iter().filter(|(_, hits)| *hits > 0).count())?;
+        writeln!(output, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+    use crate::interpreter::io_device::BufferDevice;
+
+    const BRANCHES: &str = "
+.text
+    LOCO 1
+    JZER SKIP
+    LOCO 0
+    JZER SKIP
+    JNEG SKIP
+SKIP:
+    HALT
+";
+
+    fn run(program: &Program, coverage: &mut Coverage) {
+        let mut vm = VirtualMachine::with_io_device(program, BufferDevice::new(""));
+        vm.set_history_limits(1, DEFAULT_HISTORY_MEMORY_BUDGET);
+        coverage.start_run();
+        vm.run_with(|vm, _| coverage.record(vm)).unwrap();
+    }
+
+    fn lcov(coverage: &Coverage, program: &Program) -> String {
+        let mut output = Vec::new();
+        coverage.write_lcov(&mut output, "branches.asm", program).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn lcov_has_the_line_hits_and_both_directions_of_each_branch() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(BRANCHES).unwrap();
+        let mut coverage = Coverage::new(&program);
+        run(&program, &mut coverage);
+
+        assert_eq!(coverage.branch(1), Some(BranchCoverage { taken: 0, not_taken: 1 }));
+        assert_eq!(coverage.branch(3), Some(BranchCoverage { taken: 1, not_taken: 0 }));
+        assert_eq!(lcov(&coverage, &program), "\
+TN:
+SF:branches.asm
+BRDA:4,0,0,0
+BRDA:4,0,1,1
+BRDA:6,0,0,1
+BRDA:6,0,1,0
+BRDA:7,0,0,-
+BRDA:7,0,1,-
+BRF:6
+BRH:2
+DA:3,1
+DA:4,1
+DA:5,1
+DA:6,1
+DA:7,0
+DA:9,1
+LF:6
+LH:5
+end_of_record
+");
+    }
+
+    #[test]
+    fn counts_add_up_over_many_runs() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(BRANCHES).unwrap();
+        let mut coverage = Coverage::new(&program);
+        run(&program, &mut coverage);
+        run(&program, &mut coverage);
+
+        assert_eq!(coverage.instruction_hits(), [2, 2, 2, 2, 0, 2]);
+        assert_eq!(coverage.branch(3), Some(BranchCoverage { taken: 2, not_taken: 0 }));
+        assert!(lcov(&coverage, &program).contains("BRDA:4,0,1,2\n"));
+    }
+}
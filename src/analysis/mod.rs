@@ -0,0 +1,3 @@
+pub mod coverage;
+pub mod profile;
+pub mod trace;
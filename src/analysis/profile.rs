@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::interpreter::io_device::IoDevice;
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::program::Program;
+use crate::interpreter::virtual_machine::VirtualMachine;
+
+/// Name of the code outside of any called function.
+pub const ROOT_FUNCTION: &str = "main";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: u64,
+    pub inclusive: u64, // instructions executed by the function and the functions it called
+    pub exclusive: u64, // instructions executed by the function itself
+}
+
+/// Counts the executed instructions per source line and per function, following CALL and RETN.
+/// Functions are named by the label of their first instruction.
+/// Like the `Tracer`, it needs the machine to record at least one instruction of history.
+pub struct Profiler {
+    function_names: HashMap<u32, String>, // instruction index -> label
+    line_counts: HashMap<(u32, u32), u64>, // (file, line) -> instructions
+    functions: HashMap<String, FunctionProfile>,
+    folded_stacks: HashMap<String, u64>, // "main;FUNCTION;INNER" -> instructions
+    call_stack: Vec<String>,
+    last_instruction_count: u64,
+}
+
+impl Profiler {
+    pub fn new(program: &Program) -> Profiler {
+        let function_names = (0..program.instructions.len() as u32)
+            .filter_map(|pc| program.text_label_at(pc).map(|label| (pc, label)))
+            .collect::<HashMap<u32, String>>();
+        let mut functions = HashMap::new();
+        functions.insert(ROOT_FUNCTION.to_string(), FunctionProfile { name: ROOT_FUNCTION.to_string(), calls: 1, ..FunctionProfile::default() });
+        Profiler {
+            function_names,
+            line_counts: HashMap::new(),
+            functions,
+            folded_stacks: HashMap::new(),
+            call_stack: vec![ROOT_FUNCTION.to_string()],
+            last_instruction_count: 0,
+        }
+    }
+
+    /// Counts the instruction executed by the last `step` of `vm`, if it executed one.
+    pub fn record<D: IoDevice>(&mut self, vm: &VirtualMachine<D>) {
+        if vm.instruction_count() == self.last_instruction_count {
+            return;
+        }
+        self.last_instruction_count = vm.instruction_count();
+
+        let record = match vm.history().last() {
+            Some(record) => record,
+            None => return,
+        };
+        let instruction = match vm.program().instructions.get(record.pc as usize) {
+            Some(instruction) => *instruction,
+            None => return,
+        };
+
+        *self.line_counts.entry((instruction.file, instruction.line)).or_insert(0) += 1;
+        *self.folded_stacks.entry(self.call_stack.join(";")).or_insert(0) += 1;
+        for (i, name) in self.call_stack.iter().enumerate() {
+            // a recursive function is counted once in the inclusive count
+            if !self.call_stack[..i].contains(name) {
+                self.functions.get_mut(name).unwrap().inclusive += 1;
+            }
+        }
+        if let Some(name) = self.call_stack.last() {
+            self.functions.get_mut(name).unwrap().exclusive += 1;
+        }
+
+        match instruction.opcode {
+            Opcode::Call if vm.pc() as i64 == record.pc as i64 + instruction.arg as i64 => {
+                let name = self.function_name(vm);
+                let function = self.functions.entry(name.clone()).or_insert_with(|| FunctionProfile { name: name.clone(), ..FunctionProfile::default() });
+                function.calls += 1;
+                self.call_stack.push(name);
+            },
+            Opcode::Retn if self.call_stack.len() > 1 => {
+                self.call_stack.pop();
+            },
+            _ => {},
+        }
+    }
+
+    /// Instructions executed at each source line.
+    pub fn line_count(&self, line: u32) -> u64 {
+        self.file_line_count(0, line)
+    }
+
+    /// Instructions executed at each line of the file `file`, 0 being the assembled file.
+    pub fn file_line_count(&self, file: u32, line: u32) -> u64 {
+        self.line_counts.get(&(file, line)).copied().unwrap_or(0)
+    }
+
+    /// Functions sorted by inclusive count, the most expensive first.
+    pub fn functions(&self) -> Vec<&FunctionProfile> {
+        let mut functions = self.functions.values().collect::<Vec<&FunctionProfile>>();
+        functions.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    /// Table of the functions followed by the source listing annotated with the count of each line.
+    pub fn write_report<W: Write>(&self, output: &mut W, program: &Program) -> io::Result<()> {
+        writeln!(output, "======== Functions ========")?;
+        writeln!(output, "{:>10} {:>12} {:>12}  function", "calls", "inclusive", "exclusive")?;
+        for function in self.functions() {
+            writeln!(output, "{:>10} {:>12} {:>12}  {}", function.calls, function.inclusive, function.exclusive, function.name)?;
+        }
+
+        let source_map = &program.source_map;
+        for file in 0..source_map.file_paths().len() as u32 {
+            match source_map.file_path(file) {
+                Some(path) => writeln!(output, "======== Source: {} ========", path)?,
+                None => writeln!(output, "======== Source ========")?,
+            }
+            let lines_with_instructions = program.instructions.iter()
+                .filter(|instruction| instruction.file == file)
+                .map(|instruction| instruction.line)
+                .collect::<Vec<u32>>();
+            let mut line = 1;
+            while let Some(line_text) = source_map.file_line_text(file, line) {
+                let count = match lines_with_instructions.contains(&line) {
+                    true => self.file_line_count(file, line).to_string(),
+                    false => String::new(),
+                };
+                writeln!(output, "{:>10} {:>5}  {}", count, line, line_text)?;
+                line += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// One `main;FUNCTION;INNER count` line per call stack, the folded format read by flamegraph tools.
+    pub fn write_folded<W: Write>(&self, output: &mut W) -> io::Result<()> {
+        let mut stacks = self.folded_stacks.iter().collect::<Vec<(&String, &u64)>>();
+        stacks.sort();
+        for (stack, count) in stacks {
+            writeln!(output, "{} {}", stack, count)?;
+        }
+        Ok(())
+    }
+
+    /// Label of the function the last CALL jumped to, or its instruction index when there is no label.
+    fn function_name<D: IoDevice>(&self, vm: &VirtualMachine<D>) -> String {
+        self.function_names.get(&vm.pc()).cloned().unwrap_or_else(|| format!("pc_{}", vm.pc()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+    use crate::interpreter::io_device::BufferDevice;
+
+    const RECURSION: &str = "
+.data
+    ONE: .word 1
+.text
+    LOCO 3
+    CALL COUNT_DOWN
+    HALT
+COUNT_DOWN:
+    JZER DONE
+    SUBD ONE
+    CALL COUNT_DOWN
+DONE:
+    RETN
+";
+
+    fn profile(source: &str) -> (Program, Profiler) {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new(""));
+        vm.set_history_limits(1, DEFAULT_HISTORY_MEMORY_BUDGET);
+        let mut profiler = Profiler::new(&program);
+        vm.run_with(|vm, _| profiler.record(vm)).unwrap();
+        (program, profiler)
+    }
+
+    #[test]
+    fn recursive_calls_are_counted_once_in_the_inclusive_count() {
+        let (_, profiler) = profile(RECURSION);
+        let functions = profiler.functions().into_iter().cloned().collect::<Vec<FunctionProfile>>();
+        assert_eq!(functions, [
+            FunctionProfile { name: "main".to_string(), calls: 1, inclusive: 17, exclusive: 3 },
+            FunctionProfile { name: "COUNT_DOWN".to_string(), calls: 4, inclusive: 14, exclusive: 14 },
+        ]);
+        assert_eq!(profiler.line_count(9), 4); // JZER
+        assert_eq!(profiler.line_count(13), 4); // RETN
+        assert_eq!(profiler.line_count(12), 0); // DONE:
+    }
+
+    #[test]
+    fn folded_stacks_follow_the_calls() {
+        let (_, profiler) = profile(RECURSION);
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "\
+main 3
+main;COUNT_DOWN 4
+main;COUNT_DOWN;COUNT_DOWN 4
+main;COUNT_DOWN;COUNT_DOWN;COUNT_DOWN 4
+main;COUNT_DOWN;COUNT_DOWN;COUNT_DOWN;COUNT_DOWN 2
+");
+    }
+
+    #[test]
+    fn report_lists_the_functions_and_the_annotated_source() {
+        let (program, profiler) = profile(RECURSION);
+        let mut report = Vec::new();
+        profiler.write_report(&mut report, &program).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("         4           14           14  COUNT_DOWN\n"));
+        assert!(report.contains("         4    13      RETN\n"));
+        assert!(report.contains("\n               3      ONE: .word 1\n"));
+    }
+}
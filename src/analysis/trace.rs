@@ -0,0 +1,277 @@
+use std::io::{self, Write};
+
+use crate::interpreter::instruction::Instruction;
+use crate::interpreter::io_device::IoDevice;
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::program::Program;
+use crate::interpreter::virtual_machine::VirtualMachine;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+    Csv,
+}
+
+impl TraceFormat {
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(name: &str) -> Option<TraceFormat> {
+        match name {
+            "text" => Some(TraceFormat::Text),
+            "jsonl" | "json" => Some(TraceFormat::JsonLines),
+            "csv" => Some(TraceFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+/// One executed instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub step: u64, // starts at 1
+    pub pc: u32,
+    pub instruction: Instruction,
+    pub ac_before: i16,
+    pub sp_before: i16,
+    pub ac_after: i16,
+    pub sp_after: i16,
+    pub writes: Vec<(u32, i16)>, // address, value written
+}
+
+/// Writes a line for every executed instruction, optionally only for some source lines.
+/// The machine must record at least one instruction of history (`VirtualMachine::set_history_limits`),
+/// that is where the state before the instruction and the written words come from.
+pub struct Tracer<W: Write> {
+    output: W,
+    format: TraceFormat,
+    line_ranges: Vec<(u32, u32)>, // lines of the assembled file, no ranges means every line of every file
+    last_instruction_count: u64,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(output: W, format: TraceFormat) -> Tracer<W> {
+        Tracer {
+            output,
+            format,
+            line_ranges: Vec::new(),
+            last_instruction_count: 0,
+            error: None,
+        }
+    }
+
+    /// Traces only the lines from `first_line` to `last_line`, can be called many times.
+    pub fn add_line_range(&mut self, first_line: u32, last_line: u32) {
+        self.line_ranges.push((first_line, last_line));
+    }
+
+    /// Accepts `LINE`, `FIRST-LAST` or a .text label, which covers the lines up to the next label.
+    pub fn add_filter(&mut self, program: &Program, filter: &str) -> bool {
+        let range = match filter.split_once('-') {
+            Some((first, last)) => match (first.trim().parse::<u32>(), last.trim().parse::<u32>()) {
+                (Ok(first), Ok(last)) => Some((first, last)),
+                _ => None,
+            },
+            None => match filter.parse::<u32>() {
+                Ok(line) => Some((line, line)),
+                Err(_) => program.text_label_lines(filter),
+            },
+        };
+        match range {
+            Some((first_line, last_line)) => {
+                self.add_line_range(first_line, last_line);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Writes the header of the format, if it has one.
+    pub fn start(&mut self) {
+        if self.format == TraceFormat::Csv {
+            let header = "step,pc,line,col,opcode,arg,ac_before,sp_before,ac_after,sp_after,writes\n";
+            let result = self.output.write_all(header.as_bytes());
+            self.keep_error(result);
+        }
+    }
+
+    /// Traces the instruction executed by the last `step` of `vm`, if it executed one.
+    pub fn record<D: IoDevice>(&mut self, vm: &VirtualMachine<D>) {
+        if vm.instruction_count() == self.last_instruction_count {
+            return;
+        }
+        self.last_instruction_count = vm.instruction_count();
+
+        let record = match vm.history().last() {
+            Some(record) => record,
+            None => return,
+        };
+        let instruction = match vm.program().instructions.get(record.pc as usize) {
+            Some(instruction) => *instruction,
+            None => return,
+        };
+        if !self.is_traced(&instruction) {
+            return;
+        }
+
+        let entry = TraceEntry {
+            step: vm.instruction_count(),
+            pc: record.pc,
+            instruction,
+            ac_before: record.ac,
+            sp_before: record.sp,
+            ac_after: vm.ac(),
+            sp_after: vm.sp(),
+            writes: record.writes.iter().map(|write| (write.address, vm.stack()[write.address as usize])).collect(),
+        };
+        let line = self.format_entry(&entry, vm.program());
+        let result = self.output.write_all(line.as_bytes());
+        self.keep_error(result);
+    }
+
+    /// Flushes the output, returning the first error found while tracing.
+    pub fn finish(&mut self) -> io::Result<()> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        self.output.flush()
+    }
+
+    fn is_traced(&self, instruction: &Instruction) -> bool {
+        self.line_ranges.is_empty()
+        || (instruction.file == 0 && self.line_ranges.iter().any(|(first, last)| (*first..=*last).contains(&instruction.line)))
+    }
+
+    fn keep_error(&mut self, result: io::Result<()>) {
+        if let Err(error) = result {
+            self.error.get_or_insert(error);
+        }
+    }
+
+    fn format_entry(&self, entry: &TraceEntry, program: &Program) -> String {
+        let instruction = entry.instruction;
+        match self.format {
+            TraceFormat::Text => {
+                let writes = entry.writes.iter()
+                    .map(|(address, value)| format!(" | M[{}] = {}", address, value))
+                    .collect::<String>();
+                let file = program.source_map.file_path(instruction.file).map_or(String::new(), |path| format!("{}:", path));
+                format!(
+                    "#{} pc={} {}{}:{} {} | ac {} -> {} | sp {} -> {}{}\n",
+                    entry.step, entry.pc, file, instruction.line, instruction.col, format_instruction(entry.pc, &instruction, program),
+                    entry.ac_before, entry.ac_after, entry.sp_before, entry.sp_after, writes,
+                )
+            },
+            TraceFormat::JsonLines => {
+                let writes = entry.writes.iter()
+                    .map(|(address, value)| format!("{{\"address\":{},\"value\":{}}}", address, value))
+                    .collect::<Vec<String>>()
+                    .join(",");
+                format!(
+                    "{{\"step\":{},\"pc\":{},\"line\":{},\"col\":{},\"opcode\":\"{}\",\"arg\":{},\"ac_before\":{},\"sp_before\":{},\"ac_after\":{},\"sp_after\":{},\"writes\":[{}]}}\n",
+                    entry.step, entry.pc, instruction.line, instruction.col, instruction.opcode, instruction.arg,
+                    entry.ac_before, entry.sp_before, entry.ac_after, entry.sp_after, writes,
+                )
+            },
+            TraceFormat::Csv => {
+                let writes = entry.writes.iter()
+                    .map(|(address, value)| format!("{}={}", address, value))
+                    .collect::<Vec<String>>()
+                    .join(";");
+                format!(
+                    "{},{},{},{},{},{},{},{},{},{},{}\n",
+                    entry.step, entry.pc, instruction.line, instruction.col, instruction.opcode, instruction.arg,
+                    entry.ac_before, entry.sp_before, entry.ac_after, entry.sp_after, writes,
+                )
+            },
+        }
+    }
+}
+
+/// Mnemonic and argument, control flow arguments are shown as the source line they jump to.
+fn format_instruction(pc: u32, instruction: &Instruction, program: &Program) -> String {
+    if !Opcode::is_argumented(instruction.opcode) {
+        return instruction.opcode.to_string();
+    }
+    if Opcode::takes_line_argument(instruction.opcode) {
+        let target_pc = pc as i64 + instruction.arg as i64;
+        let target = u32::try_from(target_pc).ok().and_then(|target_pc| program.source_map.location(target_pc));
+        return match target {
+            Some(location) => match program.source_map.file_path(location.file) {
+                Some(path) => format!("{} -> {} line {}", instruction.opcode, path, location.line),
+                None => format!("{} -> line {}", instruction.opcode, location.line),
+            },
+            None => format!("{} -> end", instruction.opcode),
+        };
+    }
+    format!("{} {}", instruction.opcode, instruction.arg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+    use crate::interpreter::io_device::BufferDevice;
+
+    const SOURCE: &str = ".data\n    X: .word 0\n.text\n    LOCO 5\n    STOD X\nNEXT:\n    JUMP END\n    LOCO 1\nEND:\n    HALT\n";
+
+    fn trace(format: TraceFormat, filters: &[&str]) -> String {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(SOURCE).unwrap();
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new(""));
+        vm.set_history_limits(1, DEFAULT_HISTORY_MEMORY_BUDGET);
+        let mut tracer = Tracer::new(Vec::new(), format);
+        for filter in filters {
+            assert!(tracer.add_filter(&program, filter));
+        }
+        tracer.start();
+        vm.run_with(|vm, _| tracer.record(vm)).unwrap();
+        tracer.finish().unwrap();
+        String::from_utf8(tracer.output).unwrap()
+    }
+
+    #[test]
+    fn text_trace_shows_the_registers_and_writes() {
+        assert_eq!(trace(TraceFormat::Text, &[]), "\
+#1 pc=0 4:5 LOCO 5 | ac 0 -> 5 | sp 32767 -> 32767
+#2 pc=1 5:5 STOD 32767 | ac 5 -> 5 | sp 32767 -> 32767 | M[32767] = 5
+#3 pc=2 7:5 JUMP -> line 10 | ac 5 -> 5 | sp 32767 -> 32767
+#4 pc=4 10:5 HALT | ac 5 -> 5 | sp 32767 -> 32767
+");
+    }
+
+    #[test]
+    fn jsonl_and_csv_traces_have_one_line_per_step() {
+        let jsonl = trace(TraceFormat::JsonLines, &[]);
+        assert_eq!(jsonl.lines().count(), 4);
+        assert_eq!(
+            jsonl.lines().nth(1).unwrap(),
+            "{\"step\":2,\"pc\":1,\"line\":5,\"col\":5,\"opcode\":\"STOD\",\"arg\":32767,\"ac_before\":5,\"sp_before\":32767,\"ac_after\":5,\"sp_after\":32767,\"writes\":[{\"address\":32767,\"value\":5}]}",
+        );
+
+        assert_eq!(trace(TraceFormat::Csv, &[]), "\
+step,pc,line,col,opcode,arg,ac_before,sp_before,ac_after,sp_after,writes
+1,0,4,5,LOCO,5,0,32767,5,32767,
+2,1,5,5,STOD,32767,5,32767,5,32767,32767=5
+3,2,7,5,JUMP,2,5,32767,5,32767,
+4,4,10,5,HALT,0,5,32767,5,32767,
+");
+    }
+
+    #[test]
+    fn filters_keep_lines_ranges_and_labels() {
+        let steps = |filters: &[&str]| trace(TraceFormat::Csv, filters).lines().skip(1)
+            .map(|line| line.split(',').nth(2).unwrap().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(steps(&["4"]), ["4"]);
+        assert_eq!(steps(&["5-7"]), ["5", "7"]);
+        assert_eq!(steps(&["NEXT", "10"]), ["7", "10"]);
+
+        let program = Assembler::new(InterpreterMode::Execute).assemble(SOURCE).unwrap();
+        let mut tracer = Tracer::new(Vec::new(), TraceFormat::Text);
+        assert!(!tracer.add_filter(&program, "MISSING"));
+        assert!(!tracer.add_filter(&program, "1-x"));
+        assert_eq!(TraceFormat::from_str("json"), Some(TraceFormat::JsonLines));
+        assert_eq!(TraceFormat::from_str("xml"), None);
+    }
+}
@@ -0,0 +1,352 @@
+use std::io::{self, Write};
+
+use crate::interpreter::error::position_text;
+use crate::interpreter::io_device::{IoDevice, StdioDevice};
+use crate::interpreter::watchpoint::WatchKind;
+use crate::logkit;
+use super::session::{DebugSession, StopReason};
+
+const HELP: &str = "\
+Commands:
+  break <line|label>     (b)    stop before the instruction at a source line, file:line or label
+  delete <id>            (d)    remove a breakpoint
+  watch <label|addr>     (w)    stop after the label or address (or range a..b) is written
+  awatch <label|addr>           stop after the label or address is read or written
+  unwatch <id>                  remove a watchpoint
+  info breakpoints       (i b)  list the breakpoints
+  info watchpoints       (i w)  list the watchpoints
+  info stack|symbols|instructions
+  run                    (r)    restart the program and run until a breakpoint
+  step                   (s)    execute one instruction, entering CALLs
+  next                   (n)    execute one instruction, stepping over CALLs
+  finish                 (fin)  run until the current function returns
+  continue               (c)    run until a breakpoint or the end of the program
+  reverse-step           (rs)   undo the last instruction
+  reverse-continue       (rc)   undo instructions until a breakpoint
+  print <expr>           (p)    print ac, sp, pc, M[addr], M[sp+n], a label or an expression
+  list                   (l)    show the source around the current line
+  help                   (h)
+  quit                   (q)
+An empty line repeats the last command.";
+
+/// Number of words shown when printing a data label with many words.
+const MAX_PRINTED_WORDS: u32 = 16;
+
+/// Interactive gdb-like command line on top of a `DebugSession`, reading the commands from the standard input.
+pub struct Console<D: IoDevice = StdioDevice> {
+    session: DebugSession<D>,
+    last_command: String,
+}
+
+impl<D: IoDevice> Console<D> {
+    pub fn new(session: DebugSession<D>) -> Console<D> {
+        Console {
+            session,
+            last_command: String::new(),
+        }
+    }
+
+    pub fn session(&self) -> &DebugSession<D> {
+        &self.session
+    }
+
+    /// Reads and executes commands until `quit` or the end of the input.
+    pub fn run(&mut self) {
+        logkit::message("Iguana debugger, type 'help' for the list of commands.");
+        self.show_current_line();
+        loop {
+            print!("(iguana) ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            match io::stdin().read_line(&mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {},
+            }
+            let command = match line.trim() {
+                "" => self.last_command.clone(),
+                command => command.to_string(),
+            };
+            self.last_command = command.clone();
+            if !self.execute_command(&command) {
+                break;
+            }
+        }
+    }
+
+    /// Executes one command, returns `false` when the debugger should quit.
+    pub fn execute_command(&mut self, command: &str) -> bool {
+        let (name, argument) = match command.trim().split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (command.trim(), ""),
+        };
+        match name {
+            "" => {},
+            "break" | "b" => self.break_command(argument),
+            "delete" | "d" => self.delete_command(argument),
+            "watch" | "w" => self.watch_command(argument, WatchKind::Write),
+            "awatch" => self.watch_command(argument, WatchKind::Access),
+            "unwatch" => self.unwatch_command(argument),
+            "info" | "i" => self.info_command(argument),
+            "run" | "r" => {
+                self.session.restart();
+                let reason = self.session.continue_execution();
+                self.show_stop(reason);
+            },
+            "step" | "s" => {
+                let reason = self.session.step();
+                self.show_stop(reason);
+            },
+            "next" | "n" => {
+                let reason = self.session.step_over();
+                self.show_stop(reason);
+            },
+            "finish" | "fin" => {
+                let reason = self.session.step_out();
+                self.show_stop(reason);
+            },
+            "continue" | "c" => {
+                let reason = self.session.continue_execution();
+                self.show_stop(reason);
+            },
+            "reverse-step" | "rs" => {
+                let reason = self.session.reverse_step();
+                self.show_stop(reason);
+            },
+            "reverse-continue" | "rc" => {
+                let reason = self.session.reverse_continue();
+                self.show_stop(reason);
+            },
+            "print" | "p" => self.print_command(argument),
+            "list" | "l" => self.list_command(),
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return false,
+            _ => println!("Unknown command '{}', type 'help' for the list of commands.", name),
+        }
+        true
+    }
+
+    fn break_command(&mut self, location: &str) {
+        if location.is_empty() {
+            println!("Usage: break <line|label>");
+            return;
+        }
+        let (id, pc, line) = match self.session.add_breakpoint(location) {
+            Some(breakpoint) => (breakpoint.id, breakpoint.pc, breakpoint.line),
+            None => {
+                println!("No instruction at '{}'.", location);
+                return;
+            }
+        };
+        let source_map = &self.session.vm().program().source_map;
+        match source_map.location(pc).and_then(|location| source_map.file_path(location.file)) {
+            Some(path) => println!("Breakpoint {} at {} line {}", id, path, line),
+            None => println!("Breakpoint {} at line {}", id, line),
+        }
+    }
+
+    fn delete_command(&mut self, argument: &str) {
+        match argument.parse::<u32>() {
+            Ok(id) if self.session.remove_breakpoint(id) => println!("Deleted breakpoint {}", id),
+            Ok(id) => println!("No breakpoint number {}.", id),
+            Err(_) => println!("Usage: delete <id>"),
+        }
+    }
+
+    fn watch_command(&mut self, target: &str, kind: WatchKind) {
+        if target.is_empty() {
+            println!("Usage: watch <label|address|first..last>");
+            return;
+        }
+        match self.session.vm().program().resolve_address_range(target) {
+            Some((first_address, last_address)) => {
+                let id = self.session.vm_mut().add_watchpoint(first_address, last_address, kind);
+                println!("Watchpoint {} at M[{}..{}]", id, first_address, last_address);
+            },
+            None => println!("No data label or address '{}'.", target),
+        }
+    }
+
+    fn unwatch_command(&mut self, argument: &str) {
+        match argument.parse::<u32>() {
+            Ok(id) if self.session.vm_mut().remove_watchpoint(id) => println!("Deleted watchpoint {}", id),
+            Ok(id) => println!("No watchpoint number {}.", id),
+            Err(_) => println!("Usage: unwatch <id>"),
+        }
+    }
+
+    fn info_command(&self, argument: &str) {
+        match argument {
+            "breakpoints" | "b" => {
+                if self.session.breakpoints().is_empty() {
+                    println!("No breakpoints.");
+                }
+                for breakpoint in self.session.breakpoints() {
+                    println!("{:>3}  line {:<5} {}", breakpoint.id, breakpoint.line, breakpoint.location);
+                }
+            },
+            "watchpoints" | "w" => {
+                if self.session.vm().watchpoints().is_empty() {
+                    println!("No watchpoints.");
+                }
+                for watchpoint in self.session.vm().watchpoints() {
+                    let kind = match watchpoint.kind {
+                        WatchKind::Write => "write",
+                        WatchKind::Access => "read/write",
+                    };
+                    println!("{:>3}  M[{}..{}] {}", watchpoint.id, watchpoint.first_address, watchpoint.last_address, kind);
+                }
+            },
+            "stack" => self.print_stack(),
+            "symbols" => self.print_symbol_table(),
+            "instructions" => self.print_instructions(),
+            _ => println!("Usage: info breakpoints|watchpoints|stack|symbols|instructions"),
+        }
+    }
+
+    fn print_stack(&self) {
+        let vm = self.session.vm();
+        println!("======== Stack ========");
+        for i in ((vm.sp() as usize) .. vm.stack().len()).rev() {
+            println!("Stack[{}]: {} --- {}", i, vm.stack()[i], vm.stack()[i] as u8 as char);
+        }
+        println!("=======================");
+    }
+
+    fn print_symbol_table(&self) {
+        println!("======== Symbol Table ========");
+        for (i, (label, address)) in self.session.vm().program().symbol_table.iter().enumerate() {
+            println!("{} --> {} :: {:?}", i, label, address);
+        }
+        println!("=============================");
+    }
+
+    fn print_instructions(&self) {
+        println!("======== Memory ========");
+        for (i, instruction) in self.session.vm().program().instructions.iter().enumerate() {
+            println!("{} --> {:?}", i, instruction);
+        }
+        println!("=======================");
+    }
+
+    fn print_command(&self, argument: &str) {
+        let vm = self.session.vm();
+        let program = vm.program();
+        if argument.is_empty() {
+            println!("Usage: print <expr>");
+            return;
+        }
+
+        if let Some(address_expression) = argument.strip_prefix("M[").or_else(|| argument.strip_prefix("m[")).and_then(|rest| rest.strip_suffix(']')) {
+            match self.session.evaluate(address_expression) {
+                Ok(address) => match vm.stack().get(address as usize).filter(|_| address >= 0) {
+                    Some(value) => println!("M[{}] = {}", address, value),
+                    None => println!("Address {} out of stack bounds", address),
+                },
+                Err(message) => println!("{}", message),
+            }
+            return;
+        }
+
+        if let Some(symbol) = program.data_symbol(argument) {
+            let addresses = (symbol.last_address()..=symbol.address).rev().take(MAX_PRINTED_WORDS as usize);
+            let values = addresses.map(|address| vm.stack()[address as usize].to_string()).collect::<Vec<String>>();
+            if symbol.words <= 1 {
+                println!("{} = M[{}] = {}", symbol.name, symbol.address, values.join(", "));
+            } else {
+                let more = if symbol.words > MAX_PRINTED_WORDS { ", ..." } else { "" };
+                println!("{} = M[{}..{}] = [{}{}]", symbol.name, symbol.address, symbol.last_address(), values.join(", "), more);
+            }
+            return;
+        }
+        if let Some(line) = program.text_label_line(argument) {
+            println!("{} = line {}", argument, line);
+            return;
+        }
+
+        match argument.to_lowercase().as_str() {
+            "ac" => println!("ac = {}", vm.ac()),
+            "sp" => println!("sp = {}", vm.sp()),
+            "pc" => match vm.current_line() {
+                Some(line) => println!("pc = {} (line {})", vm.pc(), line),
+                None => println!("pc = {}", vm.pc()),
+            },
+            _ => match self.session.evaluate(argument) {
+                Ok(value) => println!("{} = {}", argument, value),
+                Err(message) => println!("{}", message),
+            },
+        }
+    }
+
+    fn list_command(&self) {
+        let program = self.session.vm().program();
+        let source_map = &program.source_map;
+        let current = match source_map.location(self.session.vm().pc()) {
+            Some(location) => location,
+            None => {
+                println!("The program is not running.");
+                return;
+            }
+        };
+        if let Some(path) = source_map.file_path(current.file) {
+            println!("{}:", path);
+        }
+        let first_line = current.line.saturating_sub(3).max(1);
+        for line in first_line..=current.line + 3 {
+            let line_text = match source_map.file_line_text(current.file, line) {
+                Some(line_text) => line_text,
+                None => break,
+            };
+            let has_breakpoint = self.session.breakpoints().iter()
+                .filter_map(|breakpoint| source_map.location(breakpoint.pc))
+                .any(|location| location.file == current.file && location.line == line);
+            let marker = if line == current.line {
+                "=>"
+            } else if has_breakpoint {
+                "b "
+            } else {
+                "  "
+            };
+            println!("{} {:>4}  {}", marker, line, line_text);
+        }
+    }
+
+    fn show_stop(&mut self, reason: StopReason) {
+        for hit in self.session.vm_mut().take_watch_hits() {
+            let program = self.session.vm().program();
+            println!("{} {}", hit.describe(program), position_text(program.source_map.file_path(hit.file), hit.line, 0));
+        }
+        match reason {
+            StopReason::Step | StopReason::Watchpoint | StopReason::Interrupted => self.show_current_line(),
+            StopReason::Breakpoint(id) => {
+                println!("Breakpoint {}", id);
+                self.show_current_line();
+            },
+            StopReason::Halted => println!("Program finished with exit code {}.", self.session.vm().exit_code()),
+            StopReason::Trapped(error) => {
+                logkit::iguana_error_message(&error);
+                self.show_current_line();
+            },
+            StopReason::WaitingForInput => println!("The program is waiting for input."),
+            StopReason::HistoryStart => {
+                println!("No more reverse execution history.");
+                self.show_current_line();
+            },
+        }
+    }
+
+    fn show_current_line(&self) {
+        let vm = self.session.vm();
+        let source_map = &vm.program().source_map;
+        match source_map.location(vm.pc()) {
+            Some(location) => {
+                let line_text = source_map.file_line_text(location.file, location.line).unwrap_or("").trim();
+                match source_map.file_path(location.file) {
+                    Some(path) => println!("{}:{:>4}  {}", path, location.line, line_text),
+                    None => println!("{:>4}  {}", location.line, line_text),
+                }
+            },
+            None => println!("The program is not running."),
+        }
+    }
+}
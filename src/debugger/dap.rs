@@ -0,0 +1,458 @@
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::interpreter::assembler::{Assembler, InterpreterMode};
+use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+use crate::interpreter::io_device::BufferDevice;
+use crate::interpreter::program::{MemoryFill, Program};
+use crate::interpreter::virtual_machine::VirtualMachine;
+use super::session::{DebugSession, StopReason, DEFAULT_HISTORY_LENGTH};
+
+const THREAD_ID: i64 = 1;
+
+// variablesReference values, the words of the i-th data label use DATA_SYMBOL_REFERENCE + i
+const REGISTERS_REFERENCE: i64 = 1;
+const DATA_REFERENCE: i64 = 2;
+const DATA_SYMBOL_REFERENCE: i64 = 1000;
+
+/// Debug Adapter Protocol server, reading requests from `reader` and writing responses and events to `writer`.
+/// The program's output is sent as `output` events, its input is given by the `input` launch argument
+/// or by evaluating `input <text>` in the debug console.
+pub struct DapServer<R: BufRead, W: Write> {
+    reader: R,
+    writer: W,
+    seq: i64,
+    session: Option<DebugSession<BufferDevice>>,
+    source_path: String,
+    stop_on_entry: bool,
+}
+
+impl<R: BufRead, W: Write> DapServer<R, W> {
+    pub fn new(reader: R, writer: W) -> DapServer<R, W> {
+        DapServer {
+            reader,
+            writer,
+            seq: 1,
+            session: None,
+            source_path: String::new(),
+            stop_on_entry: false,
+        }
+    }
+
+    /// Serves requests until `disconnect` or the end of the input.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(request) = read_message(&mut self.reader)? {
+            let command = request["command"].as_str().unwrap_or("").to_string();
+            let arguments = request["arguments"].clone();
+            let result = self.handle_request(&command, &arguments);
+
+            let request_seq = request["seq"].as_i64().unwrap_or(0);
+            match result {
+                Ok(body) => self.send_response(request_seq, &command, true, None, body)?,
+                Err(message) => self.send_response(request_seq, &command, false, Some(message), Value::Null)?,
+            }
+            self.after_request(&command)?;
+            if command == "disconnect" {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Body of the response to a request, or the error message.
+    fn handle_request(&mut self, command: &str, arguments: &Value) -> Result<Value, String> {
+        match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsStepBack": true,
+                "supportsEvaluateForHovers": true,
+            })),
+            "launch" => self.launch(arguments).map(|_| Value::Null),
+            "setBreakpoints" => self.set_breakpoints(arguments),
+            "setExceptionBreakpoints" | "configurationDone" | "disconnect" | "terminate" => Ok(Value::Null),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "main" }] })),
+            "stackTrace" => self.stack_trace(),
+            "scopes" => Ok(json!({ "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REFERENCE, "expensive": false },
+                { "name": "Data", "variablesReference": DATA_REFERENCE, "expensive": false },
+            ] })),
+            "variables" => self.variables(arguments),
+            "evaluate" => self.evaluate(arguments),
+            "continue" => Ok(json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" => Ok(Value::Null),
+            _ => Err(format!("Unsupported request '{}'.", command)),
+        }
+    }
+
+    /// Runs the execution requests after their response, as the protocol expects the events to follow it.
+    fn after_request(&mut self, command: &str) -> io::Result<()> {
+        if command == "initialize" {
+            return self.send_event("initialized", Value::Null);
+        }
+        let session = match self.session.as_mut() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
+        let reason = match command {
+            "configurationDone" if self.stop_on_entry => return self.send_stopped("entry", None),
+            "configurationDone" | "continue" => session.continue_execution(),
+            "next" => session.step_over(),
+            "stepIn" => session.step(),
+            "stepOut" => session.step_out(),
+            "stepBack" => session.reverse_step(),
+            "reverseContinue" => session.reverse_continue(),
+            _ => return Ok(()),
+        };
+        self.send_output()?;
+
+        match reason {
+            StopReason::Step | StopReason::HistoryStart => self.send_stopped("step", None),
+            StopReason::Breakpoint(_) => self.send_stopped("breakpoint", None),
+            StopReason::Watchpoint => self.send_stopped("data breakpoint", None),
+            StopReason::Interrupted => self.send_stopped("pause", None),
+            StopReason::WaitingForInput => self.send_stopped("pause", Some("Waiting for input, evaluate 'input <text>' to give it.".to_string())),
+            StopReason::Trapped(error) => self.send_stopped("exception", Some(error.to_string())),
+            StopReason::Halted => {
+                let exit_code = self.session.as_ref().map_or(0, |session| session.vm().exit_code());
+                self.send_event("exited", json!({ "exitCode": exit_code }))?;
+                self.send_event("terminated", Value::Null)
+            },
+        }
+    }
+
+    fn launch(&mut self, arguments: &Value) -> Result<(), String> {
+        let path = arguments["program"].as_str().ok_or("Missing the 'program' launch argument.")?;
+        let mut assembler = Assembler::new(InterpreterMode::Execute);
+        for (name, value) in arguments["symbols"].as_object().into_iter().flatten() {
+            let value = value.as_i64().and_then(|value| i16::try_from(value).ok()).ok_or(format!("Invalid value for the symbol '{}', expected a 16-bit integer.", name))?;
+            assembler.define(name, value);
+        }
+        let program = assembler.assemble_file(path).map_err(|error| error.to_string())?;
+
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new(arguments["input"].as_str().unwrap_or("")));
+        if let Some(seed) = arguments["seed"].as_u64() {
+            vm.set_memory_fill(MemoryFill::Random(Some(seed)));
+        }
+        vm.set_history_limits(DEFAULT_HISTORY_LENGTH, DEFAULT_HISTORY_MEMORY_BUDGET);
+
+        self.source_path = std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string());
+        self.stop_on_entry = arguments["stopOnEntry"].as_bool().unwrap_or(false);
+        self.session = Some(DebugSession::new(vm));
+        Ok(())
+    }
+
+    /// Replaces the breakpoints of one source file, the program or one of the files it includes.
+    fn set_breakpoints(&mut self, arguments: &Value) -> Result<Value, String> {
+        let file = arguments["source"]["path"].as_str().map_or(Some(0), |path| self.source_file(path));
+        let session = self.session.as_mut().ok_or("The program is not launched.")?;
+        let source_map = &session.vm().program().source_map;
+        let ids = session.breakpoints().iter()
+            .filter(|breakpoint| source_map.location(breakpoint.pc).map(|location| location.file) == file)
+            .map(|breakpoint| breakpoint.id)
+            .collect::<Vec<u32>>();
+        let requested_lines = arguments["breakpoints"].as_array().unwrap_or(&Vec::new()).iter()
+            .map(|requested| requested["line"].as_u64().unwrap_or(0))
+            .map(|line| (line, file.and_then(|file| source_map.file_instruction_index_by_line(file, line as u32))))
+            .collect::<Vec<(u64, Option<u32>)>>();
+        for id in ids {
+            session.remove_breakpoint(id);
+        }
+
+        let mut breakpoints = Vec::new();
+        for (line, pc) in requested_lines {
+            match pc.and_then(|pc| session.add_breakpoint_at_pc(pc)) {
+                Some(breakpoint) => breakpoints.push(json!({ "id": breakpoint.id, "verified": true, "line": breakpoint.line })),
+                None => breakpoints.push(json!({ "verified": false, "line": line, "message": "No instruction at or after this line." })),
+            }
+        }
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    /// Id of the file at `path`, 0 for the program itself.
+    fn source_file(&self, path: &str) -> Option<u32> {
+        let session = self.session.as_ref()?;
+        let canonical = |path: &str| std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string());
+        let path = canonical(path);
+        if path == self.source_path {
+            return Some(0);
+        }
+        session.vm().program().source_map.file_paths().iter()
+            .position(|file_path| !file_path.is_empty() && canonical(file_path) == path)
+            .map(|file| file as u32)
+    }
+
+    /// Path of a source file for the editor, `source_path` for the program itself.
+    fn source_path(&self, program: &Program, file: u32) -> String {
+        match program.source_map.file_path(file) {
+            Some(path) => std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string()),
+            None => self.source_path.clone(),
+        }
+    }
+
+    /// The current instruction, then the CALL of each frame of the shadow call stack, innermost first.
+    fn stack_trace(&self) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or("The program is not launched.")?;
+        let vm = session.vm();
+        let program = vm.program();
+        let call_stack = vm.call_stack();
+
+        let mut frames = Vec::new();
+        let mut pc = vm.pc();
+        for depth in (0..=call_stack.len()).rev() {
+            let name = match depth {
+                0 => "main".to_string(),
+                _ => program.function_name(call_stack[depth - 1].target_pc),
+            };
+            let location = program.source_map.location(pc);
+            frames.push(json!({
+                "id": depth,
+                "name": name,
+                "source": { "path": location.map_or(self.source_path.clone(), |location| self.source_path(program, location.file)) },
+                "line": location.map_or(0, |location| location.line),
+                "column": location.map_or(0, |location| location.col.max(1)),
+            }));
+            if depth > 0 {
+                pc = call_stack[depth - 1].call_pc;
+            }
+        }
+        Ok(json!({ "stackFrames": frames, "totalFrames": frames.len() }))
+    }
+
+    fn variables(&self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session.as_ref().ok_or("The program is not launched.")?;
+        let vm = session.vm();
+        let program = vm.program();
+        let reference = arguments["variablesReference"].as_i64().unwrap_or(0);
+
+        let variables = match reference {
+            REGISTERS_REFERENCE => vec![
+                variable("ac", vm.ac().to_string(), 0),
+                variable("sp", vm.sp().to_string(), 0),
+                variable("pc", vm.pc().to_string(), 0),
+            ],
+            DATA_REFERENCE => program.data_symbols.iter().enumerate().map(|(i, symbol)| {
+                match symbol.words {
+                    1 => variable(&symbol.name, vm.stack()[symbol.address as usize].to_string(), 0),
+                    words => variable(&symbol.name, format!("M[{}] ({} words)", symbol.address, words), DATA_SYMBOL_REFERENCE + i as i64),
+                }
+            }).collect(),
+            _ => match program.data_symbols.get((reference - DATA_SYMBOL_REFERENCE).max(0) as usize) {
+                Some(symbol) if reference >= DATA_SYMBOL_REFERENCE => (0..symbol.words).map(|offset| {
+                    let address = symbol.address - offset;
+                    variable(&format!("M[{}]", address), vm.stack()[address as usize].to_string(), 0)
+                }).collect(),
+                _ => Vec::new(),
+            },
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    /// `input <text>` gives a line of input to the program, anything else is evaluated like the debugger's print.
+    fn evaluate(&mut self, arguments: &Value) -> Result<Value, String> {
+        let session = self.session.as_mut().ok_or("The program is not launched.")?;
+        let expression = arguments["expression"].as_str().unwrap_or("").trim();
+
+        if let Some(input) = expression.strip_prefix("input ") {
+            session.vm_mut().io_device_mut().push_input(input);
+            return Ok(json!({ "result": format!("Input '{}' queued.", input), "variablesReference": 0 }));
+        }
+        let result = match expression.strip_prefix("M[").and_then(|rest| rest.strip_suffix(']')) {
+            Some(address_expression) => {
+                let address = session.evaluate(address_expression)?;
+                match session.vm().stack().get(address as usize).filter(|_| address >= 0) {
+                    Some(value) => value.to_string(),
+                    None => return Err(format!("Address {} out of stack bounds", address)),
+                }
+            },
+            None => session.evaluate(expression)?.to_string(),
+        };
+        Ok(json!({ "result": result, "variablesReference": 0 }))
+    }
+
+    fn send_output(&mut self) -> io::Result<()> {
+        let output = match self.session.as_mut() {
+            Some(session) => session.vm_mut().io_device_mut().take_output(),
+            None => return Ok(()),
+        };
+        if output.is_empty() {
+            return Ok(());
+        }
+        self.send_event("output", json!({ "category": "stdout", "output": output }))
+    }
+
+    fn send_stopped(&mut self, reason: &str, text: Option<String>) -> io::Result<()> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["text"] = json!(text);
+            body["description"] = json!(text);
+        }
+        self.send_event("stopped", body)
+    }
+
+    fn send_response(&mut self, request_seq: i64, command: &str, success: bool, message: Option<String>, body: Value) -> io::Result<()> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request_seq,
+            "command": command,
+            "success": success,
+        });
+        if let Some(message) = message {
+            response["message"] = json!(message);
+        }
+        if !body.is_null() {
+            response["body"] = body;
+        }
+        self.send_message(response)
+    }
+
+    fn send_event(&mut self, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send_message(message)
+    }
+
+    fn send_message(&mut self, mut message: Value) -> io::Result<()> {
+        message["seq"] = json!(self.seq);
+        self.seq += 1;
+        write_message(&mut self.writer, &message)
+    }
+}
+
+/// Reads the next `Content-Length` framed message, `None` at the end of the input.
+/// The Debug Adapter and the Language Server protocols share this framing.
+pub(crate) fn read_message<R: BufRead>(reader: &mut R) -> io::Result<Option<Value>> {
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            if content_length.is_some() {
+                break;
+            }
+            continue;
+        }
+        if let Some(length) = header.strip_prefix("Content-Length:") {
+            content_length = length.trim().parse::<usize>().ok();
+        }
+    }
+
+    let mut content = vec![0; content_length.unwrap_or(0)];
+    reader.read_exact(&mut content)?;
+    serde_json::from_slice(&content).map(Some).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+pub(crate) fn write_message<W: Write>(writer: &mut W, message: &Value) -> io::Result<()> {
+    let content = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", content.len(), content)?;
+    writer.flush()
+}
+
+fn variable(name: &str, value: String, variables_reference: i64) -> Value {
+    json!({ "name": name, "value": value, "variablesReference": variables_reference })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const PROGRAM: &str = ".data
+    X: .word 5
+.text
+    LOCO 7
+    PRINTLNAC
+    STOD X
+    HALT
+";
+
+    /// Serves `requests` for `source`, saved to a temporary file, and returns every message sent back.
+    fn serve(name: &str, source: &str, requests: &[Value]) -> Vec<Value> {
+        let path = std::env::temp_dir().join(format!("iguana-dap-{}-{}.asm", std::process::id(), name));
+        std::fs::write(&path, source).unwrap();
+
+        let mut input = Vec::new();
+        for (seq, request) in requests.iter().enumerate() {
+            let mut request = request.clone();
+            request["seq"] = json!(seq + 1);
+            request["type"] = json!("request");
+            if request["command"] == "launch" {
+                request["arguments"]["program"] = json!(path.display().to_string());
+            }
+            write_message(&mut input, &request).unwrap();
+        }
+        let mut output = Vec::new();
+        DapServer::new(Cursor::new(input), &mut output).run().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut reader).unwrap() {
+            messages.push(message);
+        }
+        messages
+    }
+
+    /// `command` of the responses and `event` of the events, in order.
+    fn kinds(messages: &[Value]) -> Vec<String> {
+        messages.iter()
+            .map(|message| message["command"].as_str().or(message["event"].as_str()).unwrap_or("").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn runs_to_a_breakpoint_and_shows_the_state() {
+        let messages = serve("breakpoint", PROGRAM, &[
+            json!({ "command": "initialize", "arguments": {} }),
+            json!({ "command": "launch", "arguments": {} }),
+            json!({ "command": "setBreakpoints", "arguments": { "breakpoints": [{ "line": 6 }, { "line": 20 }] } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": THREAD_ID } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": REGISTERS_REFERENCE } }),
+            json!({ "command": "evaluate", "arguments": { "expression": "M[X]" } }),
+            json!({ "command": "continue", "arguments": { "threadId": THREAD_ID } }),
+            json!({ "command": "disconnect" }),
+        ]);
+        assert_eq!(kinds(&messages), [
+            "initialize", "initialized", "launch", "setBreakpoints", "configurationDone", "output", "stopped",
+            "stackTrace", "variables", "evaluate", "continue", "exited", "terminated", "disconnect",
+        ]);
+        assert!(messages.iter().filter(|message| message["type"] == "response").all(|response| response["success"] == true));
+        assert!(messages.iter().enumerate().all(|(i, message)| message["seq"] == json!(i + 1)));
+        assert_eq!(messages[2]["request_seq"], 2);
+
+        let breakpoints = &messages[3]["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["line"], 6);
+        assert_eq!(breakpoints[1]["verified"], false);
+        assert_eq!(messages[5]["body"]["output"], "7\n");
+        assert_eq!(messages[6]["body"]["reason"], "breakpoint");
+
+        let frames = &messages[7]["body"]["stackFrames"];
+        assert_eq!(frames[0]["name"], "main");
+        assert_eq!(frames[0]["line"], 6);
+        assert_eq!(messages[8]["body"]["variables"][0], json!({ "name": "ac", "value": "7", "variablesReference": 0 }));
+        assert_eq!(messages[9]["body"]["result"], "5");
+        assert_eq!(messages[11]["body"]["exitCode"], 0);
+    }
+
+    #[test]
+    fn reports_failed_requests() {
+        let messages = serve("failures", PROGRAM, &[
+            json!({ "command": "stackTrace" }),
+            json!({ "command": "launch", "arguments": { "symbols": { "BIG": 40000 } } }),
+            json!({ "command": "unknown" }),
+        ]);
+        assert_eq!(kinds(&messages), ["stackTrace", "launch", "unknown"]);
+        assert!(messages.iter().all(|response| response["success"] == false));
+        assert_eq!(messages[0]["message"], "The program is not launched.");
+        assert_eq!(messages[1]["message"], "Invalid value for the symbol 'BIG', expected a 16-bit integer.");
+        assert_eq!(messages[2]["message"], "Unsupported request 'unknown'.");
+    }
+}
@@ -0,0 +1,465 @@
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::interpreter::io_device::{IoDevice, StdioDevice};
+use crate::logkit;
+use super::session::{DebugSession, StopReason};
+
+/// Registers as GDB sees them: ac and sp are 16 bits, pc is the 32 bits instruction index.
+/// Memory is byte addressed for GDB, the stack word at address `a` is at bytes `2a` and `2a + 1` (little endian).
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.iguana.mac">
+    <reg name="ac" bitsize="16" type="int16" regnum="0"/>
+    <reg name="sp" bitsize="16" type="uint16" regnum="1"/>
+    <reg name="pc" bitsize="32" type="code_ptr" regnum="2"/>
+  </feature>
+</target>
+"#;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGILL: u8 = 4;
+const INTERRUPT: u8 = 0x03; // sent by GDB on Ctrl-C, outside of a packet
+
+/// Remote stub of the GDB Remote Serial Protocol, driving a `DebugSession` over TCP.
+pub struct GdbServer<D: IoDevice = StdioDevice> {
+    session: DebugSession<D>,
+    is_ack_mode: bool,
+}
+
+impl<D: IoDevice> GdbServer<D> {
+    pub fn new(session: DebugSession<D>) -> GdbServer<D> {
+        GdbServer {
+            session,
+            is_ack_mode: true,
+        }
+    }
+
+    /// Waits for one GDB connection on `address`, e.g. `127.0.0.1:1234`, and serves it until GDB detaches.
+    pub fn listen(&mut self, address: &str) -> io::Result<()> {
+        let listener = TcpListener::bind(address)?;
+        logkit::message(format!("Waiting for GDB on {}, connect with 'target remote {}'.", address, address).as_str());
+        let (stream, peer) = listener.accept()?;
+        logkit::message(format!("GDB connected from {}.", peer).as_str());
+        self.serve(stream)
+    }
+
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        self.is_ack_mode = true;
+
+        while let Some(packet) = read_packet(&mut reader, &mut writer, self.is_ack_mode)? {
+            let (reply, is_done) = self.handle_packet(&packet, &mut reader);
+            if let Some(reply) = reply {
+                write_packet(&mut writer, &reply)?;
+            }
+            if is_done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reply to one packet (`None` for no reply), and whether the connection ends.
+    /// `reader` is polled for an interrupt while the program runs.
+    fn handle_packet(&mut self, packet: &str, reader: &mut BufReader<TcpStream>) -> (Option<String>, bool) {
+        let reply = match packet {
+            "?" => stop_reply(SIGTRAP),
+            "g" => self.read_registers(),
+            "k" => return (None, true),
+            "D" => return (Some("OK".to_string()), true),
+            "s" => {
+                let reason = self.session.step();
+                self.stop_reason_reply(reason)
+            },
+            "c" => {
+                let reason = self.session.continue_until_interrupted(|| is_interrupted(reader));
+                self.stop_reason_reply(reason)
+            },
+            "bs" => {
+                let reason = self.session.reverse_step();
+                self.stop_reason_reply(reason)
+            },
+            "bc" => {
+                let reason = self.session.reverse_continue();
+                self.stop_reason_reply(reason)
+            },
+            "QStartNoAckMode" => {
+                self.is_ack_mode = false;
+                "OK".to_string()
+            },
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ if packet.starts_with("qSupported") => "PacketSize=4000;QStartNoAckMode+;qXfer:features:read+;ReverseStep+;ReverseContinue+".to_string(),
+            _ if packet.starts_with("qXfer:features:read:target.xml:") => read_target_xml(&packet["qXfer:features:read:target.xml:".len()..]),
+            _ if packet.starts_with('H') => "OK".to_string(),
+            _ if packet.starts_with('G') => self.write_registers(&packet[1..]),
+            _ if packet.starts_with('p') => self.read_register(&packet[1..]),
+            _ if packet.starts_with('P') => self.write_register(&packet[1..]),
+            _ if packet.starts_with('m') => self.read_memory(&packet[1..]),
+            _ if packet.starts_with('M') => self.write_memory(&packet[1..]),
+            _ if packet.starts_with("Z0,") || packet.starts_with("Z1,") => self.insert_breakpoint(&packet[3..]),
+            _ if packet.starts_with("z0,") || packet.starts_with("z1,") => self.remove_breakpoint(&packet[3..]),
+            _ => String::new(), // not supported
+        };
+        (Some(reply), false)
+    }
+
+    fn stop_reason_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Halted => format!("W{:02x}", self.session.vm().exit_code() as u8),
+            StopReason::Trapped(error) => {
+                logkit::iguana_error_message(&error);
+                stop_reply(SIGILL)
+            },
+            StopReason::HistoryStart => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::Interrupted => stop_reply(SIGINT),
+            _ => stop_reply(SIGTRAP),
+        }
+    }
+
+    fn register_bytes(&self, register: usize) -> Option<Vec<u8>> {
+        let vm = self.session.vm();
+        match register {
+            0 => Some(vm.ac().to_le_bytes().to_vec()),
+            1 => Some(vm.sp().to_le_bytes().to_vec()),
+            2 => Some(vm.pc().to_le_bytes().to_vec()),
+            _ => None,
+        }
+    }
+
+    fn set_register_bytes(&mut self, register: usize, bytes: &[u8]) -> bool {
+        let vm = self.session.vm_mut();
+        match (register, bytes.len()) {
+            (0, 2) => vm.set_ac(i16::from_le_bytes([bytes[0], bytes[1]])),
+            (1, 2) => vm.set_sp(i16::from_le_bytes([bytes[0], bytes[1]])),
+            (2, 4) => vm.set_pc(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..3).filter_map(|register| self.register_bytes(register)).map(|bytes| to_hex(&bytes)).collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let bytes = match from_hex(data) {
+            Some(bytes) if bytes.len() == 8 => bytes,
+            _ => return "E01".to_string(),
+        };
+        self.set_register_bytes(0, &bytes[0..2]);
+        self.set_register_bytes(1, &bytes[2..4]);
+        self.set_register_bytes(2, &bytes[4..8]);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, argument: &str) -> String {
+        match usize::from_str_radix(argument, 16).ok().and_then(|register| self.register_bytes(register)) {
+            Some(bytes) => to_hex(&bytes),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, argument: &str) -> String {
+        let (register, value) = match argument.split_once('=') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        match (usize::from_str_radix(register, 16), from_hex(value)) {
+            (Ok(register), Some(bytes)) if self.set_register_bytes(register, &bytes) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// `m addr,length`, in bytes.
+    fn read_memory(&self, argument: &str) -> String {
+        let (address, length) = match parse_address_length(argument) {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let end = match address.checked_add(length) {
+            Some(end) => end,
+            None => return "E01".to_string(),
+        };
+        let stack = self.session.vm().stack();
+        let mut bytes = Vec::new();
+        for byte_address in address..end {
+            match stack.get(byte_address / 2) {
+                Some(word) => bytes.push(word.to_le_bytes()[byte_address % 2]),
+                None if bytes.is_empty() => return "E01".to_string(),
+                None => break,
+            }
+        }
+        to_hex(&bytes)
+    }
+
+    /// `M addr,length:XX...`, in bytes.
+    fn write_memory(&mut self, argument: &str) -> String {
+        let (address_length, data) = match argument.split_once(':') {
+            Some(parts) => parts,
+            None => return "E01".to_string(),
+        };
+        let (address, length, bytes) = match (parse_address_length(address_length), from_hex(data)) {
+            (Some((address, length)), Some(bytes)) if bytes.len() == length => (address, length, bytes),
+            _ => return "E01".to_string(),
+        };
+        let end = match address.checked_add(length) {
+            Some(end) if end.div_ceil(2) <= self.session.vm().stack().len() => end,
+            _ => return "E01".to_string(),
+        };
+        for (byte_address, byte) in (address..end).zip(bytes) {
+            let vm = self.session.vm_mut();
+            let mut word = match vm.stack().get(byte_address / 2) {
+                Some(word) => word.to_le_bytes(),
+                None => return "E01".to_string(),
+            };
+            word[byte_address % 2] = byte;
+            vm.set_stack_word(byte_address / 2, i16::from_le_bytes(word));
+        }
+        "OK".to_string()
+    }
+
+    /// `Z0,addr,kind`, the address is an instruction index.
+    fn insert_breakpoint(&mut self, argument: &str) -> String {
+        match parse_breakpoint_address(argument) {
+            Some(pc) if self.session.breakpoint_at(pc).is_some() => "OK".to_string(),
+            Some(pc) if self.session.add_breakpoint_at_pc(pc).is_some() => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, argument: &str) -> String {
+        match parse_breakpoint_address(argument) {
+            Some(pc) => {
+                self.session.remove_breakpoints_at_pc(pc);
+                "OK".to_string()
+            },
+            None => "E01".to_string(),
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+/// `offset,length` of a qXfer read.
+fn read_target_xml(argument: &str) -> String {
+    let (offset, length) = match parse_address_length(argument) {
+        Some(parts) => parts,
+        None => return "E01".to_string(),
+    };
+    let xml = TARGET_XML.as_bytes();
+    if offset >= xml.len() {
+        return "l".to_string();
+    }
+    let end = match offset.checked_add(length) {
+        Some(end) => end.min(xml.len()),
+        None => return "E01".to_string(),
+    };
+    let prefix = if end == xml.len() { "l" } else { "m" };
+    format!("{}{}", prefix, String::from_utf8_lossy(&xml[offset..end]))
+}
+
+fn parse_address_length(argument: &str) -> Option<(usize, usize)> {
+    let (address, length) = argument.split_once(',')?;
+    Some((usize::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+fn parse_breakpoint_address(argument: &str) -> Option<u32> {
+    let address = argument.split(',').next()?;
+    u32::from_str_radix(address, 16).ok()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Whether GDB sent the interrupt byte while the program runs, without waiting for it.
+/// Other bytes are dropped, as GDB sends no packets until the program stops.
+fn is_interrupted(reader: &mut BufReader<TcpStream>) -> bool {
+    if reader.get_ref().set_nonblocking(true).is_err() {
+        return false;
+    }
+    let mut byte = [0u8; 1];
+    let mut is_interrupted = false;
+    while let Ok(1) = reader.read(&mut byte) {
+        if byte[0] == INTERRUPT {
+            is_interrupted = true;
+            break;
+        }
+    }
+    let _ = reader.get_ref().set_nonblocking(false);
+    is_interrupted
+}
+
+/// Reads the next `$data#checksum` packet, `None` when the connection is closed.
+fn read_packet<R: Read, W: Write>(reader: &mut R, writer: &mut W, is_ack_mode: bool) -> io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+    loop {
+        // skip acks and interrupts until the start of a packet
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'$' {
+                break;
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0u8; 2];
+        reader.read_exact(&mut checksum)?;
+
+        let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+        let actual = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        if is_ack_mode {
+            if expected != Some(actual) {
+                writer.write_all(b"-")?;
+                continue;
+            }
+            writer.write_all(b"+")?;
+        }
+        return Ok(Some(String::from_utf8_lossy(&data).to_string()));
+    }
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+    writer.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::io_device::BufferDevice;
+    use crate::interpreter::virtual_machine::VirtualMachine;
+
+    /// A GDB client over localhost, talking to a stub that serves `source` in another thread.
+    struct Client {
+        stream: TcpStream,
+        server: thread::JoinHandle<()>,
+    }
+
+    impl Client {
+        fn connect(source: &str) -> Client {
+            let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let address = listener.local_addr().unwrap();
+            let server = thread::spawn(move || {
+                let vm = VirtualMachine::with_io_device(&program, BufferDevice::new(""));
+                let (stream, _) = listener.accept().unwrap();
+                GdbServer::new(DebugSession::new(vm)).serve(stream).unwrap();
+            });
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+            let mut client = Client { stream, server };
+            assert_eq!(client.send("QStartNoAckMode"), "OK");
+            client
+        }
+
+        /// Sends a packet and returns the data of the reply, skipping the acks.
+        fn send(&mut self, data: &str) -> String {
+            write_packet(&mut self.stream, data).unwrap();
+            self.reply()
+        }
+
+        fn reply(&mut self) -> String {
+            let mut sink = Vec::new();
+            read_packet(&mut self.stream, &mut sink, false).unwrap().unwrap()
+        }
+
+        fn detach(mut self) {
+            assert_eq!(self.send("D"), "OK");
+            self.server.join().unwrap();
+        }
+    }
+
+    const PROGRAM: &str = ".data
+    X: .word 0x1234
+.text
+    LOCO 7
+    STOD X
+    HALT
+";
+
+    #[test]
+    fn steps_and_reads_registers_and_memory() {
+        let mut client = Client::connect(PROGRAM);
+        assert_eq!(client.send("?"), "S05");
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("p0"), "0700"); // ac = 7
+        assert_eq!(client.send("p2"), "01000000"); // pc = 1
+        assert_eq!(client.send("mfffe,2"), "3412"); // X is the word at 32767
+        assert_eq!(client.send("s"), "S05");
+        assert_eq!(client.send("mfffe,2"), "0700");
+        assert_eq!(client.send("Mfffe,2:2a00"), "OK");
+        assert_eq!(client.send("mfffe,2"), "2a00");
+        assert_eq!(client.send("c"), "W00");
+        client.detach();
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut client = Client::connect(PROGRAM);
+        assert_eq!(client.send("Z0,2,1"), "OK");
+        assert_eq!(client.send("c"), "S05");
+        assert_eq!(client.send("p2"), "02000000");
+        assert_eq!(client.send("z0,2,1"), "OK");
+        assert_eq!(client.send("c"), "W00");
+        client.detach();
+    }
+
+    #[test]
+    fn rejects_out_of_range_addresses() {
+        let mut client = Client::connect(PROGRAM);
+        assert_eq!(client.send("mffffffffffffffff,2"), "E01");
+        assert_eq!(client.send("m10000,2"), "E01");
+        assert_eq!(client.send("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(client.send("Mffff,2:0000"), "E01");
+        assert_eq!(client.send("qXfer:features:read:target.xml:1,ffffffffffffffff"), "E01");
+        assert!(client.send("qXfer:features:read:target.xml:0,10").starts_with("m<?xml"));
+        client.detach();
+    }
+
+    #[test]
+    fn interrupts_a_running_program() {
+        let mut client = Client::connect(".text\nLOOP:\n    JUMP LOOP\n");
+        write_packet(&mut client.stream, "c").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        client.stream.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(client.reply(), "S02");
+        assert_eq!(client.send("p2"), "00000000");
+        client.detach();
+    }
+}
@@ -0,0 +1,4 @@
+pub mod console;
+pub mod dap;
+pub mod gdbserver;
+pub mod session;
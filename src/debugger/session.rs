@@ -0,0 +1,337 @@
+use std::path::Path;
+
+use crate::interpreter::error::IguanaError;
+use crate::interpreter::io_device::{IoDevice, StdioDevice};
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::virtual_machine::{StepOutcome, VirtualMachine};
+
+/// Instructions recorded for reverse execution when the history length is not set.
+pub const DEFAULT_HISTORY_LENGTH: usize = 100_000;
+
+/// Instructions executed between two checks of `continue_until_interrupted`.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub id: u32,
+    pub pc: u32,
+    pub line: u32,
+    pub location: String, // source line or label, as given by the user
+}
+
+/// Why the execution stopped and control went back to the debugger.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StopReason {
+    Step, // the step, next or finish command is done
+    Breakpoint(u32), // id of the breakpoint at the current pc
+    Watchpoint, // the last instruction hit a watchpoint, the hits are in `VirtualMachine::watch_hits`
+    Halted,
+    Trapped(IguanaError),
+    WaitingForInput,
+    HistoryStart, // reverse execution reached the oldest recorded instruction
+    Interrupted, // the client asked to stop, e.g. GDB's Ctrl-C
+}
+
+/// Drives a `VirtualMachine` with breakpoints and stepping over or out of CALLs.
+pub struct DebugSession<D: IoDevice = StdioDevice> {
+    vm: VirtualMachine<D>,
+    breakpoints: Vec<Breakpoint>,
+    next_breakpoint_id: u32,
+}
+
+impl<D: IoDevice> DebugSession<D> {
+    pub fn new(vm: VirtualMachine<D>) -> DebugSession<D> {
+        DebugSession {
+            vm,
+            breakpoints: Vec::new(),
+            next_breakpoint_id: 1,
+        }
+    }
+
+    pub fn vm(&self) -> &VirtualMachine<D> {
+        &self.vm
+    }
+
+    pub fn vm_mut(&mut self) -> &mut VirtualMachine<D> {
+        &mut self.vm
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.breakpoints
+    }
+
+    /// Instruction index of a source line, a line of an included file (`lib/print.asm:12`) or a .text label,
+    /// lines without instructions resolve to the next instruction.
+    pub fn resolve_location(&self, location: &str) -> Option<u32> {
+        let program = self.vm.program();
+        if let Some((path, line)) = location.rsplit_once(':') {
+            let file = program.source_map.file_paths().iter()
+                .position(|file_path| !file_path.is_empty() && Path::new(file_path).ends_with(path))?;
+            return program.source_map.file_instruction_index_by_line(file as u32, line.parse().ok()?);
+        }
+        match location.parse::<u32>() {
+            Ok(line) => program.source_map.instruction_index_by_line(line),
+            Err(_) => program.text_label_indices.get(location).copied(),
+        }
+    }
+
+    /// Adds a breakpoint at a source line or a .text label, `None` when the location has no instruction.
+    pub fn add_breakpoint(&mut self, location: &str) -> Option<&Breakpoint> {
+        let pc = self.resolve_location(location)?;
+        self.insert_breakpoint(pc, location.to_string())
+    }
+
+    /// Adds a breakpoint at an instruction index, `None` when there is no instruction there.
+    pub fn add_breakpoint_at_pc(&mut self, pc: u32) -> Option<&Breakpoint> {
+        self.insert_breakpoint(pc, format!("pc {}", pc))
+    }
+
+    pub fn remove_breakpoints_at_pc(&mut self, pc: u32) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.pc != pc);
+        self.breakpoints.len() != count
+    }
+
+    fn insert_breakpoint(&mut self, pc: u32, location: String) -> Option<&Breakpoint> {
+        let line = self.vm.program().source_map.location(pc)?.line;
+        self.breakpoints.push(Breakpoint {
+            id: self.next_breakpoint_id,
+            pc,
+            line,
+            location,
+        });
+        self.next_breakpoint_id += 1;
+        self.breakpoints.last()
+    }
+
+    pub fn remove_breakpoint(&mut self, id: u32) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.id != id);
+        self.breakpoints.len() != count
+    }
+
+    pub fn breakpoint_at(&self, pc: u32) -> Option<&Breakpoint> {
+        self.breakpoints.iter().find(|breakpoint| breakpoint.pc == pc)
+    }
+
+    /// Puts the program back at its first instruction, keeping the breakpoints.
+    pub fn restart(&mut self) {
+        self.vm.reset();
+    }
+
+    /// Executes one instruction, entering called functions.
+    pub fn step(&mut self) -> StopReason {
+        self.run_until(|_| true)
+    }
+
+    /// Executes one instruction, running a CALL until the called function returns.
+    pub fn step_over(&mut self) -> StopReason {
+        self.run_until(|depth| depth <= 0)
+    }
+
+    /// Runs until the current function returns.
+    pub fn step_out(&mut self) -> StopReason {
+        self.run_until(|depth| depth < 0)
+    }
+
+    /// Runs until a breakpoint or the end of the program.
+    pub fn continue_execution(&mut self) -> StopReason {
+        self.run_until(|_| false)
+    }
+
+    /// Continues like `continue_execution`, but stops when `is_interrupted` is true, which is checked every `INTERRUPT_CHECK_INTERVAL` instructions.
+    pub fn continue_until_interrupted<F: FnMut() -> bool>(&mut self, mut is_interrupted: F) -> StopReason {
+        let mut steps = 0u64;
+        let mut was_interrupted = false;
+        let reason = self.run_until(|_| {
+            steps += 1;
+            was_interrupted = steps.is_multiple_of(INTERRUPT_CHECK_INTERVAL) && is_interrupted();
+            was_interrupted
+        });
+        match was_interrupted {
+            true => StopReason::Interrupted,
+            false => reason,
+        }
+    }
+
+    /// Undoes one instruction.
+    pub fn reverse_step(&mut self) -> StopReason {
+        match self.vm.reverse_step() {
+            true => StopReason::Step,
+            false => StopReason::HistoryStart,
+        }
+    }
+
+    /// Undoes instructions until a breakpoint or the oldest recorded instruction.
+    pub fn reverse_continue(&mut self) -> StopReason {
+        while self.vm.reverse_step() {
+            if let Some(breakpoint) = self.breakpoint_at(self.vm.pc()) {
+                return StopReason::Breakpoint(breakpoint.id);
+            }
+        }
+        StopReason::HistoryStart
+    }
+
+    /// Evaluates a sum of terms like `sp+2`, `BUFFER-1` or `0x7fff`, the terms are numbers, `ac`, `sp`, `pc`, data labels or constants.
+    pub fn evaluate(&self, expression: &str) -> Result<i64, String> {
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        for c in expression.chars() {
+            match c {
+                '+' | '-' if !term.trim().is_empty() => {
+                    total += sign * self.evaluate_term(term.trim())?;
+                    term.clear();
+                    sign = if c == '-' { -1 } else { 1 };
+                },
+                '-' => sign = -sign,
+                '+' => {},
+                _ => term.push(c),
+            }
+        }
+        if term.trim().is_empty() {
+            return Err(format!("Invalid expression '{}'.", expression));
+        }
+        Ok(total + sign * self.evaluate_term(term.trim())?)
+    }
+
+    fn evaluate_term(&self, term: &str) -> Result<i64, String> {
+        let vm = &self.vm;
+        match term.to_lowercase().as_str() {
+            "ac" => return Ok(vm.ac() as i64),
+            "sp" => return Ok(vm.sp() as i64),
+            "pc" => return Ok(vm.pc() as i64),
+            _ => {},
+        }
+        if let Some(symbol) = vm.program().data_symbol(term) {
+            return Ok(symbol.address as i64);
+        }
+        if let Some(value) = vm.program().constants.get(term) {
+            return Ok(*value as i64);
+        }
+        parse_number(term).ok_or(format!("Unknown symbol '{}'.", term))
+    }
+
+    /// Steps until `is_done` accepts the call depth relative to the start (CALL adds 1, RETN subtracts 1).
+    /// Breakpoints are not checked at the instruction the execution starts from.
+    fn run_until<F: FnMut(i32) -> bool>(&mut self, mut is_done: F) -> StopReason {
+        let mut depth = 0;
+        let mut is_first_step = true;
+        loop {
+            if !is_first_step {
+                if let Some(breakpoint) = self.breakpoint_at(self.vm.pc()) {
+                    return StopReason::Breakpoint(breakpoint.id);
+                }
+            }
+            is_first_step = false;
+
+            let opcode = self.vm.current_instruction().map(|instruction| instruction.opcode);
+            match self.vm.step() {
+                StepOutcome::Continued => {},
+                StepOutcome::Halted => return StopReason::Halted,
+                StepOutcome::Trapped(error) => return StopReason::Trapped(error),
+                StepOutcome::WaitingForInput => return StopReason::WaitingForInput,
+            }
+            match opcode {
+                Some(Opcode::Call) => depth += 1,
+                Some(Opcode::Retn) => depth -= 1,
+                _ => {},
+            }
+            if is_done(depth) {
+                return StopReason::Step;
+            }
+            if !self.vm.watch_hits().is_empty() {
+                return StopReason::Watchpoint;
+            }
+        }
+    }
+}
+
+/// Decimal, 0x hexadecimal or 0b binary number.
+fn parse_number(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse::<i64>().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+    use crate::interpreter::io_device::BufferDevice;
+    use crate::interpreter::program::Program;
+
+    fn create_session(source: &str) -> DebugSession<BufferDevice> {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new(""));
+        vm.set_history_limits(DEFAULT_HISTORY_LENGTH, DEFAULT_HISTORY_MEMORY_BUDGET);
+        DebugSession::new(vm)
+    }
+
+    const CALLS: &str = "
+.data
+    X: .word 1
+.text
+    LOCO 5
+    STOD X
+    CALL FN
+    EXIT 3
+FN:
+    PUSH
+    POP
+    RETN
+";
+
+    #[test]
+    fn reverse_step_undoes_registers_memory_and_calls() {
+        let mut session = create_session(CALLS);
+        assert_eq!(session.continue_execution(), StopReason::Halted);
+        assert_eq!(session.vm().exit_code(), 3);
+
+        assert_eq!(session.reverse_step(), StopReason::Step); // EXIT
+        assert_eq!(session.vm().exit_code(), 0);
+        assert!(!session.vm().is_halted());
+        assert_eq!(session.reverse_step(), StopReason::Step); // RETN
+        assert_eq!(session.vm().call_stack().len(), 1);
+        assert_eq!(session.vm().current_line(), Some(12));
+
+        while session.vm().pc() != 1 {
+            assert_eq!(session.reverse_step(), StopReason::Step);
+        }
+        assert!(session.vm().call_stack().is_empty());
+        assert_eq!(session.vm().stack()[Program::data_address(0)], 1);
+        assert_eq!((session.vm().ac(), session.vm().instruction_count()), (5, 1));
+        assert_eq!(session.reverse_step(), StopReason::Step);
+        assert_eq!(session.reverse_step(), StopReason::HistoryStart);
+        assert_eq!((session.vm().ac(), session.vm().pc()), (0, 0));
+
+        assert_eq!(session.continue_execution(), StopReason::Halted);
+        assert_eq!(session.vm().exit_code(), 3);
+    }
+
+    #[test]
+    fn reverse_continue_stops_at_breakpoints() {
+        let mut session = create_session(CALLS);
+        let id = session.add_breakpoint("FN").unwrap().id;
+        assert_eq!(session.continue_execution(), StopReason::Breakpoint(id));
+        assert_eq!(session.continue_execution(), StopReason::Halted);
+        assert_eq!(session.reverse_continue(), StopReason::Breakpoint(id));
+        assert_eq!(session.vm().current_line(), Some(10));
+        assert_eq!(session.reverse_continue(), StopReason::HistoryStart);
+        assert_eq!(session.vm().pc(), 0);
+    }
+
+    #[test]
+    fn reverse_step_needs_a_history() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble("    LOCO 1\n    HALT\n").unwrap();
+        let mut session = DebugSession::new(VirtualMachine::with_io_device(&program, BufferDevice::new("")));
+        assert_eq!(session.step(), StopReason::Step);
+        assert_eq!(session.reverse_step(), StopReason::HistoryStart);
+        assert_eq!(session.vm().ac(), 1);
+    }
+}
@@ -0,0 +1,960 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use super::error::{AssemblyErrorKind, ErrorKind, IguanaError};
+use super::expression;
+use super::instruction::Instruction;
+use super::opcode::Opcode;
+use super::program::{DataSymbol, Program, SourceFile, SourceMap, STACK_SIZE};
+use super::token::Token;
+use super::tokenizer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpreterMode {
+    Execute,
+    Binary, // system calls are rejected, and `BINARY` is defined to leave them out
+}
+
+/// Symbol defined when assembling in binary mode, so that `.ifndef BINARY` keeps code out of the exported program.
+pub const BINARY_SYMBOL: &str = "BINARY";
+
+enum Section {
+    Data,
+    Text,
+}
+
+/// A `.word` or `.byte` value that uses labels, e.g. `STR_A` or `TABLE-1`, computed once every label is known.
+struct DataReference {
+    index: usize, // of the word in `data`
+    token: Token,
+    is_dot_byte: bool,
+}
+
+/// Turns MAC assembly source text into a `Program`.
+pub struct Assembler {
+    interpreter_mode: InterpreterMode,
+    symbols: HashMap<String, i16>, // seen by .if, .ifdef and .ifndef, e.g. -D DEBUG
+
+    memory: Vec<Instruction>, // Memory, used to store the instructions
+    data: Vec<Option<i16>>, // Initial data memory, from the top of the stack downwards
+    data_references: Vec<DataReference>, // data words patched after the first pass
+    symbol_table: HashMap<String, u32>, // Symbol Table, used to store the address of labels
+    data_symbols: Vec<DataSymbol>,
+    text_label_indices: HashMap<String, u32>, // .text label -> index of the instruction it names
+    constants: HashMap<String, i16>, // .equ constants
+    line_branches: Vec<usize>, // instructions whose argument is still a line offset, resolved after the second pass
+    label_definitions: HashMap<String, Token>, // label -> token that defined it, to report labels defined twice
+    files: Vec<SourceFile>, // the assembled file and the files it includes, indexed by file id
+}
+
+impl Assembler {
+    pub fn new(interpreter_mode: InterpreterMode) -> Assembler {
+        let mut symbols = HashMap::new();
+        if interpreter_mode == InterpreterMode::Binary {
+            symbols.insert(BINARY_SYMBOL.to_string(), 1);
+        }
+        Assembler {
+            interpreter_mode,
+            symbols,
+            memory: Vec::new(),
+            data: Vec::new(),
+            data_references: Vec::new(),
+            symbol_table: HashMap::new(),
+            data_symbols: Vec::new(),
+            text_label_indices: HashMap::new(),
+            constants: HashMap::new(),
+            line_branches: Vec::new(),
+            label_definitions: HashMap::new(),
+            files: Vec::new(),
+        }
+    }
+
+    /// Defines a symbol for the conditional directives, like `-D NAME=VALUE` in the command line.
+    pub fn define(&mut self, name: &str, value: i16) {
+        self.symbols.insert(name.to_string(), value);
+    }
+
+    pub fn assemble_file(&mut self, file_path: &str) -> Result<Program, IguanaError> {
+        let source = match std::fs::read_to_string(file_path) {
+            Ok(content) => content.replace("\r", ""),
+            Err(_) => {
+                return Err(IguanaError::io("Error reading file. Please check if the file exists and is accessible."));
+            },
+        };
+        self.assemble_at(&source, file_path)
+    }
+
+    /// Assembles a source text, its `.include`s are found relative to the current directory.
+    pub fn assemble(&mut self, source: &str) -> Result<Program, IguanaError> {
+        self.assemble_at(source, "")
+    }
+
+    /// Assembles a source text as if it was the content of the file at `file_path`, e.g. a file being edited.
+    pub fn assemble_at(&mut self, source: &str, file_path: &str) -> Result<Program, IguanaError> {
+        self.files = vec![SourceFile { path: file_path.to_string(), source: source.to_string() }];
+        let file_paths = |files: &[SourceFile]| files.iter().map(|file| file.path.clone()).collect::<Vec<String>>();
+
+        let mut symbols = self.symbols.clone();
+        let result = self.include_files(tokenizer::tokenize(source), &mut vec![0], &mut symbols)
+            .and_then(|tokens| tokenizer::expand_macros(&tokens))
+            .and_then(|tokens| tokenizer::qualify_local_labels(&tokens))
+            .and_then(|tokens| self.assemble_tokens(&tokens));
+        result.map_err(|error| error.with_file_paths(&file_paths(&self.files)))
+    }
+
+    /// Replaces every `.include "path"` by the tokens of the file, whose path is relative to the including file.
+    /// The conditional blocks are applied at the same time, so an `.include` inside a removed block is not read,
+    /// and `symbols` gets the constants that the conditions of the next files can use.
+    /// `include_stack` holds the ids of the files being included, from the assembled file to the file of `tokens`.
+    fn include_files(&mut self, tokens: Vec<Token>, include_stack: &mut Vec<u32>, symbols: &mut HashMap<String, i16>) -> Result<Vec<Token>, IguanaError> {
+        let mut included_tokens = Vec::new();
+        let mut conditionals = tokenizer::Conditionals::new(symbols);
+        let mut token_counter = 0;
+        while let Some(token) = tokens.get(token_counter) {
+            if let Some(next_token_counter) = conditionals.read(&tokens, token_counter)? {
+                token_counter = next_token_counter;
+                continue;
+            }
+            token_counter += 1;
+            if !conditionals.is_active() {
+                continue;
+            }
+            if token.get_token() != ".include" {
+                included_tokens.push(token.clone());
+                continue;
+            }
+
+            let path_literal = match tokens.get(token_counter).filter(|path_token| path_token.line == token.line).map(|path_token| path_token.to_string_literal()) {
+                Some(Ok(Some(path_literal))) => path_literal,
+                Some(Err(error)) => return Err(error),
+                _ => return Err(token.error(AssemblyErrorKind::InvalidInclude, "Expected a file path between quotes after '.include', e.g. '.include \"lib/print.asm\"'.")),
+            };
+            token_counter += 1;
+            let path = include_path(&self.files[token.file as usize].path, &path_literal);
+
+            let including_paths = include_stack.iter().map(|file| self.files[*file as usize].path.as_str()).collect::<Vec<&str>>();
+            if including_paths.iter().any(|including_path| is_same_file(including_path, &path)) {
+                let cycle = including_paths.iter().map(|path| display_path(path)).collect::<Vec<&str>>().join(" -> ");
+                return Err(token.error(
+                    AssemblyErrorKind::InvalidInclude,
+                    format!("Include cycle: {} -> {}.", cycle, path).as_str(),
+                ));
+            }
+
+            let source = match std::fs::read_to_string(&path) {
+                Ok(content) => content.replace("\r", ""),
+                Err(_) => {
+                    return Err(token.error(
+                        AssemblyErrorKind::InvalidInclude,
+                        format!("Error reading included file '{}'. Please check if the file exists and is accessible.", path).as_str(),
+                    ));
+                },
+            };
+            let file = self.files.len() as u32;
+            let file_tokens = tokenizer::tokenize(&source).into_iter()
+                .map(|mut file_token| { file_token.file = file; file_token })
+                .collect::<Vec<Token>>();
+            self.files.push(SourceFile { path, source });
+
+            include_stack.push(file);
+            included_tokens.extend(self.include_files(file_tokens, include_stack, conditionals.symbols())?);
+            include_stack.pop();
+        }
+        conditionals.finish()?;
+        Ok(included_tokens)
+    }
+
+    fn assemble_tokens(&mut self, tokens: &[Token]) -> Result<Program, IguanaError> {
+        self.memory.clear();
+        self.data.clear();
+        self.data_references.clear();
+        self.symbol_table.clear();
+        self.data_symbols.clear();
+        self.text_label_indices.clear();
+        self.constants.clear();
+        self.line_branches.clear();
+        self.label_definitions.clear();
+
+        self.first_pass(tokens)?;
+        self.resolve_data_references()?;
+        self.second_pass(tokens)?;
+        self.resolve_branch_addresses()?;
+
+        Ok(Program {
+            instructions: self.memory.clone(),
+            data: self.data.clone(),
+            symbol_table: self.symbol_table.clone(),
+            data_symbols: self.data_symbols.clone(),
+            text_label_indices: self.text_label_indices.clone(),
+            constants: self.constants.clone(),
+            source_map: SourceMap::new(&self.files, &self.memory),
+        })
+    }
+
+    fn first_pass(&mut self, raw_tokens_vector: &[Token]) -> Result<(), IguanaError> {
+        // ==== PRIMEIRA PASSAGEM ====
+        let mut section = Section::Text;
+        let mut last_line_initialized = (0, 0); // (file, line)
+        let mut instruction_counter = 0;
+
+        let mut token_counter = 0;
+        while let Some(actual_raw_token) = raw_tokens_vector.get(token_counter) {
+            match actual_raw_token.get_token().as_str() {
+                ".data" => { section = Section::Data; },
+                ".text" => { section = Section::Text; },
+                ".equ" => {
+                    token_counter = self.define_constant(raw_tokens_vector, token_counter)?;
+                    continue;
+                },
+                _ => {
+                    match section {
+                        Section::Data => {
+                            token_counter = self.first_pass_data_label(raw_tokens_vector, token_counter)?;
+                            continue;
+                        },
+                        Section::Text => {
+                            /*  logica
+                             *  primeiro, ver se o token atual é uma label, se for:
+                             *      adicionar a label na symbol_table com o valor do ''pc'',
+                             *      raw_token.label <- label,
+                             *
+                             */
+                            if actual_raw_token.is_label() {
+                                let next_closest_instruction = match get_next_closest_instruction_by_token_counter(raw_tokens_vector, token_counter) {
+                                    Some(next_closest_instruction) => next_closest_instruction,
+                                    None => {
+                                        return Err(actual_raw_token.error(AssemblyErrorKind::ExpectedInstruction, "Expected an instruction after label"));
+                                    }
+                                };
+
+                                // the instructions expanded from a macro are all on the line of the invocation
+                                if last_line_initialized.0 == next_closest_instruction.file
+                                    && last_line_initialized.1 >= next_closest_instruction.line
+                                    && actual_raw_token.macro_origin.is_none() {
+                                    return Err(actual_raw_token.error(
+                                        AssemblyErrorKind::MisplacedLabel,
+                                        "You cannot initialize labels this way. Do not put instructions after multiple labels declarations at the same line",
+                                    ));
+                                }
+
+                                let label = get_label_name(actual_raw_token)?;
+                                self.define_label(&label, actual_raw_token)?;
+                                self.text_label_indices.insert(label.clone(), instruction_counter);
+                                self.symbol_table.insert(
+                                    label,
+                                    next_closest_instruction.line,
+                                );
+                            } else {
+                                match get_next_closest_instruction_by_token_counter( raw_tokens_vector, token_counter ) {
+                                    Some(next_closest_instruction) => {
+                                        last_line_initialized = (next_closest_instruction.file, next_closest_instruction.line);
+                                    },
+                                    None => {
+                                        return Err(actual_raw_token.error(
+                                            AssemblyErrorKind::ExpectedInstruction,
+                                            "Expected an instruction or a label before an instruction",
+                                        ));
+                                    }
+                                }
+                                token_counter += match Opcode::from_str(actual_raw_token.get_token().as_str()) {
+                                    Some(opcode) if Opcode::is_argumented(opcode) => { instruction_counter += 1; 2 },
+                                    Some(_) => { instruction_counter += 1; 1 },
+                                    None => 1,
+                                };
+
+                                continue;
+                            }
+                        }
+                    }
+                }
+            }
+            token_counter += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Allocates the data declared by the label at `token_counter` and returns the index of the token after the declaration.
+    fn first_pass_data_label(&mut self, raw_tokens_vector: &[Token], token_counter: usize) -> Result<usize, IguanaError> {
+        /* logica
+         * pegar o token atual, ver se ele é uma label, se for:
+         *    pegar o próximo token, ver se ele é .word, .byte, .ascii ou .asciiz
+         *    se for ver se o proximo token é um valor valido e se tem virgula após ele,
+         *    se for, ir adicionando os valores numa Vec<i8> de acordo com o tipo do .word, .byte, .ascii ou .asciiz
+         *    caso não tenha virgula, adicionar apenas o único valor no Vec<i8> e ir para o próximo token, que pode ser uma nova label
+         */
+        let actual_raw_token = &raw_tokens_vector[token_counter];
+        if !actual_raw_token.is_label() {
+            return Err(actual_raw_token.error(
+                AssemblyErrorKind::InvalidLabel,
+                "Expected a valid label or a valid value, labels cannot start with numbers",
+            ));
+        }
+
+        let label = get_label_name(actual_raw_token)?;
+        self.define_label(&label, actual_raw_token)?;
+
+        let next_raw_token = match raw_tokens_vector.get(token_counter + 1) {
+            Some(next_raw_token) => next_raw_token,
+            None => {
+                return Err(actual_raw_token.error(
+                    AssemblyErrorKind::ExpectedDirective,
+                    format!("Expected '.word', '.byte', '.space', '.ascii', or '.asciiz' after label '{}'.", label).as_str(),
+                ));
+            }
+        };
+
+        match next_raw_token.get_token().as_str() {
+            ".word" | ".byte" => {
+                /*
+                 * ir pegando todos os valores até não ter mais virgula
+                 * nesse momento:
+                 *      actual_raw_token = Label
+                 *      next_raw_token = .word ou .byte
+                 *      next_next_raw_token = <valor>
+                 */
+                let is_dot_byte = next_raw_token.get_token() == ".byte";
+                let values = get_comma_separated_values(raw_tokens_vector, token_counter + 2, is_dot_byte, &self.constants)?;
+
+                if values.is_empty() {
+                    return Err(actual_raw_token.error(
+                        AssemblyErrorKind::ExpectedOperand,
+                        format!("Expected at least one valid value after '{}', but found none.", next_raw_token.get_token()).as_str(),
+                    ));
+                }
+
+                let overflow_message = "Stack overflow: insufficient space to insert values.";
+                let address = self.next_data_address(overflow_message, actual_raw_token)?;
+                self.insert_data_label(label, address, values.len());
+
+                for (value_token, value) in values.iter() {
+                    if value.is_none() {
+                        self.data_references.push(DataReference { index: self.data.len(), token: value_token.clone(), is_dot_byte });
+                    }
+                    self.reserve_data_word(Some(value.unwrap_or(0)), overflow_message, actual_raw_token)?;
+                }
+
+                Ok(token_counter + values.len()*2 + 1)
+            },
+            ".ascii" | ".asciiz" => {
+                let invalid_string_error = |token: &Token| token.error(
+                    AssemblyErrorKind::ExpectedOperand,
+                    "Expect a valid string after .ascii or asciiz",
+                );
+                let next_next_raw_token = match raw_tokens_vector.get(token_counter + 2) {
+                    Some(next_next_raw_token) => next_next_raw_token,
+                    None => return Err(invalid_string_error(next_raw_token)),
+                };
+                if !next_next_raw_token.is_string_literal() {
+                    return Err(invalid_string_error(next_next_raw_token));
+                }
+                let string_literal = match next_next_raw_token.to_string_literal()? {
+                    Some(string_literal) => string_literal,
+                    None => return Err(invalid_string_error(next_next_raw_token)),
+                };
+
+                let overflow_message = "Stack overflow: no space left to insert string literal";
+                let address = self.next_data_address(overflow_message, next_next_raw_token)?;
+
+                let mut string_literal_bytes: Vec<u8> = string_literal.as_bytes().to_vec();
+
+                if next_raw_token.get_token() == ".asciiz" {
+                    string_literal_bytes.push(0);
+                }
+                self.insert_data_label(label, address, string_literal_bytes.len());
+                /*
+                 *  NÃO SEI SE ISSO É NECESSÁRIO, POIS NÃO SEI SE A ORDEM DOS BYTES IMPORTA
+                 *  O mais convencional é que e.g.:
+                 *  "abc" -> [97, 98, 99]
+                 *  stack:
+                 *    127 -> 99 (c)
+                 *    126 -> 98 (b)
+                 *    125 -> 97 (a)
+                 *
+                 *  ou seja, o primeiro byte da string literal é o último a ser colocado na stack (a stack cresce de cima para baixo)
+                 */
+                for b in string_literal_bytes {
+                    self.reserve_data_word(Some(b as i16), overflow_message, next_next_raw_token)?;
+                }
+
+                Ok(token_counter + 3)
+            },
+            ".space" => {
+                /*
+                 *  Na minha ISA, o .space aloca espaço na memoria (stack) e não inicializa com nenhum valor
+                 *  então, o valor do .space é a quantidade de bytes que serão alocados na stack
+                 *  Não é possivel que o argumento do .space seja um numero negativo nem impar, pois a stack é de 16 bits
+                 *  e.g.:
+                 *      AREA: .space 10 ou .space 0xa -> aloca 10 bytes na stack
+                 *      AREA: .space 11 -> erro, pois a stack é de 16 bits e não pode alocar um número ímpar de bytes
+                 *      AREA: .space -10 -> erro, pois a stack não pode alocar um número negativo de bytes
+                 *      AREA: .space 0b1010 -> aloca 10 bytes na stack
+                 *      AREA: .space 0x0a -> aloca 10 bytes na stack
+                 */
+                let next_next_raw_token = match raw_tokens_vector.get(token_counter + 2) {
+                    Some(next_next_raw_token) => next_next_raw_token,
+                    None => {
+                        return Err(next_raw_token.error(AssemblyErrorKind::ExpectedOperand, "Expected a valid value after .space"));
+                    }
+                };
+                let constant = |name: &str| self.constants.get(name).map(|value| *value as i32);
+                let value = match expression::is_expression(&next_next_raw_token.get_token()) {
+                    true => expression::evaluate(next_next_raw_token, &constant)?.map(i32::from),
+                    false => next_next_raw_token.to_i32_value()?.or_else(|| constant(&next_next_raw_token.get_token())),
+                };
+                let value: i32 = match value {
+                    Some(v) => v,
+                    None => {
+                        return Err(next_next_raw_token.error(AssemblyErrorKind::ExpectedOperand, "Expected a valid value after .space"));
+                    }
+                };
+
+                if value < 0 {
+                    return Err(next_next_raw_token.error(AssemblyErrorKind::OperandOutOfRange, "Expected a positive value after .space"));
+                }
+                if value % 2 != 0 {
+                    return Err(next_next_raw_token.error(AssemblyErrorKind::OperandOutOfRange, "Expected an even (multiple of 2) value after .space, this ISA only supports 16 bits stack values"));
+                }
+
+                let overflow_message = "Stack overflow: no space left to insert .space";
+                let address = self.next_data_address(overflow_message, next_next_raw_token)?;
+                self.insert_data_label(label, address, (value/2) as usize);
+
+                for _ in 0..(value/2) {
+                    self.reserve_data_word(None, overflow_message, next_next_raw_token)?;
+                }
+
+                Ok(token_counter + 3)
+            }
+            _ => {
+                Err(next_raw_token.error(AssemblyErrorKind::ExpectedDirective, "Expected .word, .byte, .ascii or .asciiz after label"))
+            }
+        }
+    }
+
+    /// Reports a label that was already defined, in this file or in another one.
+    fn define_label(&mut self, label: &str, token: &Token) -> Result<(), IguanaError> {
+        if let Some(definition) = self.label_definitions.get(label) {
+            let included_path = &self.files[token.file as usize].path;
+            let message = match definition.file != token.file && self.files[definition.file as usize].path == *included_path {
+                true => format!("Label '{}' is already defined, '{}' is included more than once.", label, included_path),
+                false => format!("Label '{}' is already defined.", label),
+            };
+            return Err(
+                token.error(AssemblyErrorKind::InvalidLabel, &message)
+                    .with_related(definition.related_location(format!("'{}' is first defined here", label).as_str()))
+            );
+        }
+        self.label_definitions.insert(label.to_string(), token.clone());
+        Ok(())
+    }
+
+    /// Defines the constant of the `.equ NAME, value` at `token_counter` and returns the index of the token after it.
+    /// The value can use the constants and labels defined before it.
+    fn define_constant(&mut self, raw_tokens_vector: &[Token], token_counter: usize) -> Result<usize, IguanaError> {
+        let directive = &raw_tokens_vector[token_counter];
+        let (name_token, value_token) = match raw_tokens_vector.get(token_counter + 1..token_counter + 4) {
+            Some([name_token, comma_token, value_token]) if comma_token.get_token() == "," => (name_token, value_token),
+            _ => {
+                return Err(directive.error(AssemblyErrorKind::ExpectedOperand, "Expected a name and a value after '.equ', e.g. '.equ SIZE, 10'."));
+            }
+        };
+
+        let name = name_token.get_token();
+        if !tokenizer::is_valid_name(&name) {
+            return Err(name_token.error(
+                AssemblyErrorKind::InvalidLabel,
+                format!("Invalid constant name '{}', names must start with a letter or '_' and contain only letters, digits or '_'.", name).as_str(),
+            ));
+        }
+        if Opcode::from_str(&name).is_some() {
+            return Err(name_token.error(
+                AssemblyErrorKind::InvalidLabel,
+                format!("Constant '{}' cannot have the same name of an instruction.", name).as_str(),
+            ));
+        }
+
+        let value = match expression::evaluate(value_token, &|symbol| self.get_symbol_value(symbol))? {
+            Some(value) => value,
+            None => {
+                return Err(value_token.error(
+                    AssemblyErrorKind::ExpectedOperand,
+                    format!("Expected a value, or a label or constant defined before, after '.equ {},'.", name).as_str(),
+                ));
+            }
+        };
+        self.define_label(&name, name_token)?;
+        self.constants.insert(name, value);
+        Ok(token_counter + 4)
+    }
+
+    /// Value of a name in an expression: a constant, the address of a .data label or the line of a .text label.
+    fn get_symbol_value(&self, name: &str) -> Option<i32> {
+        match self.constants.get(name) {
+            Some(value) => Some(*value as i32),
+            None => self.symbol_table.get(name).map(|value| *value as i32),
+        }
+    }
+
+    /// Patches the `.word` and `.byte` values that use labels, which can be defined after them.
+    /// A `.text` label is the index of its instruction, i.e. the `pc` that a `RETN` to it jumps to.
+    fn resolve_data_references(&mut self) -> Result<(), IguanaError> {
+        for reference in self.data_references.iter() {
+            let lookup = |name: &str| match self.text_label_indices.get(name) {
+                Some(index) => Some(*index as i32),
+                None => self.get_symbol_value(name),
+            };
+            let value = match expression::evaluate(&reference.token, &lookup)? {
+                Some(value) => value,
+                None if reference.token.get_token().starts_with('.') => return Err(undefined_local_label_error(&reference.token)),
+                None => {
+                    return Err(reference.token.error(
+                        AssemblyErrorKind::UndefinedLabel,
+                        format!("Undefined label or constant '{}'.", reference.token.get_token()).as_str(),
+                    ));
+                }
+            };
+            if reference.is_dot_byte && !(0..=255).contains(&value) {
+                return Err(reference.token.error(
+                    AssemblyErrorKind::OperandOutOfRange,
+                    format!("Value {} of '{}' out of range (0...255)", value, reference.token.get_token()).as_str(),
+                ));
+            }
+            self.data[reference.index] = Some(value);
+        }
+        Ok(())
+    }
+
+    fn insert_data_label(&mut self, label: String, address: u32, words: usize) {
+        self.symbol_table.insert(label.clone(), address);
+        self.data_symbols.push(DataSymbol { name: label, address, words: words as u32 });
+    }
+
+    /// Address that the next reserved data word will have.
+    fn next_data_address(&self, overflow_message: &str, token: &Token) -> Result<u32, IguanaError> {
+        if self.data.len() >= STACK_SIZE {
+            return Err(token.error(AssemblyErrorKind::DataOverflow, overflow_message));
+        }
+        Ok(Program::data_address(self.data.len()) as u32)
+    }
+
+    /// Reserves one data word below the previous one, the very first word of the data memory lives at the top of the stack.
+    /// `.space` words are reserved with `None`, as they are not initialized.
+    fn reserve_data_word(&mut self, value: Option<i16>, overflow_message: &str, token: &Token) -> Result<(), IguanaError> {
+        if self.data.len() >= STACK_SIZE {
+            return Err(token.error(AssemblyErrorKind::DataOverflow, overflow_message));
+        }
+        self.data.push(value);
+        Ok(())
+    }
+
+    fn second_pass(&mut self, raw_tokens: &[Token]) -> Result<(), IguanaError> {
+        self.memory.clear();
+        let mut section = Section::Text;
+        let mut token_counter = 0;
+        while let Some(actual_raw_token) = raw_tokens.get(token_counter) {
+            match actual_raw_token.get_token().as_str() {
+                ".data" => { section = Section::Data; token_counter += 1; },
+                ".text" => { section = Section::Text; token_counter += 1; },
+                ".equ" => { token_counter += 4; },
+                _ => {
+                    match section {
+                        Section::Data => { token_counter += 1; },
+                        Section::Text => {
+                            match Opcode::from_str(actual_raw_token.get_token().as_str()) {
+                                Some(opcode) if self.interpreter_mode == InterpreterMode::Binary && Opcode::is_system_call(opcode) => {
+                                    return Err(actual_raw_token.error(
+                                        AssemblyErrorKind::UnsupportedInstruction,
+                                        format!("'{}' only exists in the interpreter and cannot be exported to a binary, put it between '.ifndef BINARY' and '.endif'.", actual_raw_token.get_token()).as_str(),
+                                    ));
+                                },
+                                Some(opcode) => {
+                                    if Opcode::is_argumented(opcode) {
+                                        let next_raw_token = match raw_tokens.get(token_counter + 1) {
+                                            Some(next_raw_token) => next_raw_token,
+                                            None => {
+                                                return Err(actual_raw_token.error(
+                                                    AssemblyErrorKind::ExpectedOperand,
+                                                    "Expected a label or a value after instruction",
+                                                ));
+                                            }
+                                        };
+
+                                        let arg = if Opcode::takes_line_argument(opcode) {
+                                            match self.text_label_indices.get(&next_raw_token.get_token()) {
+                                                Some(target_instruction_index) => get_branch_offset(self.memory.len(), *target_instruction_index, next_raw_token)?,
+                                                None => {
+                                                    self.line_branches.push(self.memory.len());
+                                                    self.get_line_argument(actual_raw_token, next_raw_token)?
+                                                }
+                                            }
+                                        } else {
+                                            self.get_value_argument(next_raw_token)?
+                                        };
+
+                                        self.memory.push(
+                                            Instruction {
+                                                opcode,
+                                                arg,
+                                                file: actual_raw_token.file,
+                                                line: actual_raw_token.line,
+                                                col: actual_raw_token.col,
+                                            }
+                                        );
+                                        token_counter += 2;
+                                    } else { // caso não seja uma instrução com argumentos
+                                        self.memory.push(
+                                            Instruction {
+                                                opcode,
+                                                arg: 0,
+                                                file: actual_raw_token.file,
+                                                line: actual_raw_token.line,
+                                                col: actual_raw_token.col,
+                                            }
+                                        );
+
+                                        token_counter += 1;
+                                    }
+                                },
+                                None if actual_raw_token.is_label() => {
+                                    token_counter += 1;
+                                },
+                                None if expression::is_expression(&actual_raw_token.get_token()) => {
+                                    return Err(actual_raw_token.error(
+                                        AssemblyErrorKind::ExpectedInstruction,
+                                        "Expected an valid instruction, expressions cannot have spaces (e.g. 'LOCO SIZE*2')",
+                                    ));
+                                }
+                                None => {
+                                    return Err(actual_raw_token.error(AssemblyErrorKind::ExpectedInstruction, "Expected an valid instruction"));
+                                }
+                            }
+                        },
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Argument of control flow instructions that do not name a .text label, stored as the offset from the instruction line to the target line.
+    /// Expressions and constants give a line number, like the literals.
+    fn get_line_argument(&self, actual_raw_token: &Token, next_raw_token: &Token) -> Result<i16, IguanaError> {
+        let raw_argument: u32 = match self.symbol_table.get(&next_raw_token.get_token()) {
+            Some(label_line_address) => *label_line_address,
+            None => {
+                let line = match expression::is_expression(&next_raw_token.get_token()) {
+                    true => expression::evaluate(next_raw_token, &|name| self.get_symbol_value(name))?.and_then(|line| u32::try_from(line).ok()),
+                    false => next_raw_token.to_u32_value()?.or_else(|| self.constants.get(&next_raw_token.get_token()).and_then(|line| u32::try_from(*line).ok())),
+                };
+                match line {
+                    Some(v) => v,
+                    None if next_raw_token.get_token().starts_with('.') => return Err(undefined_local_label_error(next_raw_token)),
+                    None => {
+                        return Err(next_raw_token.error(
+                            AssemblyErrorKind::ExpectedOperand,
+                            "Expected a label or a valid positive value after instruction",
+                        ));
+                    }
+                }
+            }
+        };
+
+        let offsetted_argument: i64 = raw_argument as i64 - actual_raw_token.line as i64;
+
+        match i16::try_from(offsetted_argument) {
+            Ok(offset) => Ok(offset),
+            Err(_) => {
+                Err(next_raw_token.error(
+                    AssemblyErrorKind::OperandOutOfRange,
+                    format!("Processed control flow instruction with argument {} out of i16 bounds", offsetted_argument).as_str(),
+                ))
+            }
+        }
+    }
+
+    /// Argument of every other instruction: a label address, a constant, a decimal, hexadecimal, binary or char literal,
+    /// or an expression with them such as `BUFFER-1`.
+    fn get_value_argument(&self, next_raw_token: &Token) -> Result<i16, IguanaError> {
+        if let Some(label_address) = self.symbol_table.get(&next_raw_token.get_token()) {
+            return i16::try_from(*label_address).map_err(|_| next_raw_token.error(
+                AssemblyErrorKind::LiteralOverflow,
+                format!("Label '{}' is {}, out of range of (-32768...32767).", next_raw_token.get_token(), label_address).as_str(),
+            ));
+        }
+
+        match expression::evaluate(next_raw_token, &|name| self.get_symbol_value(name))? {
+            Some(value) => Ok(value),
+            None if next_raw_token.get_token().starts_with('.') => Err(undefined_local_label_error(next_raw_token)),
+            None => {
+                Err(next_raw_token.error(
+                    AssemblyErrorKind::ExpectedOperand,
+                    "Expected a label or a valid value in range of (-32768...32767) after instruction",
+                ))
+            }
+        }
+    }
+
+    /// Turns the line offsets of `line_branches` into instruction offsets.
+    fn resolve_branch_addresses(&mut self) -> Result<(), IguanaError> {
+        for i in self.line_branches.clone() {
+            let instr = self.memory[i];
+            let targer_instruction_line = instr.line as i64 + instr.arg as i64;
+            if targer_instruction_line < 0 {
+                return Err(IguanaError::assembly(AssemblyErrorKind::OperandOutOfRange, "Expected a positive line value", instr.line, instr.col).in_file(instr.file));
+            }
+
+            match self.get_closest_instruction_index_by_line(instr.file, targer_instruction_line as u32) {
+                Some(target_instruction_index) => {
+                    match i16::try_from( target_instruction_index as i64 - i as i64 ) {
+                        Ok(offset) => {
+                            self.memory[i].arg = offset;
+                        }
+                        Err(_) => {
+                            return Err(IguanaError::assembly(AssemblyErrorKind::OperandOutOfRange, "Branch instruction out of bounds.", instr.line, instr.col).in_file(instr.file));
+                        }
+                    }
+                }
+                None => {
+                    self.memory[i].arg = i16::MAX;
+                }
+            }
+        }
+        Ok(())
+    }
+
+
+    fn get_closest_instruction_index_by_line(&self, file: u32, line: u32) -> Option<u32> {
+        self.memory.iter()
+            .position(|instruction| instruction.file == file && instruction.line >= line)
+            .map(|index| index as u32)
+    }
+}
+
+
+/// Path of an included file: `included_path` relative to the directory of the including file.
+fn include_path(including_path: &str, included_path: &str) -> String {
+    let directory = Path::new(including_path).parent().unwrap_or(Path::new(""));
+    directory.join(included_path).to_string_lossy().to_string()
+}
+
+/// Whether two paths name the same file, even when written differently (e.g. `lib/../main.asm` and `main.asm`).
+fn is_same_file(a: &str, b: &str) -> bool {
+    let canonical = |path: &str| std::fs::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+    !a.is_empty() && canonical(a) == canonical(b)
+}
+
+/// Path shown in the include cycles, the assembled file may not have one.
+fn display_path(path: &str) -> &str {
+    match path {
+        "" => "<source>",
+        _ => path,
+    }
+}
+
+/// Local labels that were not renamed by `tokenizer::qualify_local_labels` are not defined in their scope.
+fn undefined_local_label_error(token: &Token) -> IguanaError {
+    token.error(
+        AssemblyErrorKind::UndefinedLabel,
+        format!("Undefined local label '{}', outside of the global label it belongs to use its full name, e.g. 'FUNCTION{}'.", token.get_token(), token.get_token()).as_str(),
+    )
+}
+
+/// Offset from the instruction at `instruction_index` to the one at `target_instruction_index`.
+fn get_branch_offset(instruction_index: usize, target_instruction_index: u32, token: &Token) -> Result<i16, IguanaError> {
+    match i16::try_from(target_instruction_index as i64 - instruction_index as i64) {
+        Ok(offset) => Ok(offset),
+        Err(_) => Err(token.error(AssemblyErrorKind::OperandOutOfRange, "Branch instruction out of bounds.")),
+    }
+}
+
+/// Label name without the trailing ':', which cannot be the name of an instruction.
+fn get_label_name(token: &Token) -> Result<String, IguanaError> {
+    let label = token.get_token()[..token.get_token().len()-1].to_string();
+
+    // Isso serve para impedir que uma label tenha o mesmo nome de uma instrução
+    if Opcode::from_str(label.as_str()).is_some() {
+        return Err(token.error(
+            AssemblyErrorKind::InvalidLabel,
+            format!("Label '{}' cannot have the same name of an instruction.", label).as_str(),
+        ));
+    }
+    Ok(label)
+}
+
+/// Values of `.word` and `.byte`, literals or expressions with the constants defined before them.
+/// The values that use labels are `None`, as they are only known after the first pass.
+fn get_comma_separated_values(vector: &[Token], offset: usize, is_dot_byte: bool, constants: &HashMap<String, i16>) -> Result<Vec<(Token, Option<i16>)>, IguanaError> {
+    let mut values = Vec::new();
+    let mut aux_value_counter = offset;
+    while let Some(aux_raw_token) = vector.get(aux_value_counter) {
+        if aux_raw_token.get_token() == "," {
+            aux_value_counter += 1;
+            continue;
+        }
+
+        let value = match expression::evaluate(aux_raw_token, &|name| constants.get(name).map(|value| *value as i32)) {
+            Err(error) if error.kind == ErrorKind::Assembly(AssemblyErrorKind::UndefinedLabel) => None,
+            result => result?,
+        };
+        match value {
+            Some(value) => {
+                if is_dot_byte && !(0..=255).contains(&value) {
+                    return Err(aux_raw_token.error(AssemblyErrorKind::OperandOutOfRange, "Value out of range (0...255)"));
+                }
+                values.push((aux_raw_token.clone(), Some(value)));
+                aux_value_counter += 1;
+            },
+            None if is_label_value(aux_raw_token) && (values.is_empty() || vector[aux_value_counter - 1].get_token() == ",") => {
+                values.push((aux_raw_token.clone(), None));
+                aux_value_counter += 1;
+            },
+            None => {
+                if vector[aux_value_counter - 1].get_token() == "," {
+                    let message = if is_dot_byte {
+                        "Expected a valid value in range of 0...255 or a label"
+                    } else {
+                        "Expected a valid value in range of -32768...32767 or a label"
+                    };
+                    return Err(aux_raw_token.error(AssemblyErrorKind::ExpectedOperand, message));
+                }
+                break;
+            }
+        }
+    }
+
+    Ok(values)
+}
+
+/// Whether a data value can be a label or an expression with labels, e.g. `STR_A` or `TABLE-1`,
+/// rather than the label, directive or instruction that follows the values.
+fn is_label_value(token: &Token) -> bool {
+    let text = token.get_token();
+    !token.is_label() && !text.starts_with('.') && !text.starts_with('"') && Opcode::from_str(&text).is_none()
+}
+
+fn get_next_closest_instruction_by_token_counter(raw_tokens: &[Token], offset: usize) -> Option<&Token> {
+    let mut section = Section::Text;
+
+    for actual_token in raw_tokens.iter().skip(offset) {
+        match actual_token.get_token().as_str() {
+            ".data" => {
+                section = Section::Data;
+            }
+            ".text" => {
+                section = Section::Text;
+            }
+            _ => {
+                if let Section::Text = section {
+                    if Opcode::from_str(actual_token.get_token().as_str()).is_some() {
+                        return Some(actual_token);
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_labels_are_instruction_indices_only_in_data_values() {
+        let source = ".data\n    TABLE: .word FN, FN+1, DATA\n    DATA: .word 0\n.text\n    LOCO FN\n    JUMP FN\nFN:\n    .equ LINE, FN\n    LOCO LINE+1\n    HALT\n";
+        let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+        assert_eq!(program.instructions[0].arg, 9); // LOCO FN, the line of its instruction
+        assert_eq!(program.instructions[1].arg, 1); // offset from JUMP to FN
+        assert_eq!(program.instructions[2].arg, 10); // LOCO LINE+1
+        assert_eq!(program.data[..3], [Some(2), Some(3), Some(32764)]);
+    }
+
+    #[test]
+    fn labels_out_of_the_word_range_are_errors() {
+        let source = format!(".text\n    LOCO FN\n{}FN:\n    HALT\n", "\n".repeat(40000));
+        let error = Assembler::new(InterpreterMode::Execute).assemble(&source).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+    }
+
+    #[test]
+    fn undefined_labels_in_data_values_are_errors() {
+        let error = Assembler::new(InterpreterMode::Execute).assemble(".data\n    X: .word MISSING\n.text\n    HALT\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::UndefinedLabel));
+        let error = Assembler::new(InterpreterMode::Execute).assemble(".data\n    X: .byte X\n.text\n    HALT\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::OperandOutOfRange));
+    }
+
+    /// Writes `files` to a new temporary directory and returns its path.
+    fn write_files(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("iguana-include-{}-{}", std::process::id(), name));
+        for (path, source) in files {
+            let path = directory.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, source).unwrap();
+        }
+        directory
+    }
+
+    fn assemble_in(directory: &Path, path: &str) -> Result<Program, IguanaError> {
+        Assembler::new(InterpreterMode::Execute).assemble_file(directory.join(path).to_str().unwrap())
+    }
+
+    #[test]
+    fn included_files_are_relative_to_the_including_file() {
+        let directory = write_files("relative", &[
+            ("main.asm", ".include \"lib/double.asm\"\n.text\n    LOCO 21\n    CALL DOUBLE\n    HALTAC\n"),
+            ("lib/double.asm", ".include \"constants.asm\"\n.text\nDOUBLE:\n    ADDD SELF\n    RETN\n"),
+            ("lib/constants.asm", ".data\n    SELF: .word 0\n"),
+        ]);
+        let program = assemble_in(&directory, "main.asm");
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        let program = program.unwrap();
+        assert_eq!(program.source_map.file_paths().len(), 3);
+        assert_eq!(program.source_map.location(0).map(|location| (location.file, location.line)), Some((1, 4)));
+    }
+
+    #[test]
+    fn include_cycles_are_errors() {
+        let directory = write_files("cycle", &[
+            ("a.asm", ".include \"lib/b.asm\"\n.text\n    HALT\n"),
+            ("lib/b.asm", ".text\n    LOCO 1\n.include \"../a.asm\"\n"),
+        ]);
+        let error = assemble_in(&directory, "a.asm").unwrap_err();
+        let self_include = write_files("self", &[("self.asm", ".include \"self.asm\"\n")]);
+        let self_error = assemble_in(&self_include, "self.asm").unwrap_err();
+        std::fs::remove_dir_all(&directory).unwrap();
+        std::fs::remove_dir_all(&self_include).unwrap();
+
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidInclude));
+        let a = directory.join("a.asm").display().to_string();
+        let b = directory.join("lib/b.asm").display().to_string();
+        let a_from_b = directory.join("lib/../a.asm").display().to_string();
+        assert_eq!(error.message, format!("Include cycle: {} -> {} -> {}.", a, b, a_from_b));
+        assert_eq!((error.file_path.as_deref(), error.line, error.col), (Some(b.as_str()), 3, 1));
+        assert_eq!(self_error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidInclude));
+    }
+
+    #[test]
+    fn missing_included_files_are_errors() {
+        let directory = write_files("missing", &[
+            ("main.asm", ".text\n    HALT\n.include \"missing.asm\"\n"),
+            ("skipped.asm", ".ifdef DEBUG\n.include \"missing.asm\"\n.endif\n.text\n    HALT\n"),
+            ("unquoted.asm", ".include missing.asm\n"),
+        ]);
+        let error = assemble_in(&directory, "main.asm").unwrap_err();
+        let skipped = assemble_in(&directory, "skipped.asm");
+        let unquoted_error = assemble_in(&directory, "unquoted.asm").unwrap_err();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidInclude));
+        let missing = directory.join("missing.asm").display().to_string();
+        assert_eq!(error.message, format!("Error reading included file '{}'. Please check if the file exists and is accessible.", missing));
+        assert_eq!((error.file_path, error.line, error.col), (None, 3, 1));
+        assert!(skipped.is_ok()); // the .include is in a removed block
+        assert_eq!(unquoted_error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidInclude));
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+
+/// Process exit codes of the command line interface.
+/// A program that ends with `EXIT X` or `HALTAC` chooses its own exit code instead of `EXIT_SUCCESS`.
+pub const EXIT_SUCCESS: i32 = 0;
+pub const EXIT_USAGE: i32 = 2;
+pub const EXIT_ASSEMBLY_ERROR: i32 = 3;
+pub const EXIT_RUNTIME_ERROR: i32 = 4;
+pub const EXIT_IO_ERROR: i32 = 5;
+pub const EXIT_LIMIT_EXCEEDED: i32 = 6;
+
+/// What went wrong while turning the source text into instructions and data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssemblyErrorKind {
+    InvalidLiteral,
+    LiteralOverflow,
+    InvalidLabel,
+    UndefinedLabel,
+    MisplacedLabel,
+    ExpectedInstruction,
+    ExpectedOperand,
+    ExpectedDirective,
+    DataOverflow,
+    OperandOutOfRange,
+    InvalidMacro,
+    InvalidInclude,
+    InvalidConditional,
+    UnsupportedInstruction,
+}
+
+/// What went wrong while the virtual machine was executing a program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    AddressOutOfBounds,
+    StackPointerOutOfBounds,
+    PcOutOfBounds,
+    Overflow,
+    DivisionByZero,
+    InvalidOperand,
+    InvalidInput,
+    Io,
+    UninitializedRead,
+    ReturnAddressMismatch,
+    InfiniteLoop,
+}
+
+/// Which of the `ExecutionLimits` stopped the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Steps,
+    Timeout,
+    Sleep,
+    Output,
+}
+
+/// A second position in the source that explains an error, e.g. the macro an instruction was expanded from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelatedLocation {
+    pub message: String,
+    pub file: u32,
+    pub file_path: Option<String>,
+    pub line: u32,
+    pub col: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Io,
+    Assembly(AssemblyErrorKind),
+    Runtime(RuntimeErrorKind),
+    LimitExceeded(LimitKind),
+}
+
+/// Error returned by the tokenizer, the assembler passes and the virtual machine.
+/// `line` and `col` are 0 when the error has no position in the source (e.g. file errors).
+/// `file` is 0 for the assembled file and the id of an `.include`d file otherwise, whose path is shown with the position.
+/// Runtime and limit errors carry the functions being executed, innermost first, e.g. `["PRINT_STRING", "FIBONACCI", "main"]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IguanaError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: u32,
+    pub col: u32,
+    pub file: u32,
+    pub file_path: Option<String>,
+    pub backtrace: Vec<String>,
+    pub related: Option<Box<RelatedLocation>>,
+}
+
+impl IguanaError {
+    pub fn io(message: &str) -> IguanaError {
+        IguanaError {
+            kind: ErrorKind::Io,
+            message: message.to_string(),
+            line: 0,
+            col: 0,
+            file: 0,
+            file_path: None,
+            backtrace: Vec::new(),
+            related: None,
+        }
+    }
+
+    pub fn assembly(kind: AssemblyErrorKind, message: &str, line: u32, col: u32) -> IguanaError {
+        IguanaError {
+            kind: ErrorKind::Assembly(kind),
+            message: message.to_string(),
+            line,
+            col,
+            file: 0,
+            file_path: None,
+            backtrace: Vec::new(),
+            related: None,
+        }
+    }
+
+    pub fn runtime(kind: RuntimeErrorKind, message: &str, line: u32, col: u32) -> IguanaError {
+        IguanaError {
+            kind: ErrorKind::Runtime(kind),
+            message: message.to_string(),
+            line,
+            col,
+            file: 0,
+            file_path: None,
+            backtrace: Vec::new(),
+            related: None,
+        }
+    }
+
+    pub fn limit_exceeded(kind: LimitKind, message: &str, line: u32, col: u32) -> IguanaError {
+        IguanaError {
+            kind: ErrorKind::LimitExceeded(kind),
+            message: message.to_string(),
+            line,
+            col,
+            file: 0,
+            file_path: None,
+            backtrace: Vec::new(),
+            related: None,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self.kind {
+            ErrorKind::Io => EXIT_IO_ERROR,
+            ErrorKind::Assembly(_) => EXIT_ASSEMBLY_ERROR,
+            ErrorKind::Runtime(_) => EXIT_RUNTIME_ERROR,
+            ErrorKind::LimitExceeded(_) => EXIT_LIMIT_EXCEEDED,
+        }
+    }
+
+    pub fn is_positional(&self) -> bool {
+        self.line != 0
+    }
+
+    pub fn with_backtrace(mut self, backtrace: Vec<String>) -> IguanaError {
+        self.backtrace = backtrace;
+        self
+    }
+
+    pub fn in_file(mut self, file: u32) -> IguanaError {
+        self.file = file;
+        self
+    }
+
+    /// Sets the paths of the files the error points to, `paths[id]` being the path of the file `id`.
+    pub fn with_file_paths(mut self, paths: &[String]) -> IguanaError {
+        let path = |file: u32| match file {
+            0 => None,
+            _ => paths.get(file as usize).cloned(),
+        };
+        self.file_path = path(self.file);
+        if let Some(related) = self.related.as_mut() {
+            related.file_path = path(related.file);
+        }
+        self
+    }
+
+    pub fn with_related(mut self, related: RelatedLocation) -> IguanaError {
+        self.related = Some(Box::new(related));
+        self
+    }
+
+    /// The backtrace as `PRINT_STRING <- FIBONACCI <- main`, `None` when there is none.
+    pub fn backtrace_text(&self) -> Option<String> {
+        match self.backtrace.is_empty() {
+            true => None,
+            false => Some(self.backtrace.join(" <- ")),
+        }
+    }
+}
+
+impl fmt::Display for IguanaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.is_positional() {
+            true => write!(f, "{} {}", self.message, position_text(self.file_path.as_deref(), self.line, self.col))?,
+            false => write!(f, "{}", self.message)?,
+        }
+        if let Some(related) = &self.related {
+            write!(f, " ({} {})", related.message, position_text(related.file_path.as_deref(), related.line, related.col))?;
+        }
+        match self.backtrace_text() {
+            Some(backtrace) => write!(f, " [BACKTRACE: {}]", backtrace),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for IguanaError {}
+
+/// `[LINE: 3, COL: 5]`, or `[FILE: lib/print.asm, LINE: 3, COL: 5]` in an included file. The column is left out when it is 0.
+pub fn position_text(file_path: Option<&str>, line: u32, col: u32) -> String {
+    let file = file_path.map_or(String::new(), |path| format!("FILE: {}, ", path));
+    match col {
+        0 => format!("[{}LINE: {}]", file, line),
+        _ => format!("[{}LINE: {}, COL: {}]", file, line, col),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+
+    #[test]
+    fn assembly_errors_are_returned_with_their_kind_and_position() {
+        let error = Assembler::new(InterpreterMode::Execute).assemble(".text\n    LOCO 0x8000\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+        assert_eq!((error.line, error.col), (2, 10));
+
+        let error = Assembler::new(InterpreterMode::Execute).assemble(".text\n    NOPE\n").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::ExpectedInstruction));
+        assert!(error.is_positional());
+    }
+
+    #[test]
+    fn every_kind_has_its_exit_code() {
+        assert_eq!([EXIT_SUCCESS, EXIT_USAGE, EXIT_ASSEMBLY_ERROR, EXIT_RUNTIME_ERROR, EXIT_IO_ERROR, EXIT_LIMIT_EXCEEDED], [0, 2, 3, 4, 5, 6]);
+        assert_eq!(IguanaError::io("").exit_code(), EXIT_IO_ERROR);
+        assert_eq!(IguanaError::assembly(AssemblyErrorKind::InvalidLabel, "", 1, 1).exit_code(), EXIT_ASSEMBLY_ERROR);
+        assert_eq!(IguanaError::runtime(RuntimeErrorKind::Overflow, "", 1, 1).exit_code(), EXIT_RUNTIME_ERROR);
+        assert_eq!(IguanaError::limit_exceeded(LimitKind::Steps, "", 1, 1).exit_code(), EXIT_LIMIT_EXCEEDED);
+    }
+
+    #[test]
+    fn missing_files_are_io_errors_without_a_position() {
+        let error = Assembler::new(InterpreterMode::Execute).assemble_file("/nonexistent/program.asm").unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Io);
+        assert!(!error.is_positional());
+    }
+
+    #[test]
+    fn display_shows_the_position_the_related_location_and_the_backtrace() {
+        let error = IguanaError::runtime(RuntimeErrorKind::DivisionByZero, "Division by zero is not allowed.", 11, 5);
+        assert_eq!(error.to_string(), "Division by zero is not allowed. [LINE: 11, COL: 5]");
+
+        let error = error.with_backtrace(vec!["PRINT".to_string(), "main".to_string()]);
+        assert_eq!(error.to_string(), "Division by zero is not allowed. [LINE: 11, COL: 5] [BACKTRACE: PRINT <- main]");
+
+        let related = RelatedLocation { message: "expanded from".to_string(), file: 1, file_path: None, line: 2, col: 0 };
+        let error = IguanaError::assembly(AssemblyErrorKind::ExpectedOperand, "Expected an operand.", 3, 1).in_file(1).with_related(related)
+            .with_file_paths(&["main.asm".to_string(), "lib.asm".to_string()]);
+        assert_eq!(error.to_string(), "Expected an operand. [FILE: lib.asm, LINE: 3, COL: 1] (expanded from [FILE: lib.asm, LINE: 2])");
+
+        assert_eq!(IguanaError::io("Error reading the file.").to_string(), "Error reading the file.");
+    }
+
+    #[test]
+    fn the_assembled_file_has_no_path_in_positions() {
+        let error = IguanaError::assembly(AssemblyErrorKind::InvalidLabel, "Invalid label.", 1, 1).with_file_paths(&["main.asm".to_string()]);
+        assert_eq!(error.file_path, None);
+        assert_eq!(position_text(Some("lib.asm"), 4, 0), "[FILE: lib.asm, LINE: 4]");
+    }
+}
@@ -0,0 +1,395 @@
+use super::error::{AssemblyErrorKind, IguanaError};
+use super::token::Token;
+
+/// Binary operators from the lowest to the highest precedence, as in C.
+const PRECEDENCE: [&[&str]; 10] = [
+    &["||"], &["&&"], &["|"], &["^"], &["&"], &["==", "!="], &["<", "<=", ">", ">="], &["<<", ">>"], &["+", "-"], &["*", "/", "%"],
+];
+
+/// Operators in the order they are matched, the longer ones first.
+const OPERATORS: [&str; 22] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "<", ">", "+", "-", "*", "/", "%", "&", "|", "^", "~", "!", "(", ")",
+];
+
+const OPERATOR_CHARS: &str = "+-*/%&|^~!=()<>";
+
+#[derive(Debug, Clone, PartialEq)]
+enum Item {
+    Value(i32),
+    Name(String),
+    Operator(&'static str),
+}
+
+/// Value of an operand such as `BUFFER-1`, `SIZE*2`, `(LENGTH+1)/2` or `'a'+1`, with the labels and constants found by `lookup`.
+/// Returns `Ok(None)` when the token is not a value at all (e.g. an instruction or an undefined label alone),
+/// so the caller can report what it expected, and an error when it is an invalid expression.
+pub fn evaluate(token: &Token, lookup: &dyn Fn(&str) -> Option<i32>) -> Result<Option<i16>, IguanaError> {
+    let text = token.get_token();
+    if !is_expression(&text) || token.is_char_literal() {
+        if let Some(value) = token.to_i16_value()? {
+            return Ok(Some(value));
+        }
+        return match lookup(&text) {
+            Some(value) => to_i16(token, value).map(Some),
+            None => Ok(None),
+        };
+    }
+    if let Ok(value) = text.parse::<i16>() {
+        return Ok(Some(value));
+    }
+
+    let mut parser = Parser { token, items: get_items(token)?, position: 0, lookup };
+    let value = parser.parse_binary(0)?;
+    if let Some(item) = parser.items.get(parser.position) {
+        return Err(parser.unexpected_item_error(item));
+    }
+    to_i16(token, value).map(Some)
+}
+
+/// Whether the token has operators or parentheses, string literals never do.
+pub fn is_expression(text: &str) -> bool {
+    !text.starts_with('"') && text.chars().any(|c| OPERATOR_CHARS.contains(c))
+}
+
+/// Replaces the names and numbers in an expression, leaving the char literals and operators as they are, e.g. `N*2` -> `(X+1)*2`.
+pub fn replace_names(text: &str, replace: &dyn Fn(&str) -> Option<String>) -> String {
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut replaced = String::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let length = match chars[index] {
+            '\'' => char_literal_length(&chars[index..]).unwrap_or(chars.len() - index),
+            c if is_name_char(c) => chars[index..].iter().take_while(|c| is_name_char(**c)).count(),
+            _ => 1,
+        };
+        let word = chars[index..index + length].iter().collect::<String>();
+        match replace(&word).filter(|_| is_name_char(chars[index])) {
+            Some(replacement) => replaced.push_str(&replacement),
+            None => replaced.push_str(&word),
+        }
+        index += length;
+    }
+    replaced
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '.' || c == '@'
+}
+
+/// Length of the char literal at the start of `chars`, e.g. 3 for `'a'` and 4 for `'\n'`.
+fn char_literal_length(chars: &[char]) -> Option<usize> {
+    let length = match chars.get(1) {
+        Some('\\') => 4,
+        Some(_) => 3,
+        None => return None,
+    };
+    match chars.get(length - 1) {
+        Some('\'') => Some(length),
+        _ => None,
+    }
+}
+
+fn get_items(token: &Token) -> Result<Vec<Item>, IguanaError> {
+    let text = token.get_token();
+    let chars = text.chars().collect::<Vec<char>>();
+    let mut items = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let rest = chars[index..].iter().collect::<String>();
+        if chars[index] == '\'' {
+            let length = char_literal_length(&chars[index..]).unwrap_or(0);
+            let literal = token.with_text(&rest.chars().take(length).collect::<String>());
+            match literal.from_char_to_i16() {
+                Some(value) => items.push(Item::Value(value as i32)),
+                None => {
+                    return Err(token.error(
+                        AssemblyErrorKind::InvalidLiteral,
+                        format!("Invalid char literal in expression '{}'.", text).as_str(),
+                    ));
+                }
+            }
+            index += length;
+        } else if is_name_char(chars[index]) {
+            let word = rest.chars().take_while(|c| is_name_char(*c)).collect::<String>();
+            index += word.chars().count();
+            if !word.starts_with(|c: char| c.is_ascii_digit()) {
+                items.push(Item::Name(word));
+                continue;
+            }
+            match token.with_text(&word).to_i32_value()? {
+                Some(value) => items.push(Item::Value(value)),
+                None => {
+                    return Err(token.error(
+                        AssemblyErrorKind::InvalidLiteral,
+                        format!("Invalid number '{}' in expression '{}'.", word, text).as_str(),
+                    ));
+                }
+            }
+        } else {
+            let operator = OPERATORS.into_iter().find(|operator| rest.starts_with(operator));
+            match operator {
+                Some(operator) => {
+                    items.push(Item::Operator(operator));
+                    index += operator.len();
+                },
+                None => {
+                    return Err(token.error(
+                        AssemblyErrorKind::InvalidLiteral,
+                        format!("Invalid character '{}' in expression '{}'.", chars[index], text).as_str(),
+                    ));
+                }
+            }
+        }
+    }
+    Ok(items)
+}
+
+fn to_i16(token: &Token, value: i32) -> Result<i16, IguanaError> {
+    i16::try_from(value).map_err(|_| overflow_error(token))
+}
+
+fn overflow_error(token: &Token) -> IguanaError {
+    token.error(
+        AssemblyErrorKind::LiteralOverflow,
+        format!("Expression '{}' overflows, its value must be in range of (-32768...32767).", token.get_token()).as_str(),
+    )
+}
+
+/// Recursive descent parser, the values are computed in 32 bits and checked against the i16 range at the end.
+struct Parser<'a> {
+    token: &'a Token,
+    items: Vec<Item>,
+    position: usize,
+    lookup: &'a dyn Fn(&str) -> Option<i32>,
+}
+
+impl Parser<'_> {
+    fn parse_binary(&mut self, level: usize) -> Result<i32, IguanaError> {
+        if level == PRECEDENCE.len() {
+            return self.parse_unary();
+        }
+
+        let mut value = self.parse_binary(level + 1)?;
+        while let Some(Item::Operator(operator)) = self.items.get(self.position) {
+            let operator = *operator;
+            if !PRECEDENCE[level].contains(&operator) {
+                break;
+            }
+            self.position += 1;
+            let right_value = self.parse_binary(level + 1)?;
+            value = self.apply(operator, value, right_value)?;
+        }
+        Ok(value)
+    }
+
+    fn parse_unary(&mut self) -> Result<i32, IguanaError> {
+        let item = match self.items.get(self.position) {
+            Some(item) => item.clone(),
+            None => return Err(self.expected_value_error()),
+        };
+        self.position += 1;
+
+        match item {
+            Item::Value(value) => Ok(value),
+            Item::Name(name) => match (self.lookup)(&name) {
+                Some(value) => Ok(value),
+                None => {
+                    Err(self.token.error(
+                        AssemblyErrorKind::UndefinedLabel,
+                        format!("Undefined label or constant '{}' in expression '{}'.", name, self.token.get_token()).as_str(),
+                    ))
+                }
+            },
+            Item::Operator("-") => self.parse_unary()?.checked_neg().ok_or_else(|| overflow_error(self.token)),
+            Item::Operator("+") => self.parse_unary(),
+            Item::Operator("~") => Ok(!self.parse_unary()?),
+            Item::Operator("!") => Ok((self.parse_unary()? == 0) as i32),
+            Item::Operator("(") => {
+                let value = self.parse_binary(0)?;
+                match self.items.get(self.position) {
+                    Some(Item::Operator(")")) => {
+                        self.position += 1;
+                        Ok(value)
+                    },
+                    _ => {
+                        Err(self.token.error(
+                            AssemblyErrorKind::InvalidLiteral,
+                            format!("Expected ')' to close '(' in expression '{}'.", self.token.get_token()).as_str(),
+                        ))
+                    }
+                }
+            },
+            Item::Operator(_) => {
+                self.position -= 1;
+                Err(self.expected_value_error())
+            },
+        }
+    }
+
+    fn apply(&self, operator: &str, left: i32, right: i32) -> Result<i32, IguanaError> {
+        if matches!(operator, "/" | "%") && right == 0 {
+            return Err(self.token.error(
+                AssemblyErrorKind::OperandOutOfRange,
+                format!("Division by zero in expression '{}'.", self.token.get_token()).as_str(),
+            ));
+        }
+        if matches!(operator, "<<" | ">>") && !(0..16).contains(&right) {
+            return Err(self.token.error(
+                AssemblyErrorKind::OperandOutOfRange,
+                format!("Invalid shift of {} bits in expression '{}', expected a value in range of (0...15).", right, self.token.get_token()).as_str(),
+            ));
+        }
+
+        let value = match operator {
+            "+" => left.checked_add(right),
+            "-" => left.checked_sub(right),
+            "*" => left.checked_mul(right),
+            "/" => left.checked_div(right),
+            "%" => left.checked_rem(right),
+            "<<" => left.checked_mul(1 << right),
+            ">>" => Some(left >> right),
+            "&" => Some(left & right),
+            "|" => Some(left | right),
+            "^" => Some(left ^ right),
+            "==" => Some((left == right) as i32),
+            "!=" => Some((left != right) as i32),
+            "<" => Some((left < right) as i32),
+            "<=" => Some((left <= right) as i32),
+            ">" => Some((left > right) as i32),
+            ">=" => Some((left >= right) as i32),
+            "&&" => Some((left != 0 && right != 0) as i32),
+            _ => Some((left != 0 || right != 0) as i32),
+        };
+        value.ok_or_else(|| overflow_error(self.token))
+    }
+
+    fn expected_value_error(&self) -> IguanaError {
+        let message = match self.items.get(self.position) {
+            Some(item) => format!("Expected a value, a label or '(' before {} in expression '{}'.", item_text(item), self.token.get_token()),
+            None => format!("Expected a value, a label or '(' at the end of expression '{}'.", self.token.get_token()),
+        };
+        self.token.error(AssemblyErrorKind::InvalidLiteral, &message)
+    }
+
+    fn unexpected_item_error(&self, item: &Item) -> IguanaError {
+        self.token.error(
+            AssemblyErrorKind::InvalidLiteral,
+            format!("Unexpected {} in expression '{}'.", item_text(item), self.token.get_token()).as_str(),
+        )
+    }
+}
+
+fn item_text(item: &Item) -> String {
+    match item {
+        Item::Value(value) => format!("value {}", value),
+        Item::Name(name) => format!("'{}'", name),
+        Item::Operator(operator) => format!("'{}'", operator),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::error::ErrorKind;
+    use crate::interpreter::tokenizer::tokenize;
+
+    fn lookup(name: &str) -> Option<i32> {
+        match name {
+            "SIZE" => Some(10),
+            "BUFFER" => Some(32767),
+            _ => None,
+        }
+    }
+
+    fn value(text: &str) -> Result<Option<i16>, IguanaError> {
+        evaluate(&Token::new().with_text(text), &lookup)
+    }
+
+    fn error_kind(text: &str) -> ErrorKind {
+        value(text).unwrap_err().kind
+    }
+
+    #[test]
+    fn follows_the_precedence_of_c() {
+        assert_eq!(value("1+2*3").unwrap(), Some(7));
+        assert_eq!(value("(1+2)*3").unwrap(), Some(9));
+        assert_eq!(value("10-4-3").unwrap(), Some(3));
+        assert_eq!(value("1<<2+1").unwrap(), Some(8));
+        assert_eq!(value("6&3|8").unwrap(), Some(10));
+        assert_eq!(value("1|6^3&5").unwrap(), Some(7));
+        assert_eq!(value("17/5%2").unwrap(), Some(1));
+        assert_eq!(value("1+1==2&&3>2").unwrap(), Some(1));
+        assert_eq!(value("0||2<=1").unwrap(), Some(0));
+    }
+
+    #[test]
+    fn applies_unary_operators() {
+        assert_eq!(value("-SIZE").unwrap(), Some(-10));
+        assert_eq!(value("--3").unwrap(), Some(3));
+        assert_eq!(value("+3").unwrap(), Some(3));
+        assert_eq!(value("~0").unwrap(), Some(-1));
+        assert_eq!(value("!0").unwrap(), Some(1));
+        assert_eq!(value("-(2*3)").unwrap(), Some(-6));
+    }
+
+    #[test]
+    fn reads_literals_and_names() {
+        assert_eq!(value("0x10+0b11").unwrap(), Some(19));
+        assert_eq!(value("BUFFER-1").unwrap(), Some(32766));
+        assert_eq!(value("SIZE*2").unwrap(), Some(20));
+        assert_eq!(value("'a'").unwrap(), Some(97));
+        assert_eq!(value("'a'+1").unwrap(), Some(98));
+        assert_eq!(value("'\\n'*2").unwrap(), Some(20));
+        assert_eq!(value("NOT_A_NAME").unwrap(), None);
+        assert_eq!(value("LOCO").unwrap(), None);
+    }
+
+    #[test]
+    fn char_literals_with_operators_are_one_token() {
+        let texts = tokenize("LOCO 'a'+1\n").iter().map(|token| token.get_token()).collect::<Vec<String>>();
+        assert_eq!(texts, ["LOCO", "'a'+1"]);
+        let token = tokenize("LOCO 'a'+1\n").pop().unwrap();
+        assert_eq!(evaluate(&token, &lookup).unwrap(), Some(98));
+    }
+
+    #[test]
+    fn shifts_only_by_0_to_15_bits() {
+        assert_eq!(value("1<<14").unwrap(), Some(16384));
+        assert_eq!(value("-32768>>15").unwrap(), Some(-1));
+        assert_eq!(error_kind("1<<16"), ErrorKind::Assembly(AssemblyErrorKind::OperandOutOfRange));
+        assert_eq!(error_kind("1>>-1"), ErrorKind::Assembly(AssemblyErrorKind::OperandOutOfRange));
+        assert_eq!(error_kind("1<<15"), ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+    }
+
+    #[test]
+    fn checks_the_i16_range() {
+        assert_eq!(value("32766+1").unwrap(), Some(32767));
+        assert_eq!(value("-32767-1").unwrap(), Some(-32768));
+        assert_eq!(value("(BUFFER+1)/2").unwrap(), Some(16384)); // only the result must fit
+        assert_eq!(error_kind("BUFFER+1"), ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+        assert_eq!(error_kind("-32767-2"), ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+        assert_eq!(error_kind("0x7fffffff*2"), ErrorKind::Assembly(AssemblyErrorKind::LiteralOverflow));
+    }
+
+    #[test]
+    fn rejects_division_by_zero() {
+        assert_eq!(error_kind("1/0"), ErrorKind::Assembly(AssemblyErrorKind::OperandOutOfRange));
+        assert_eq!(error_kind("1%(SIZE-10)"), ErrorKind::Assembly(AssemblyErrorKind::OperandOutOfRange));
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert_eq!(error_kind("MISSING+1"), ErrorKind::Assembly(AssemblyErrorKind::UndefinedLabel));
+        assert_eq!(error_kind("(1+2"), ErrorKind::Assembly(AssemblyErrorKind::InvalidLiteral));
+        assert_eq!(error_kind("1+"), ErrorKind::Assembly(AssemblyErrorKind::InvalidLiteral));
+        assert_eq!(error_kind("1)"), ErrorKind::Assembly(AssemblyErrorKind::InvalidLiteral));
+        assert_eq!(error_kind("1=2"), ErrorKind::Assembly(AssemblyErrorKind::InvalidLiteral));
+        assert_eq!(error_kind("12ab+1"), ErrorKind::Assembly(AssemblyErrorKind::InvalidLiteral));
+    }
+
+    #[test]
+    fn replaces_names_but_not_char_literals() {
+        let replaced = replace_names("N*2+'N'", &|name| (name == "N").then(|| "(X+1)".to_string()));
+        assert_eq!(replaced, "(X+1)*2+'N'");
+    }
+}
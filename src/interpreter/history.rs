@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+use super::virtual_machine::CallFrame;
+
+/// Default memory the undo log may use before dropping its oldest records.
+pub const DEFAULT_HISTORY_MEMORY_BUDGET: usize = 16 * 1024 * 1024;
+
+/// A stack word overwritten by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UndoWrite {
+    pub address: u32,
+    pub old_value: i16,
+    pub was_initialized: bool,
+}
+
+/// Machine state before one instruction, enough to undo it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub ac: i16,
+    pub sp: i16,
+    pub pc: u32,
+    pub exit_code: i32,
+    pub call_stack_len: usize,
+    pub popped_frame: Option<CallFrame>, // removed by a RETN
+    pub writes: Vec<UndoWrite>, // in execution order
+}
+
+impl UndoRecord {
+    fn memory_size(&self) -> usize {
+        size_of::<UndoRecord>() + self.writes.len() * size_of::<UndoWrite>()
+    }
+}
+
+/// Bounded undo log of the executed instructions, the oldest records are dropped
+/// once there are more than `max_length` of them or they use more than `memory_budget` bytes.
+#[derive(Debug, Clone)]
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    max_length: usize, // 0 disables the recording
+    memory_budget: usize,
+    memory_used: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(0, DEFAULT_HISTORY_MEMORY_BUDGET)
+    }
+}
+
+impl History {
+    pub fn new(max_length: usize, memory_budget: usize) -> History {
+        History {
+            records: VecDeque::new(),
+            max_length,
+            memory_budget,
+            memory_used: 0,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_length > 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn max_length(&self) -> usize {
+        self.max_length
+    }
+
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    pub fn set_limits(&mut self, max_length: usize, memory_budget: usize) {
+        self.max_length = max_length;
+        self.memory_budget = memory_budget;
+        self.shrink();
+    }
+
+    pub fn clear(&mut self) {
+        self.records.clear();
+        self.memory_used = 0;
+    }
+
+    /// Starts the record of the instruction about to be executed.
+    pub fn push(&mut self, record: UndoRecord) {
+        self.memory_used += record.memory_size();
+        self.records.push_back(record);
+        self.shrink();
+    }
+
+    /// Adds a stack write to the record of the instruction being executed.
+    pub fn record_write(&mut self, write: UndoWrite) {
+        if let Some(record) = self.records.back_mut() {
+            record.writes.push(write);
+            self.memory_used += size_of::<UndoWrite>();
+        }
+    }
+
+    /// Record of the last executed instruction.
+    pub fn last(&self) -> Option<&UndoRecord> {
+        self.records.back()
+    }
+
+    /// Keeps the call frame removed by the instruction being executed.
+    pub fn record_popped_frame(&mut self, frame: CallFrame) {
+        if let Some(record) = self.records.back_mut() {
+            record.popped_frame = Some(frame);
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<UndoRecord> {
+        let record = self.records.pop_back()?;
+        self.memory_used -= record.memory_size();
+        Some(record)
+    }
+
+    fn shrink(&mut self) {
+        while self.records.len() > self.max_length || (self.memory_used > self.memory_budget && self.records.len() > 1) {
+            match self.records.pop_front() {
+                Some(record) => self.memory_used -= record.memory_size(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(pc: u32) -> UndoRecord {
+        UndoRecord { ac: 0, sp: 0, pc, exit_code: 0, call_stack_len: 0, popped_frame: None, writes: Vec::new() }
+    }
+
+    #[test]
+    fn drops_the_oldest_records_past_the_max_length() {
+        let mut history = History::new(3, DEFAULT_HISTORY_MEMORY_BUDGET);
+        for pc in 0..5 {
+            history.push(record(pc));
+        }
+        assert_eq!(history.len(), 3);
+        assert_eq!(history.pop().unwrap().pc, 4);
+        assert_eq!(history.pop().unwrap().pc, 3);
+        assert_eq!(history.pop().unwrap().pc, 2);
+        assert!(history.pop().is_none());
+
+        history.set_limits(0, DEFAULT_HISTORY_MEMORY_BUDGET);
+        assert!(!history.is_enabled());
+    }
+
+    #[test]
+    fn drops_the_oldest_records_past_the_memory_budget_but_keeps_the_last() {
+        let budget = 2 * size_of::<UndoRecord>() + 3 * size_of::<UndoWrite>();
+        let mut history = History::new(100, budget);
+        history.push(record(0));
+        history.push(record(1));
+        for address in 0..4 {
+            history.record_write(UndoWrite { address, old_value: 0, was_initialized: false });
+        }
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.last().unwrap().writes.len(), 4);
+
+        history.push(record(2));
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.last().unwrap().pc, 2);
+
+        history.set_limits(100, 1);
+        assert_eq!(history.len(), 1);
+        history.clear();
+        assert!(history.is_empty());
+        history.push(record(3));
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn writes_and_frames_go_to_the_last_record() {
+        let mut history = History::new(10, DEFAULT_HISTORY_MEMORY_BUDGET);
+        history.record_write(UndoWrite { address: 1, old_value: 1, was_initialized: true }); // no record yet
+        history.push(record(0));
+        history.record_write(UndoWrite { address: 5, old_value: 7, was_initialized: true });
+        history.record_popped_frame(CallFrame { call_pc: 1, target_pc: 4, sp_at_entry: 100 });
+        let record = history.pop().unwrap();
+        assert_eq!(record.writes, [UndoWrite { address: 5, old_value: 7, was_initialized: true }]);
+        assert_eq!(record.popped_frame.unwrap().target_pc, 4);
+    }
+}
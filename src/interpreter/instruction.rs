@@ -1,14 +1,16 @@
 use super::opcode::Opcode;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct  Instruction {
     pub opcode: Opcode,
     pub arg: i16,
+    pub file: u32, // 0 for the assembled file, the id of an .include'd file otherwise
     pub line: u32,
     pub col: u32,
 }
 
 impl Instruction {
+    #[allow(clippy::wrong_self_convention)]
     pub fn to_format(&self) -> String {
         format!("{:08b}{:016b}", self.opcode as u8, self.arg)
     }
@@ -0,0 +1,140 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+
+/// Where the PRINT* operations write to and the INPUT* operations read from.
+pub trait IoDevice {
+    /// Reads one line of input, an empty string means the end of the input.
+    fn read_line(&mut self) -> io::Result<String>;
+
+    /// Reads one character, `None` when the input line doesn't hold exactly one character.
+    fn read_char(&mut self) -> io::Result<Option<char>> {
+        let line = self.read_line()?;
+        let mut chars = line.trim().chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => Ok(Some(c)),
+            _ => Ok(None),
+        }
+    }
+
+    fn write_text(&mut self, text: &str) -> io::Result<()>;
+}
+
+/// Reads from the standard input and writes to the standard output.
+#[derive(Debug, Default)]
+pub struct StdioDevice;
+
+impl IoDevice for StdioDevice {
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut input = String::new();
+        io::stdin().read_line(&mut input)?;
+        Ok(input)
+    }
+
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        let mut stdout = io::stdout();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()
+    }
+}
+
+/// Keeps the input and the output in memory, useful to drive programs from tests or other programs.
+/// Reading when there is no input left fails with `io::ErrorKind::WouldBlock`, more input can be pushed later.
+#[derive(Debug, Default)]
+pub struct BufferDevice {
+    input: VecDeque<String>,
+    output: String,
+}
+
+impl BufferDevice {
+    pub fn new(input: &str) -> BufferDevice {
+        let mut device = BufferDevice::default();
+        device.push_input(input);
+        device
+    }
+
+    /// Appends input lines, each line is consumed by one INPUT* operation.
+    pub fn push_input(&mut self, input: &str) {
+        for line in input.lines() {
+            self.input.push_back(format!("{}\n", line));
+        }
+    }
+
+    pub fn output(&self) -> &str {
+        &self.output
+    }
+
+    pub fn take_output(&mut self) -> String {
+        std::mem::take(&mut self.output)
+    }
+}
+
+impl IoDevice for BufferDevice {
+    fn read_line(&mut self) -> io::Result<String> {
+        match self.input.pop_front() {
+            Some(line) => Ok(line),
+            None => Err(io::Error::new(io::ErrorKind::WouldBlock, "no input available")),
+        }
+    }
+
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        self.output.push_str(text);
+        Ok(())
+    }
+}
+
+/// Reads the input from a file and writes the output to another one.
+#[derive(Debug)]
+pub struct FileDevice {
+    input: BufReader<File>,
+    output: File,
+}
+
+impl FileDevice {
+    pub fn open(input_path: &str, output_path: &str) -> io::Result<FileDevice> {
+        Ok(FileDevice {
+            input: BufReader::new(File::open(input_path)?),
+            output: File::create(output_path)?,
+        })
+    }
+}
+
+impl IoDevice for FileDevice {
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut input = String::new();
+        self.input.read_line(&mut input)?;
+        Ok(input)
+    }
+
+    fn write_text(&mut self, text: &str) -> io::Result<()> {
+        self.output.write_all(text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buffer_device_gives_one_line_per_read() {
+        let mut device = BufferDevice::new("12\nhello world");
+        assert_eq!(device.read_line().unwrap(), "12\n");
+        assert_eq!(device.read_line().unwrap(), "hello world\n");
+        assert_eq!(device.read_line().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        device.push_input("x\nxy\n");
+        assert_eq!(device.read_char().unwrap(), Some('x'));
+        assert_eq!(device.read_char().unwrap(), None);
+        assert_eq!(device.read_char().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn buffer_device_keeps_the_output_until_taken() {
+        let mut device = BufferDevice::default();
+        device.write_text("1").unwrap();
+        device.write_text("2\n").unwrap();
+        assert_eq!(device.output(), "12\n");
+        assert_eq!(device.take_output(), "12\n");
+        assert_eq!(device.output(), "");
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::{Duration, Instant};
+
+/// Bounds on the resources a program can use, `None` means unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ExecutionLimits {
+    pub max_steps: Option<u64>, // executed instructions
+    pub timeout: Option<Duration>, // wall-clock time since the first instruction
+    pub max_sleep: Option<Duration>, // total time of the SLEEPD and SLEEPI operations
+    pub max_output_bytes: Option<u64>, // bytes written by the PRINT* operations
+}
+
+impl ExecutionLimits {
+    pub fn with_max_steps(mut self, max_steps: u64) -> ExecutionLimits {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> ExecutionLimits {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn with_max_sleep(mut self, max_sleep: Duration) -> ExecutionLimits {
+        self.max_sleep = Some(max_sleep);
+        self
+    }
+
+    pub fn with_max_output_bytes(mut self, max_output_bytes: u64) -> ExecutionLimits {
+        self.max_output_bytes = Some(max_output_bytes);
+        self
+    }
+}
+
+/// What was used of the limits since the last reset.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    pub started_at: Option<Instant>,
+    pub sleep: Duration,
+    pub output_bytes: u64,
+}
+
+impl ResourceUsage {
+    /// Wall-clock time since the first instruction.
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.map_or(Duration::ZERO, |started_at| started_at.elapsed())
+    }
+}
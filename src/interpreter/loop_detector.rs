@@ -0,0 +1,169 @@
+use super::virtual_machine::CallFrame;
+
+/// A cycle of machine states: the instructions from `first_pc` to `last_pc` repeat every `period` steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DetectedLoop {
+    pub first_pc: u32,
+    pub last_pc: u32,
+    pub period: u64,
+}
+
+/// Machine state saved at a checkpoint, compared word by word when the hashes match.
+struct Checkpoint {
+    hash: u64,
+    pc: u32,
+    ac: i16,
+    sp: i16,
+    stack: Vec<i16>,
+    call_stack: Vec<CallFrame>,
+}
+
+/// Notices when the machine comes back to a state it was already in, which means it will loop forever
+/// as long as no input is read.
+/// The memory is hashed incrementally from the written words, and the state after every step is compared
+/// to a checkpoint that moves forward after 1, 2, 4, 8... steps (Brent's cycle detection), so any cycle
+/// is found within a few times its length without remembering every state.
+pub struct LoopDetector {
+    memory_hash: u64,
+    checkpoint: Option<Checkpoint>,
+    steps_since_checkpoint: u64,
+    steps_to_next_checkpoint: u64,
+    pc_range: Option<(u32, u32)>, // instructions executed since the checkpoint
+}
+
+impl LoopDetector {
+    pub fn new(stack: &[i16]) -> LoopDetector {
+        let mut detector = LoopDetector {
+            memory_hash: 0,
+            checkpoint: None,
+            steps_since_checkpoint: 0,
+            steps_to_next_checkpoint: 1,
+            pc_range: None,
+        };
+        detector.restart(stack);
+        detector
+    }
+
+    /// Forgets the states seen so far, e.g. after an input or a change made by a debugger.
+    pub fn restart(&mut self, stack: &[i16]) {
+        self.memory_hash = stack.iter().enumerate().fold(0, |hash, (address, value)| hash ^ word_hash(address, *value));
+        self.checkpoint = None;
+        self.steps_since_checkpoint = 0;
+        self.steps_to_next_checkpoint = 1;
+        self.pc_range = None;
+    }
+
+    pub fn record_write(&mut self, address: usize, old_value: i16, new_value: i16) {
+        self.memory_hash ^= word_hash(address, old_value) ^ word_hash(address, new_value);
+    }
+
+    /// Called after the instruction at `executed_pc` was executed, with the resulting state.
+    pub fn check(&mut self, executed_pc: u32, pc: u32, ac: i16, sp: i16, stack: &[i16], call_stack: &[CallFrame]) -> Option<DetectedLoop> {
+        let hash = self.memory_hash ^ mix(((pc as u64) << 32) | ((ac as u16 as u64) << 16) | sp as u16 as u64);
+
+        if let Some(checkpoint) = self.checkpoint.as_ref() {
+            self.steps_since_checkpoint += 1;
+            self.pc_range = Some(match self.pc_range {
+                Some((first_pc, last_pc)) => (first_pc.min(executed_pc), last_pc.max(executed_pc)),
+                None => (executed_pc, executed_pc),
+            });
+
+            let is_same_state = checkpoint.hash == hash
+                && checkpoint.pc == pc
+                && checkpoint.ac == ac
+                && checkpoint.sp == sp
+                && checkpoint.call_stack == call_stack
+                && checkpoint.stack == stack;
+            if is_same_state {
+                let (first_pc, last_pc) = self.pc_range.unwrap_or((pc, pc));
+                return Some(DetectedLoop { first_pc, last_pc, period: self.steps_since_checkpoint });
+            }
+            if self.steps_since_checkpoint < self.steps_to_next_checkpoint {
+                return None;
+            }
+        }
+
+        self.checkpoint = Some(Checkpoint { hash, pc, ac, sp, stack: stack.to_vec(), call_stack: call_stack.to_vec() });
+        self.steps_to_next_checkpoint *= 2;
+        self.steps_since_checkpoint = 0;
+        self.pc_range = None;
+        None
+    }
+}
+
+fn word_hash(address: usize, value: i16) -> u64 {
+    mix(((address as u64) << 16) | value as u16 as u64)
+}
+
+/// splitmix64 finalizer.
+fn mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Executes the instructions 0..length over and over without changing anything else, until a loop is found.
+    fn detect_cycle(detector: &mut LoopDetector, stack: &[i16], length: u32, max_steps: u32) -> Option<(u32, DetectedLoop)> {
+        (1..=max_steps).find_map(|step| {
+            let executed_pc = (step - 1) % length;
+            detector.check(executed_pc, step % length, 0, 10, stack, &[]).map(|detected| (step, detected))
+        })
+    }
+
+    #[test]
+    fn finds_a_cycle_with_its_period_and_instructions() {
+        let stack = vec![0; 16];
+        let mut detector = LoopDetector::new(&stack);
+        let (step, detected) = detect_cycle(&mut detector, &stack, 3, 100).unwrap();
+        assert_eq!(detected, DetectedLoop { first_pc: 0, last_pc: 2, period: 3 });
+        assert!(step <= 3 * 4);
+    }
+
+    #[test]
+    fn states_with_other_memory_are_not_a_cycle() {
+        let mut stack = vec![0; 16];
+        let mut detector = LoopDetector::new(&stack);
+        for step in 0..1000 {
+            // the same instruction increments M[5] every time
+            let old_value = stack[5];
+            stack[5] += 1;
+            detector.record_write(5, old_value, stack[5]);
+            assert_eq!(detector.check(0, 0, 0, 10, &stack, &[]), None, "step {}", step);
+        }
+        let (_, detected) = detect_cycle(&mut detector, &stack, 1, 100).unwrap();
+        assert_eq!(detected.period, 1);
+    }
+
+    #[test]
+    fn calls_are_part_of_the_state() {
+        let stack = vec![0; 16];
+        let mut detector = LoopDetector::new(&stack);
+        let mut call_stack = Vec::new();
+        for step in 0..100 {
+            call_stack.push(CallFrame { call_pc: 0, target_pc: 0, sp_at_entry: 10 });
+            assert_eq!(detector.check(0, 0, 0, 10, &stack, &call_stack), None, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn restart_forgets_the_states_seen() {
+        let mut stack = vec![0; 16];
+        let mut detector = LoopDetector::new(&stack);
+        assert_eq!(detector.check(0, 1, 0, 10, &stack, &[]), None);
+
+        // e.g. a debugger going back to the state before the checkpoint and executing it again
+        detector.restart(&stack);
+        assert_eq!(detector.check(0, 1, 0, 10, &stack, &[]), None);
+
+        // the memory hash is computed again from the changed memory, the cycle is still found
+        stack[3] = 7;
+        detector.restart(&stack);
+        let (_, detected) = detect_cycle(&mut detector, &stack, 2, 100).unwrap();
+        assert_eq!(detected.period, 2);
+    }
+}
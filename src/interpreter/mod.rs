@@ -1,5 +1,14 @@
+pub mod assembler;
+pub mod error;
+pub mod expression;
+pub mod history;
+pub mod instruction;
+pub mod io_device;
+pub mod limits;
+pub mod loop_detector;
+pub mod opcode;
+pub mod program;
+pub mod token;
+pub mod tokenizer;
 pub mod virtual_machine;
-mod instruction;
-mod opcode;
-mod token;
-mod tokenizer;
\ No newline at end of file
+pub mod watchpoint;
\ No newline at end of file
@@ -1,4 +1,6 @@
-#[derive(Debug, Clone, Copy, PartialEq)]
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Opcode {
     Lodd,
     Stod,
@@ -59,22 +61,121 @@ pub enum Opcode {
     Printlninstruction, Printinstruction, 
 
     Inputac, Inputacchar, Inputstring,
+
+    Exit, Haltac,
 }
 
 
 impl Opcode {
+    /// Every opcode, in declaration order.
+    pub const ALL: [Opcode; 55] = [
+        Opcode::Lodd, Opcode::Stod, Opcode::Addd, Opcode::Subd, Opcode::Jpos, Opcode::Jzer, Opcode::Jump, Opcode::Loco,
+        Opcode::Lodl, Opcode::Stol, Opcode::Addl, Opcode::Subl, Opcode::Jneg, Opcode::Jnze, Opcode::Call,
+        Opcode::Pshi, Opcode::Popi, Opcode::Push, Opcode::Pop, Opcode::Retn, Opcode::Swap, Opcode::Insp, Opcode::Desp,
+        Opcode::Halt, Opcode::Andi, Opcode::Ori, Opcode::Xori, Opcode::Not, Opcode::Shfli, Opcode::Shfri,
+        Opcode::Andd, Opcode::Ord, Opcode::Xord, Opcode::Notd, Opcode::Shfld, Opcode::Shfrd,
+        Opcode::Muld, Opcode::Divd, Opcode::Mull, Opcode::Divl,
+        Opcode::Sleepd, Opcode::Sleepi,
+        Opcode::Printlnac, Opcode::Printac, Opcode::Printlnacchar, Opcode::Printacchar,
+        Opcode::Printlnsp, Opcode::Printsp, Opcode::Printlninstruction, Opcode::Printinstruction,
+        Opcode::Inputac, Opcode::Inputacchar, Opcode::Inputstring,
+        Opcode::Exit, Opcode::Haltac,
+    ];
+
     pub fn is_argumented(op: Opcode) -> bool {
-        match op {
+        !matches!(
+            op,
             Opcode::Pshi | Opcode::Popi | Opcode::Push | Opcode::Pop | Opcode::Retn | Opcode::Swap | Opcode::Halt |
             Opcode::Not |
             Opcode::Printlnac | Opcode::Printac | Opcode::Printlnacchar | Opcode::Printacchar    | Opcode::Printsp | Opcode::Printlnsp |
-            Opcode::Inputac | Opcode::Inputacchar
-                => false,
-            _
-                => true,
+            Opcode::Inputac | Opcode::Inputacchar |
+            Opcode::Haltac
+        )
+    }
+
+    /// Debug, input and sleep operations, which only exist in the interpreter and cannot be exported to a binary.
+    pub fn is_system_call(op: Opcode) -> bool {
+        matches!(
+            op,
+            Opcode::Printlnac | Opcode::Printac | Opcode::Printlnacchar | Opcode::Printacchar | Opcode::Printsp | Opcode::Printlnsp |
+            Opcode::Printlninstruction | Opcode::Printinstruction |
+            Opcode::Inputac | Opcode::Inputacchar | Opcode::Inputstring |
+            Opcode::Sleepd | Opcode::Sleepi
+        )
+    }
+
+    /// Control flow and instruction printing operations take a source line as argument,
+    /// which is resolved to an instruction offset after the second pass.
+    pub fn takes_line_argument(op: Opcode) -> bool {
+        matches!(
+            op,
+            Opcode::Jpos | Opcode::Jzer | Opcode::Jump | Opcode::Jneg | Opcode::Jnze | Opcode::Call |
+            Opcode::Printlninstruction | Opcode::Printinstruction
+        )
+    }
+
+    /// What the instruction does, as written in the README.
+    pub fn pseudo_behavior(op: Opcode) -> &'static str {
+        match op {
+            Opcode::Lodd => "ac = M[X]",
+            Opcode::Stod => "M[X] = ac",
+            Opcode::Addd => "ac = ac + M[X]",
+            Opcode::Subd => "ac = ac - M[X]",
+            Opcode::Jpos => "if ac >= 0: pc = pc_of_instruction_on_line( X ), else: pc = pc + 1",
+            Opcode::Jzer => "if ac == 0: pc = pc_of_instruction_on_line( X ), else: pc = pc + 1",
+            Opcode::Jump => "pc = pc_of_instruction_on_line( X )",
+            Opcode::Loco => "ac = X",
+            Opcode::Lodl => "ac = M[sp + X]",
+            Opcode::Stol => "M[sp + X] = ac",
+            Opcode::Addl => "ac = ac + M[sp + X]",
+            Opcode::Subl => "ac = ac - M[sp + X]",
+            Opcode::Jneg => "if ac < 0: pc = pc_of_instruction_on_line( X ), else: pc = pc + 1",
+            Opcode::Jnze => "if ac != 0: pc = pc_of_instruction_on_line( X ), else: pc = pc + 1",
+            Opcode::Call => "sp = sp - 1; M[sp] = pc + 1; pc = pc_of_instruction_on_line( X )",
+            Opcode::Pshi => "sp = sp - 1; M[sp] = M[ac]",
+            Opcode::Popi => "M[ac] = M[sp]; sp = sp + 1",
+            Opcode::Push => "sp = sp - 1; M[sp] = ac",
+            Opcode::Pop => "ac = M[sp]; sp = sp + 1",
+            Opcode::Retn => "pc = M[sp]; sp = sp + 1",
+            Opcode::Swap => "temp = ac; ac = sp; sp = temp",
+            Opcode::Insp => "sp = sp + X",
+            Opcode::Desp => "sp = sp - X",
+            Opcode::Halt => "end_program",
+            Opcode::Andi => "ac = ac & X",
+            Opcode::Ori => "ac = ac | X",
+            Opcode::Xori => "ac = ac ^ X",
+            Opcode::Not => "ac = ~ac",
+            Opcode::Shfli => "ac = ac << X",
+            Opcode::Shfri => "ac = ac >> X",
+            Opcode::Andd => "ac = ac & M[X]",
+            Opcode::Ord => "ac = ac | M[X]",
+            Opcode::Xord => "ac = ac ^ M[X]",
+            Opcode::Notd => "ac = ~M[X]",
+            Opcode::Shfld => "ac = ac << M[X]",
+            Opcode::Shfrd => "ac = ac >> M[X]",
+            Opcode::Muld => "ac = ac * M[X]",
+            Opcode::Divd => "ac = ac / M[X]",
+            Opcode::Mull => "ac = ac * M[sp + X]",
+            Opcode::Divl => "ac = ac / M[sp + X]",
+            Opcode::Sleepd => "sleep_in_milliseconds( M[X] )",
+            Opcode::Sleepi => "sleep_in_milliseconds( X )",
+            Opcode::Printlnac => "print( ac + '\\n' )",
+            Opcode::Printac => "print( ac )",
+            Opcode::Printlnacchar => "print( char( ac ) + '\\n' )",
+            Opcode::Printacchar => "print( char( ac ) )",
+            Opcode::Printlnsp => "print( sp + '\\n' )",
+            Opcode::Printsp => "print( sp )",
+            Opcode::Printlninstruction => "print( format( pc_of_instruction_on_line( X ) ) + '\\n' )",
+            Opcode::Printinstruction => "print( format( pc_of_instruction_on_line( X ) ) )",
+            Opcode::Inputac => "ac = input_number()",
+            Opcode::Inputacchar => "ac = number( input_character() )",
+            Opcode::Inputstring => "M[X] = input_string() + '\\0'",
+            Opcode::Exit => "end_program_with_exit_code( X )",
+            Opcode::Haltac => "end_program_with_exit_code( ac )",
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(name: &str) -> Option<Opcode> {
         match name {
             "LODD" => Some(Opcode::Lodd),
@@ -140,7 +241,17 @@ impl Opcode {
             "INPUTACCHAR" => Some(Opcode::Inputacchar),
             "INPUTSTRING" => Some(Opcode::Inputstring),
 
+            "EXIT" => Some(Opcode::Exit),
+            "HALTAC" => Some(Opcode::Haltac),
+
             _ => None,
         }
     }
-}
\ No newline at end of file
+}
+
+impl fmt::Display for Opcode {
+    /// The mnemonic, e.g. `LODD`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
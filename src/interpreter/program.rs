@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use super::error::IguanaError;
+use super::instruction::Instruction;
+
+pub const STACK_SIZE: usize = 32768;
+
+/// What the memory holds before the data section is loaded, i.e. what uninitialized words read as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryFill {
+    Zero,
+    Pattern(i16), // e.g. 0xDEAD
+    Random(Option<u64>), // seeded when the seed is given, so runs can be reproduced
+}
+
+impl Default for MemoryFill {
+    fn default() -> Self {
+        MemoryFill::Random(None)
+    }
+}
+
+impl MemoryFill {
+    pub fn fill(&self, memory: &mut [i16]) {
+        match self {
+            MemoryFill::Zero => memory.fill(0),
+            MemoryFill::Pattern(value) => memory.fill(*value),
+            MemoryFill::Random(None) => {
+                for item in memory.iter_mut() {
+                    *item = rand::random::<i16>();
+                }
+            },
+            MemoryFill::Random(Some(seed)) => {
+                let mut rng = StdRng::seed_from_u64(*seed);
+                for item in memory.iter_mut() {
+                    *item = rng.random::<i16>();
+                }
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: u32,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// A file read by the assembler, the assembled file is the file 0 and the `.include`d files follow in the order they are found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceFile {
+    pub path: String,
+    pub source: String,
+}
+
+/// Maps instructions back to the source text they were assembled from.
+/// The lines without a file are the lines of the assembled file.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    paths: Vec<String>, // indexed by file id
+    lines: Vec<Vec<String>>, // indexed by file id
+    locations: Vec<SourceLocation>, // indexed by instruction index (pc)
+}
+
+impl SourceMap {
+    pub fn new(files: &[SourceFile], instructions: &[Instruction]) -> SourceMap {
+        SourceMap {
+            paths: files.iter().map(|file| file.path.clone()).collect(),
+            lines: files.iter().map(|file| file.source.lines().map(|line| line.to_string()).collect()).collect(),
+            locations: instructions.iter()
+                .map(|instruction| SourceLocation { file: instruction.file, line: instruction.line, col: instruction.col })
+                .collect(),
+        }
+    }
+
+    /// Source location of the instruction at `pc`.
+    pub fn location(&self, pc: u32) -> Option<SourceLocation> {
+        self.locations.get(pc as usize).copied()
+    }
+
+    /// Text of a source line, lines start at 1.
+    pub fn line_text(&self, line: u32) -> Option<&str> {
+        self.file_line_text(0, line)
+    }
+
+    /// Text of a line of the file `file`, lines start at 1.
+    pub fn file_line_text(&self, file: u32, line: u32) -> Option<&str> {
+        match line {
+            0 => None,
+            _ => self.lines.get(file as usize)?.get(line as usize - 1).map(|text| text.as_str()),
+        }
+    }
+
+    pub fn line_count(&self) -> u32 {
+        self.lines.first().map_or(0, |lines| lines.len() as u32)
+    }
+
+    /// Paths of the files indexed by their ids, the path of the assembled file is empty when it was not read from a file.
+    pub fn file_paths(&self) -> &[String] {
+        &self.paths
+    }
+
+    /// Path of an `.include`d file, `None` for the assembled file.
+    pub fn file_path(&self, file: u32) -> Option<&str> {
+        match file {
+            0 => None,
+            _ => self.paths.get(file as usize).map(|path| path.as_str()),
+        }
+    }
+
+    /// Index of the first instruction of the assembled file declared at or after `line`.
+    pub fn instruction_index_by_line(&self, line: u32) -> Option<u32> {
+        self.file_instruction_index_by_line(0, line)
+    }
+
+    /// Index of the first instruction of the file `file` declared at or after `line`.
+    pub fn file_instruction_index_by_line(&self, file: u32, line: u32) -> Option<u32> {
+        self.locations.iter()
+            .position(|location| location.file == file && location.line >= line)
+            .map(|index| index as u32)
+    }
+}
+
+/// A label declared in the .data section, its words go from `address` down to `address - words + 1`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataSymbol {
+    pub name: String,
+    pub address: u32,
+    pub words: u32,
+}
+
+impl DataSymbol {
+    /// Lowest address of the label's words.
+    pub fn last_address(&self) -> u32 {
+        (self.address + 1).saturating_sub(self.words.max(1))
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        (self.last_address()..=self.address).contains(&address)
+    }
+}
+
+/// An assembled program, ready to be loaded by a `VirtualMachine` as many times as needed.
+#[derive(Debug, Clone, Default)]
+pub struct Program {
+    pub instructions: Vec<Instruction>,
+
+    /// Initial data memory. `data[i]` lives at stack address `STACK_SIZE - 1 - i`,
+    /// words reserved by `.space` are `None` and keep whatever the memory had.
+    pub data: Vec<Option<i16>>,
+
+    pub symbol_table: HashMap<String, u32>, // label -> line for .text labels, label -> stack address for .data labels
+    pub data_symbols: Vec<DataSymbol>, // .data labels in declaration order
+    pub text_label_indices: HashMap<String, u32>, // .text label -> index of the instruction it names
+    pub constants: HashMap<String, i16>, // .equ constants
+
+    pub source_map: SourceMap,
+}
+
+impl Program {
+    /// Stack pointer at the start of the execution, pointing to the last data word.
+    pub fn initial_sp(&self) -> i16 {
+        if self.data.is_empty() {
+            (STACK_SIZE - 1) as i16
+        } else {
+            (STACK_SIZE - self.data.len()) as i16
+        }
+    }
+
+    pub fn data_symbol(&self, name: &str) -> Option<&DataSymbol> {
+        self.data_symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// Source line of a .text label.
+    pub fn text_label_line(&self, name: &str) -> Option<u32> {
+        match self.data_symbol(name) {
+            Some(_) => None,
+            None => self.symbol_table.get(name).copied(),
+        }
+    }
+
+    /// .text labels and their source lines, sorted by line.
+    pub fn text_labels(&self) -> Vec<(String, u32)> {
+        let mut labels = self.symbol_table.iter()
+            .filter(|(name, _)| self.data_symbol(name).is_none())
+            .map(|(name, line)| (name.clone(), *line))
+            .collect::<Vec<(String, u32)>>();
+        labels.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        labels
+    }
+
+    /// .text label of the instruction at `pc`, the first in alphabetical order when it has many.
+    pub fn text_label_at(&self, pc: u32) -> Option<String> {
+        self.text_label_indices.iter()
+            .filter(|(_, index)| **index == pc)
+            .map(|(name, _)| name)
+            .min()
+            .cloned()
+    }
+
+    /// Name of the function starting at `pc`: its .text label, or its instruction index when it has no label.
+    pub fn function_name(&self, pc: u32) -> String {
+        self.text_label_at(pc).unwrap_or_else(|| format!("pc_{}", pc))
+    }
+
+    /// Source lines from a .text label up to the line before the next label.
+    pub fn text_label_lines(&self, name: &str) -> Option<(u32, u32)> {
+        let first_line = self.text_label_line(name)?;
+        let last_line = self.text_labels().iter()
+            .map(|(_, line)| *line)
+            .find(|line| *line > first_line)
+            .map_or(u32::MAX, |line| line - 1);
+        Some((first_line, last_line))
+    }
+
+    /// Address range named by a data label, an address or `first..last`, lowest address first.
+    pub fn resolve_address_range(&self, target: &str) -> Option<(u32, u32)> {
+        if let Some((first, last)) = target.split_once("..") {
+            let (first, last) = (self.resolve_address_range(first.trim())?.0, self.resolve_address_range(last.trim())?.1);
+            return Some((first.min(last), first.max(last)));
+        }
+        if let Some(symbol) = self.data_symbol(target) {
+            return Some((symbol.last_address(), symbol.address));
+        }
+        let address = match target.strip_prefix("0x").or_else(|| target.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => target.parse::<u32>().ok()?,
+        };
+        match (address as usize) < STACK_SIZE {
+            true => Some((address, address)),
+            false => None,
+        }
+    }
+
+    /// Data label holding `address`, like `COUNT` or `BUFFER-3` for the fourth word of `BUFFER`.
+    pub fn describe_address(&self, address: u32) -> Option<String> {
+        let symbol = self.data_symbols.iter().find(|symbol| symbol.words > 0 && symbol.contains(address))?;
+        match symbol.address - address {
+            0 => Some(symbol.name.clone()),
+            offset => Some(format!("{}-{}", symbol.name, offset)),
+        }
+    }
+
+    /// Whole stack as it is before the execution: the data section over the memory `fill`.
+    pub fn initial_memory(&self, fill: MemoryFill) -> Vec<i16> {
+        let mut memory = vec![0; STACK_SIZE];
+        fill.fill(&mut memory);
+        for (i, value) in self.data.iter().enumerate() {
+            if let Some(value) = value {
+                memory[Program::data_address(i)] = *value;
+            }
+        }
+        memory
+    }
+
+    /// Address of the `index`-th data word.
+    pub fn data_address(index: usize) -> usize {
+        STACK_SIZE - 1 - index
+    }
+
+    /// Words reserved by `.space` are written with the value given by `fill`.
+    pub fn write_binary(&self, output_path: &str, fill: MemoryFill) -> Result<(), IguanaError> {
+        let write_error = |_| IguanaError::io("Error writing in the output file.");
+        let mut output_file = File::create(output_path)
+            .map_err(|_| IguanaError::io("Error creating the output file."))?;
+
+        output_file.write_all(".text\n".as_bytes()).map_err(write_error)?;
+
+        for instr in self.instructions.iter() {
+            let instr_in_binary = instr.to_format();
+            output_file.write_all( format!( "{}\n", instr_in_binary ).as_bytes() ).map_err(write_error)?;
+        }
+
+        output_file.write_all(".data\n".as_bytes()).map_err(write_error)?;
+
+        let memory = self.initial_memory(fill);
+        for i in 0..self.data.len() {
+            let number_in_binary = format!("{:016b}", memory[Program::data_address(i)]);
+            output_file.write_all( format!( "{}\n", number_in_binary ).as_bytes() ).map_err(write_error)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+
+    #[test]
+    fn seeded_random_fill_is_deterministic() {
+        let fill = |memory_fill: MemoryFill| {
+            let mut memory = vec![0; 64];
+            memory_fill.fill(&mut memory);
+            memory
+        };
+        assert_eq!(fill(MemoryFill::Random(Some(42))), fill(MemoryFill::Random(Some(42))));
+        assert_ne!(fill(MemoryFill::Random(Some(42))), fill(MemoryFill::Random(Some(43))));
+        assert_eq!(fill(MemoryFill::Zero), vec![0; 64]);
+        assert_eq!(fill(MemoryFill::Pattern(0xDEADu16 as i16)), vec![0xDEADu16 as i16; 64]);
+    }
+
+    #[test]
+    fn data_section_is_written_over_the_fill() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(".data\n    X: .word 7\n    Y: .space 2\n.text\n    HALT\n").unwrap();
+        let memory = program.initial_memory(MemoryFill::Pattern(-1));
+        assert_eq!(memory.len(), STACK_SIZE);
+        assert_eq!(memory[Program::data_address(0)], 7);
+        assert_eq!(memory[Program::data_address(1)], -1);
+        assert_eq!(program.initial_memory(MemoryFill::Random(Some(1))), program.initial_memory(MemoryFill::Random(Some(1))));
+    }
+}
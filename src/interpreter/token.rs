@@ -1,17 +1,78 @@
-use super::super::logkit;
+use super::error::{AssemblyErrorKind, IguanaError, RelatedLocation};
 
-#[derive(Debug, Clone)] 
+/// Where a token written inside a `.macro` definition comes from, once expanded at an invocation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroOrigin {
+    pub name: String,
+    pub file: u32,
+    pub line: u32,
+    pub col: u32,
+}
+
+/// `line` and `col` are the position in the source, the invocation's for tokens expanded from a macro.
+/// `file` is 0 for the assembled file and the id of an `.include`d file otherwise.
+#[derive(Debug, Clone)]
 pub struct Token {
     token: String,
+    pub file: u32,
     pub line: u32,
     pub col: u32,
+    pub macro_origin: Option<Box<MacroOrigin>>,
+}
+
+impl Default for Token {
+    fn default() -> Self {
+        Token::new()
+    }
 }
+
+#[allow(clippy::wrong_self_convention)]
 impl Token {
     pub fn new() -> Token {
         Token {
             token: String::new(),
+            file: 0,
             line: 1,
             col: 1,
+            macro_origin: None,
+        }
+    }
+
+    /// A token with the same position and origin as this one.
+    pub fn with_text(&self, text: &str) -> Token {
+        Token {
+            token: text.to_string(),
+            ..self.clone()
+        }
+    }
+
+    /// Assembly error at the token, which also points to the macro definition when the token was expanded from one.
+    pub fn error(&self, kind: AssemblyErrorKind, message: &str) -> IguanaError {
+        self.error_at_col(kind, message, self.col)
+    }
+
+    fn error_at_col(&self, kind: AssemblyErrorKind, message: &str, col: u32) -> IguanaError {
+        let error = IguanaError::assembly(kind, message, self.line, col).in_file(self.file);
+        match self.macro_origin.as_deref() {
+            Some(origin) => error.with_related(RelatedLocation {
+                message: format!("in macro '{}'", origin.name),
+                file: origin.file,
+                file_path: None,
+                line: origin.line,
+                col: origin.col,
+            }),
+            None => error,
+        }
+    }
+
+    /// The position of the token, to be shown with an error found somewhere else.
+    pub fn related_location(&self, message: &str) -> RelatedLocation {
+        RelatedLocation {
+            message: message.to_string(),
+            file: self.file,
+            file_path: None,
+            line: self.line,
+            col: self.col,
         }
     }
 
@@ -32,16 +93,16 @@ impl Token {
     }
 
     pub fn is_string_literal(&self) -> bool {
-        self.token.chars().nth(0) == Some('"') && self.token.chars().nth(self.token.len()-1) == Some('"')
+        self.token.starts_with('"') && self.token.ends_with('"')
     }
 
     pub fn is_char_literal(&self) -> bool { // e.g.: 'a', '\n', '\t', '\0', '\'', '"', '\\'
-        self.token.len() == 3 && self.token.chars().nth(0) == Some('\'') && self.token.chars().nth(2) == Some('\'')
+        self.token.len() == 3 && self.token.starts_with('\'') && self.token.chars().nth(2) == Some('\'')
         || self.token == "'\\n'" || self.token == "'\\t'" || self.token == "'\\0'" || self.token == "'\\''" || self.token == "'\"'" || self.token == "'\\\"'" || self.token == "'\\\\'" || self.token == "'\\r'"
     }
 
     pub fn from_char_to_i16(&self) -> Option<i16> {
-        if self.token.len() == 3 && self.token.chars().nth(0) == Some('\'') && self.token.chars().nth(2) == Some('\'') {
+        if self.token.len() == 3 && self.token.starts_with('\'') && self.token.chars().nth(2) == Some('\'') {
             Some(self.token.chars().nth(1).unwrap() as i16)
         } else {
             match self.token.as_str() {
@@ -59,79 +120,79 @@ impl Token {
         }
     }
 
+    /// A global label like `LOOP:`, a local label like `.loop:` or a numeric label like `1:`.
     pub fn is_label(&self) -> bool {
-        self.token.len() >= 2 
-        && self.token.chars().nth(self.token.len()-1) == Some(':') 
-        && !self.token.chars().nth(0).unwrap().is_digit(10) 
-        && (self.token.chars().nth(0).unwrap().is_alphabetic() || self.token.chars().nth(0).unwrap() == '_')
+        self.is_global_label() || self.is_local_label() || self.is_numeric_label()
+    }
+
+    pub fn is_global_label(&self) -> bool {
+        self.token.len() >= 2
+        && self.token.ends_with(':')
+        && !self.token.chars().next().unwrap().is_ascii_digit()
+        && (self.token.chars().next().unwrap().is_alphabetic() || self.token.starts_with('_'))
+    }
+
+    /// `.loop:`, scoped to the last global label.
+    pub fn is_local_label(&self) -> bool {
+        self.token.len() >= 3
+        && self.token.starts_with('.')
+        && self.token.ends_with(':')
+        && (self.token.chars().nth(1).unwrap().is_alphabetic() || self.token.chars().nth(1) == Some('_'))
     }
 
-    pub fn to_string_literal(&self) -> Option<String> {
+    /// `1:`, referenced as `1f` (the next one) or `1b` (the previous one).
+    pub fn is_numeric_label(&self) -> bool {
+        self.token.len() >= 2
+        && self.token.ends_with(':')
+        && self.token[..self.token.len() - 1].chars().all(|c| c.is_ascii_digit())
+    }
+
+    pub fn to_string_literal(&self) -> Result<Option<String>, IguanaError> {
         let string_to_process = self.get_token(); // e.g.: <"Hello, World!\n"> ou <"Hello, World!"> ou <"\n\t\0"> ou <"\""> ou <"\\">
         let mut processed_string = String::new();
-        
-    
+
+
         // Verifica se a string está corretamente entre aspas
-        if string_to_process.len() >= 2 && string_to_process.chars().nth(0) == Some('"') && string_to_process.chars().nth(string_to_process.len() - 1) == Some('"') {
+        if string_to_process.len() >= 2 && string_to_process.starts_with('"') && string_to_process.ends_with('"') {
             let mut str_counter = 1;
             while str_counter < string_to_process.len() - 1 {
                 if string_to_process.chars().nth(str_counter) == Some('\\') {
-                    match string_to_process.chars().nth(str_counter + 1) {
-                        Some('\\') => {
-                            // Adiciona uma barra invertida literal
-                            processed_string.push('\\');
-                            str_counter += 2; // Pula a próxima barra invertida
-                        }
-                        Some('n') => {
-                            processed_string.push('\n');
-                            str_counter += 2;
-                        }
-                        Some('t') => {
-                            processed_string.push('\t');
-                            str_counter += 2;
-                        }
-                        Some('r') => {
-                            processed_string.push('\r');
-                            str_counter += 2;
-                        }
-                        Some('0') => {
-                            processed_string.push('\0');
-                            str_counter += 2;
-                        }
-                        Some('\'') => {
-                            processed_string.push('\'');
-                            str_counter += 2;
-                        }
-                        Some('"') => {
-                            processed_string.push('"');
-                            str_counter += 2;
-                        }
+                    let escaped = match string_to_process.chars().nth(str_counter + 1) {
+                        Some('\\') => '\\', // Adiciona uma barra invertida literal
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('\'') => '\'',
+                        Some('"') => '"',
                         _ => {
-                            logkit::exit_with_positional_error_message(
+                            return Err(self.error_at_col(
+                                AssemblyErrorKind::InvalidLiteral,
                                 format!("Invalid escape sequence '{}' in string literal.", string_to_process).as_str(),
-                                self.line,
                                 self.col + str_counter as u32,
-                            );
+                            ));
                         }
-                    }
+                    };
+                    processed_string.push(escaped);
+                    str_counter += 2; // Pula o caractere escapado
                 } else {
                     processed_string.push(string_to_process.chars().nth(str_counter).unwrap());
                     str_counter += 1;
                 }
             }
         } else {
-            return None; 
+            return Ok(None);
         }
-    
-        Some(processed_string)
+
+        Ok(Some(processed_string))
     }
 
     pub fn is_hex_literal(&self) -> bool {
-        self.token.len() >= 3 && self.token.chars().nth(0) == Some('0') && ( self.token.chars().nth(1) == Some('x') || self.token.chars().nth(1) == Some('X') )
+        self.token.len() >= 3 && self.token.starts_with('0') && ( self.token.chars().nth(1) == Some('x') || self.token.chars().nth(1) == Some('X') )
     }
 
-    pub fn from_hex_to_i16(&self) -> Option<i16> {// if hex_number >= i16::MAX then None 
-        let hex_string = self.token.clone().to_lowercase();
+    pub fn from_hex_to_i16(&self) -> Result<i16, IguanaError> {// if hex_number > i16::MAX then Err
+        let hex_string = self.token.to_lowercase();
         let mut hex_number: i16 = 0;
         let mut hex_counter = 2;
         while hex_counter < hex_string.len() {
@@ -153,46 +214,29 @@ impl Token {
                 Some('e') => 14,
                 Some('f') => 15,
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid hexadecimal literal. Ensure it starts with '0x' and contains valid digits.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             };
             // its like: hex_number = hex_number * 16 + hex_digit;
-            match hex_number.checked_mul(16) {
-                Some(result) => {
-                    match result.checked_add(hex_digit) {
-                        Some(result) => hex_number = result,
-                        None => {
-                            logkit::exit_with_positional_error_message(
-                                "Hexadecimal literal overflow. Value must be between 0x0 and 0x7fff.",
-                                self.line,
-                                self.col,
-                            );
-                            return None;
-                        }
-                    }
-                }
+            match hex_number.checked_mul(16).and_then(|result| result.checked_add(hex_digit)) {
+                Some(result) => hex_number = result,
                 None => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::LiteralOverflow,
                         "Hexadecimal literal overflow. Value must be between 0x0 and 0x7fff.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             }
             hex_counter += 1;
         }
-        Some(hex_number)
-
+        Ok(hex_number)
     }
-    
-    pub fn from_hex_to_i32(&self) -> Option<i32> {
-        let hex_literal = self.token.clone().to_lowercase();
+
+    pub fn from_hex_to_i32(&self) -> Result<i32, IguanaError> {
+        let hex_literal = self.token.to_lowercase();
         let mut hex_number: i32 = 0;
         let mut hex_counter = 2;
         while hex_counter < hex_literal.len() {
@@ -214,48 +258,32 @@ impl Token {
                 Some('e') => 14,
                 Some('f') => 15,
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid hexadecimal literal. Ensure it starts with '0x' and contains valid digits.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             };
             // its like: hex_number = hex_number * 16 + hex_digit;
-            match hex_number.checked_mul(16) {
-                Some(result) => {
-                    match result.checked_add(hex_digit) {
-                        Some(result) => hex_number = result,
-                        None => {
-                            logkit::exit_with_positional_error_message(
-                                "Hexadecimal literal overflow. Value must be between 0x0 and 0x7fffffff.",
-                                self.line,
-                                self.col,
-                            );
-                            return None;
-                        }
-                    }
-                }
+            match hex_number.checked_mul(16).and_then(|result| result.checked_add(hex_digit)) {
+                Some(result) => hex_number = result,
                 None => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::LiteralOverflow,
                         "Hexadecimal literal overflow. Value must be between 0x0 and 0x7fffffff.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             }
             hex_counter += 1;
         }
-        Some(hex_number)
+        Ok(hex_number)
     }
 
-    pub fn from_hex_to_u32(&self) -> Option<u32> {
-        let hex_literal = self.token.clone().to_lowercase();
+    pub fn from_hex_to_u32(&self) -> Result<u32, IguanaError> {
+        let hex_literal = self.token.to_lowercase();
         let mut hex_number: u32 = 0;
         let mut hex_counter = 2; // Pula o "0x" inicial
-    
+
         while hex_counter < hex_literal.len() {
             let hex_digit = match hex_literal.chars().nth(hex_counter) {
                 Some('0') => 0,
@@ -275,59 +303,42 @@ impl Token {
                 Some('e') => 14,
                 Some('f') => 15,
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid hexadecimal literal. Ensure it starts with '0x' and contains valid digits.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             };
-    
-            // hex_number = hex_number * 16 + hex_digit
-            match hex_number.checked_mul(16) {
-                Some(result) => {
-                    match result.checked_add(hex_digit) {
-                        Some(result) => hex_number = result,
-                        None => {
-                            logkit::exit_with_positional_error_message(
-                                "Hexadecimal literal overflow. Value must be between 0x0 and 0xffffffff.",
-                                self.line,
-                                self.col,
-                            );
-                            return None;
-                        }
-                    }
-                }
+            // its like: hex_number = hex_number * 16 + hex_digit;
+            match hex_number.checked_mul(16).and_then(|result| result.checked_add(hex_digit)) {
+                Some(result) => hex_number = result,
                 None => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::LiteralOverflow,
                         "Hexadecimal literal overflow. Value must be between 0x0 and 0xffffffff.",
-                        self.line,
-                        self.col,
-                    );
-                    return None;
+                    ));
                 }
             }
             hex_counter += 1;
         }
-        Some(hex_number)
+        Ok(hex_number)
     }
 
     pub fn is_binary_literal(&self) -> bool {
-        self.token.len() >= 3 && self.token.chars().nth(0) == Some('0') && self.token.chars().nth(1) == Some('b')
+        self.token.len() >= 3 && self.token.starts_with("0b")
     }
 
-    pub fn from_binary_to_i16(&self) -> Option<i16> {
-        let binary_string = self.token.clone(); // ex: "0b1" ou "0b11" ou "0b0000000000000000"
-    
+    pub fn from_binary_to_i16(&self) -> Result<Option<i16>, IguanaError> {
+        let binary_string = &self.token; // ex: "0b1" ou "0b11" ou "0b0000000000000000"
+
         // Verifica se começa com "0b" e tem até 16 bits (+2 do "0b")
         if !binary_string.starts_with("0b") || binary_string.len() < 3 || binary_string.len() > 18 {
-            return None;
+            return Ok(None);
         }
-    
+
         let bits = &binary_string[2..]; // Pega só os bits após "0b"
         let mut result: i16 = 0;
-    
+
         // Processa cada bit da esquerda para a direita
         for c in bits.chars() {
             match c {
@@ -338,31 +349,28 @@ impl Token {
                     result = result.wrapping_shl(1) | 1;
                 }
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid binary literal. Ensure it starts with '0b' and contains only '0' or '1'.",
-                        self.line,
-                        self.col,
-                    );
-                    return None; // Caractere inválido
+                    )); // Caractere inválido
                 }
             }
         }
-    
-        Some(result)
+
+        Ok(Some(result))
     }
-    
-    
-    pub fn from_binary_to_i32(&self) -> Option<i32> {
-        let binary_string = self.token.clone(); // ex: "0b1" ou "0b1111" ou "0b11111111111111111111111111111111"
-    
+
+    pub fn from_binary_to_i32(&self) -> Result<Option<i32>, IguanaError> {
+        let binary_string = &self.token; // ex: "0b1" ou "0b1111" ou "0b11111111111111111111111111111111"
+
         // Verifica se começa com "0b" e tem até 32 bits (+2 do "0b")
         if !binary_string.starts_with("0b") || binary_string.len() < 3 || binary_string.len() > 34 {
-            return None;
+            return Ok(None);
         }
-    
+
         let bits = &binary_string[2..]; // Pega só os bits após "0b"
         let mut result: i32 = 0;
-    
+
         // Processa cada bit da esquerda para a direita
         for c in bits.chars() {
             match c {
@@ -373,129 +381,80 @@ impl Token {
                     result = result.wrapping_shl(1) | 1;
                 }
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid binary literal. Ensure it starts with '0b' and contains only '0' or '1'.",
-                        self.line,
-                        self.col,
-                    );
-                    return None; // Caractere inválido
+                    )); // Caractere inválido
                 }
             }
         }
-    
-        Some(result)
+
+        Ok(Some(result))
     }
 
-    pub fn from_binary_to_u32(&self) -> Option<u32> {
-        let binary_string = self.token.clone(); // ex: "0b1" ou "0b1111" ou "0b11111111111111111111111111111111"
-    
+    pub fn from_binary_to_u32(&self) -> Result<Option<u32>, IguanaError> {
+        let binary_string = &self.token; // ex: "0b1" ou "0b1111" ou "0b11111111111111111111111111111111"
+
         // Verifica se começa com "0b" e tem até 32 bits (+2 do "0b")
         if !binary_string.starts_with("0b") || binary_string.len() < 3 || binary_string.len() > 34 {
-            return None;
+            return Ok(None);
         }
-    
+
         let bits = &binary_string[2..]; // Pega só os bits após "0b"
         let mut result: u32 = 0;
-    
+
         // Processa cada bit da esquerda para a direita
         for c in bits.chars() {
             match c {
                 '0' => {
-                    match result.checked_shl(1) {
-                        Some(shifted) => result = shifted,
-                        None => {
-                            logkit::exit_with_positional_error_message(
-                                "Binary literal overflow. Value must fit within 16 bits.",
-                                self.line,
-                                self.col,
-                            );
-                            return None; // Overflow
-                        }
-                    }
+                    result = result.wrapping_shl(1);
                 }
                 '1' => {
-                    match result.checked_shl(1) {
-                        Some(shifted) => {
-                            match shifted.checked_add(1) {
-                                Some(added) => result = added,
-                                None => {
-                                    logkit::exit_with_positional_error_message(
-                                        "Binary literal overflow. Value must fit within 16 bits.",
-                                        self.line,
-                                        self.col,
-                                    );
-                                    return None; // Overflow
-                                }
-                            }
-                        }
-                        None => {
-                            logkit::exit_with_positional_error_message(
-                                "Binary literal overflow. Value must fit within 16 bits.",
-                                self.line,
-                                self.col,
-                            );
-                            return None; // Overflow
-                        }
-                    }
+                    result = result.wrapping_shl(1) | 1;
                 }
                 _ => {
-                    logkit::exit_with_positional_error_message(
+                    return Err(self.error(
+                        AssemblyErrorKind::InvalidLiteral,
                         "Invalid binary literal. Ensure it starts with '0b' and contains only '0' or '1'.",
-                        self.line,
-                        self.col,
-                    );
-                    return None; // Caractere inválido
+                    )); // Caractere inválido
                 }
             }
         }
-    
-        Some(result)
+
+        Ok(Some(result))
     }
 
-    pub fn to_i16_value(&self) -> Option<i16> {
+    /// Returns `Ok(None)` when the token is not a number at all (e.g. a label),
+    /// and an error when it looks like a literal but is malformed.
+    pub fn to_i16_value(&self) -> Result<Option<i16>, IguanaError> {
         if self.is_char_literal() {
-            return self.from_char_to_i16();
+            Ok(self.from_char_to_i16())
         } else if self.is_hex_literal() {
-            return self.from_hex_to_i16();
+            self.from_hex_to_i16().map(Some)
         } else if self.is_binary_literal() {
-            return self.from_binary_to_i16();
+            self.from_binary_to_i16()
         } else {
-            match self.token.parse::<i16>() {
-                Ok(value) => Some(value),
-                Err(_) => {
-                    None
-                }
-            }
+            Ok(self.token.parse::<i16>().ok())
         }
     }
 
-    pub fn to_i32_value(&self) -> Option<i32> {
+    pub fn to_i32_value(&self) -> Result<Option<i32>, IguanaError> {
         if self.is_hex_literal() {
-            return self.from_hex_to_i32();
+            self.from_hex_to_i32().map(Some)
         } else if self.is_binary_literal() {
-            return self.from_binary_to_i32();
+            self.from_binary_to_i32()
         } else {
-            match self.token.parse::<i32>() {
-                Ok(value) => Some(value),
-                Err(_) => {
-                    None
-                }
-            }
+            Ok(self.token.parse::<i32>().ok())
         }
     }
 
-    pub fn to_u32_value(&self) -> Option<u32> {
+    pub fn to_u32_value(&self) -> Result<Option<u32>, IguanaError> {
         if self.is_hex_literal() {
-            return self.from_hex_to_u32();
+            self.from_hex_to_u32().map(Some)
         } else if self.is_binary_literal() {
-            return self.from_binary_to_u32();
+            self.from_binary_to_u32()
         } else {
-            match self.token.parse::<u32>() {
-                Ok(value) => Some(value),
-                Err(_) => {
-                    None
-                }
-            }
+            Ok(self.token.parse::<u32>().ok())
         }
     }
-}
\ No newline at end of file
+}
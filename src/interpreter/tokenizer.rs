@@ -1,15 +1,13 @@
-use crate::logkit;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 
-use super::{opcode::Opcode, token::Token};
+use super::error::{AssemblyErrorKind, IguanaError, RelatedLocation};
+use super::expression;
+use super::opcode::Opcode;
+use super::token::{MacroOrigin, Token};
 
-pub fn tokenize(file_path: &String) -> Vec<Token> {
-    let raw_content = match std::fs::read_to_string(file_path) {
-        Ok(content) => content.replace("\r", ""),
-        Err(_) => {
-            logkit::exit_with_error_message("Error reading file. Please check if the file exists and is accessible.");
-            String::new()
-        },
-    };
+pub fn tokenize(source: &str) -> Vec<Token> {
+    let raw_content = source.replace("\r", "");
 
     let mut is_comment = false;
     let mut tokens = Vec::new();
@@ -21,7 +19,7 @@ pub fn tokenize(file_path: &String) -> Vec<Token> {
     let mut escape_count = 0;
     let mut line_has_indentation = false;
 
-    for (_, c) in raw_content.chars().enumerate() {
+    for c in raw_content.chars() {
         match c {
             '\t' => {
                 line_has_indentation = true;
@@ -31,11 +29,9 @@ pub fn tokenize(file_path: &String) -> Vec<Token> {
                 is_comment = false;
                 line_has_indentation = false;
             },
-            '#' => {
-                if !is_literal_str && !is_literal_char {
-                    is_comment = true;
-                    continue;
-                }
+            '#' if !is_literal_str && !is_literal_char => {
+                is_comment = true;
+                continue;
             },
             _ => {}
         }
@@ -60,11 +56,9 @@ pub fn tokenize(file_path: &String) -> Vec<Token> {
             }
         } else if is_literal_char {
             match c {
-                '\'' if escape_count % 2 == 0 => {
+                '\'' if escape_count % 2 == 0 => { // the token goes on, e.g. 'a'+1
                     is_literal_char = false;
                     raw_token.push('\'');
-                    tokens.push(raw_token.clone());
-                    raw_token.clear();
                 }
                 '\n' => {
                     raw_token.push(' ');
@@ -83,9 +77,11 @@ pub fn tokenize(file_path: &String) -> Vec<Token> {
                 }
                 '\'' => {
                     is_literal_char = true;
+                    if raw_token.is_empty() {
+                        raw_token.line = line_counter;
+                        raw_token.col = if line_has_indentation { 0 } else { col_counter };
+                    }
                     raw_token.push('\'');
-                    raw_token.line = line_counter;
-                    raw_token.col = if line_has_indentation { 0 } else { col_counter };
                 }
                 ',' => {
                     if !raw_token.is_empty() {
@@ -132,60 +128,660 @@ pub fn tokenize(file_path: &String) -> Vec<Token> {
     if !raw_token.is_empty() {
         tokens.push(raw_token);
     }
-    
+
     tokens
 }
 
-pub fn get_removed_system_call_tokens(tokens: &Vec<Token>) -> Vec<Token> {
-    let mut new_tokens_vector: Vec<Token> = Vec::new();
-    let mut tk_counter = 0;
-    while tk_counter < tokens.len() {
-        match tokens.get(tk_counter) {
-            Some(token) => {
-                match Opcode::from_str(token.get_token().as_str()) {
-                    Some(op) => {
-                        match op {
-                            Opcode::Printlnac | Opcode::Printac | 
-                            Opcode::Printlnacchar | Opcode::Printacchar | 
-                            Opcode::Printsp | Opcode::Printlnsp |
-                            Opcode::Inputac | Opcode::Inputacchar => {
-                                tk_counter += 1;
-                                continue;
-                            }
-                            Opcode::Printlninstruction | Opcode::Printinstruction | 
-                            Opcode::Sleepd | Opcode::Sleepi | Opcode::Inputstring => {
-                                tk_counter += 2;
-                                continue;
-                            }
-                            _ => {
-                                new_tokens_vector.push(token.clone());
-                                tk_counter += 1;
-                                continue;
-                            }
-                        }
+/// An `.if`, `.ifdef` or `.ifndef` whose `.endif` was not found yet.
+struct ConditionalBlock {
+    directive: Token,
+    is_enclosing_active: bool, // whether the block is inside the kept tokens
+    condition: bool, // whether the tokens of the current branch are kept, inverted by `.else`
+    else_directive: Option<Token>,
+}
+
+/// The `.ifdef NAME`, `.ifndef NAME` and `.if EXPR` blocks of one file, read along with its tokens: the tokens of a block
+/// are kept when its condition holds, up to its `.else` or `.endif`, and the tokens after its `.else` otherwise.
+/// The conditions see the `symbols` given to the assembler, e.g. with `-D DEBUG`, and the `.equ` constants defined before
+/// them whose value does not use labels, which are added to `symbols`. Blocks can be nested, but must end in the file they start.
+pub struct Conditionals<'a> {
+    symbols: &'a mut HashMap<String, i16>,
+    blocks: Vec<ConditionalBlock>,
+    is_in_macro: bool, // the constants of a macro are only known where it is used
+}
+
+impl<'a> Conditionals<'a> {
+    pub fn new(symbols: &'a mut HashMap<String, i16>) -> Conditionals<'a> {
+        Conditionals { symbols, blocks: Vec::new(), is_in_macro: false }
+    }
+
+    pub fn symbols(&mut self) -> &mut HashMap<String, i16> {
+        self.symbols
+    }
+
+    /// Whether the tokens read now are kept.
+    pub fn is_active(&self) -> bool {
+        self.blocks.last().is_none_or(|block| block.is_enclosing_active && block.condition)
+    }
+
+    /// Reads the token at `offset` and returns the index of the token after it when it is a conditional directive,
+    /// which is not kept, or `None` for any other token.
+    pub fn read(&mut self, tokens: &[Token], offset: usize) -> Result<Option<usize>, IguanaError> {
+        let token = &tokens[offset];
+        let directive = token.get_token();
+        let rest_of_line = get_rest_of_line(tokens, offset);
+        match directive.as_str() {
+            ".ifdef" | ".ifndef" | ".if" => {
+                let argument = match rest_of_line {
+                    [argument] => argument,
+                    _ => {
+                        let message = match directive.as_str() {
+                            ".if" => "Expected an expression without spaces after '.if', e.g. '.if LEVEL>=2'.".to_string(),
+                            _ => format!("Expected a single name after '{}', e.g. '{} DEBUG'.", directive, directive),
+                        };
+                        return Err(token.error(AssemblyErrorKind::InvalidConditional, &message));
                     }
+                };
+
+                let is_enclosing_active = self.is_active();
+                let condition = match directive.as_str() {
+                    _ if !is_enclosing_active => false, // not evaluated, as the block is removed anyway
+                    ".if" => self.evaluate(argument)?,
+                    _ => {
+                        if !is_valid_name(&argument.get_token()) {
+                            return Err(argument.error(
+                                AssemblyErrorKind::InvalidConditional,
+                                format!("Invalid symbol name '{}' after '{}'.", argument.get_token(), directive).as_str(),
+                            ));
+                        }
+                        self.symbols.contains_key(&argument.get_token()) == (directive == ".ifdef")
+                    },
+                };
+                self.blocks.push(ConditionalBlock { directive: token.clone(), is_enclosing_active, condition, else_directive: None });
+            },
+            ".else" | ".endif" => {
+                if let Some(extra_token) = rest_of_line.first() {
+                    return Err(extra_token.error(
+                        AssemblyErrorKind::InvalidConditional,
+                        format!("Expected nothing after '{}' on the same line.", directive).as_str(),
+                    ));
+                }
+                let block = match self.blocks.last_mut() {
+                    Some(block) => block,
                     None => {
-                        new_tokens_vector.push(token.clone());
-                        tk_counter += 1;
-                        continue;
+                        return Err(token.error(
+                            AssemblyErrorKind::InvalidConditional,
+                            format!("Found '{}' without an '.if', '.ifdef' or '.ifndef' before it.", directive).as_str(),
+                        ));
                     }
+                };
+                if directive == ".endif" {
+                    self.blocks.pop();
+                } else if let Some(else_directive) = block.else_directive.as_ref() {
+                    return Err(
+                        token.error(AssemblyErrorKind::InvalidConditional, format!("Found a second '.else' for the same '{}'.", block.directive.get_token()).as_str())
+                            .with_related(else_directive.related_location("the first '.else' is here"))
+                    );
+                } else {
+                    block.condition = !block.condition;
+                    block.else_directive = Some(token.clone());
                 }
+            },
+            _ => {
+                if self.is_active() {
+                    self.read_constant(tokens, offset);
+                }
+                return Ok(None);
+            },
+        }
+        Ok(Some(offset + 1 + rest_of_line.len()))
+    }
+
+    /// Adds the `.equ NAME, value` at `offset` to the symbols when its value is known, the assembler reports the invalid ones.
+    fn read_constant(&mut self, tokens: &[Token], offset: usize) {
+        match tokens[offset].get_token().as_str() {
+            ".macro" => self.is_in_macro = true,
+            ".endm" => self.is_in_macro = false,
+            ".equ" if !self.is_in_macro => {
+                if let Some([name_token, comma_token, value_token]) = tokens.get(offset + 1..offset + 4) {
+                    let lookup = |name: &str| self.symbols.get(name).map(|value| *value as i32);
+                    if let (",", Ok(Some(value))) = (comma_token.get_token().as_str(), expression::evaluate(value_token, &lookup)) {
+                        self.symbols.insert(name_token.get_token(), value);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn evaluate(&self, argument: &Token) -> Result<bool, IguanaError> {
+        let undefined_name = RefCell::new(None);
+        expression::replace_names(&argument.get_token(), &|name| {
+            if is_valid_name(name) && !self.symbols.contains_key(name) && undefined_name.borrow().is_none() {
+                *undefined_name.borrow_mut() = Some(name.to_string());
             }
+            None
+        });
+        if let Some(name) = undefined_name.into_inner() {
+            return Err(argument.error(
+                AssemblyErrorKind::UndefinedLabel,
+                format!("Undefined symbol '{}' in '.if', define it with '-D {}' or an '.equ' before it, or use '.ifdef {}'.", name, name, name).as_str(),
+            ));
+        }
+
+        match expression::evaluate(argument, &|name| self.symbols.get(name).map(|value| *value as i32))? {
+            Some(value) => Ok(value != 0),
             None => {
-                logkit::exit_with_error_message("Error: Token not found.");
+                Err(argument.error(
+                    AssemblyErrorKind::InvalidConditional,
+                    format!("Expected an expression after '.if', found '{}'.", argument.get_token()).as_str(),
+                ))
             }
         }
     }
 
-    
-    for i in (0..new_tokens_vector.len()).rev() {
-        let token = &new_tokens_vector[i];
-        if token.is_label() {
-            new_tokens_vector.remove(i);
-        } else {
-            break;
+    /// Reports a block without its `.endif` at the end of the file.
+    pub fn finish(&self) -> Result<(), IguanaError> {
+        match self.blocks.last() {
+            Some(block) => {
+                Err(block.directive.error(
+                    AssemblyErrorKind::InvalidConditional,
+                    format!("Expected '.endif' to close '{}'.", block.directive.get_token()).as_str(),
+                ))
+            },
+            None => Ok(()),
         }
     }
+}
+
+/// How many macros can be expanded inside each other, so that a recursive macro is reported instead of expanded forever.
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// A `.macro NAME param1, param2` definition, with the tokens up to its `.endm`.
+struct Macro {
+    name: String,
+    params: Vec<String>,
+    body: Vec<Token>,
+    directive: Token,
+}
+
+impl Macro {
+    /// Tokens of the body for one invocation, with the parameters replaced by the arguments
+    /// and the labels defined in the body renamed as `LABEL@expansion`.
+    /// The tokens take the position of the invocation and remember their position in the definition.
+    fn expand(&self, invocation: &Token, arguments: &[Token], expansion: u32) -> Vec<Token> {
+        let local_labels = self.body.iter()
+            .filter(|token| token.is_label() && !token.is_numeric_label()) // numeric labels can be repeated anyway
+            .map(|token| token.get_token().trim_end_matches(':').to_string())
+            .collect::<Vec<String>>();
+
+        self.body.iter().map(|body_token| {
+            let text = body_token.get_token();
+            let label_name = text.strip_suffix(':').unwrap_or(&text).to_string();
+            let mut token = match self.params.iter().position(|param| *param == text) {
+                Some(index) => {
+                    let mut argument = invocation.with_text(&arguments[index].get_token());
+                    argument.col = arguments[index].col;
+                    argument
+                },
+                None if local_labels.contains(&label_name) => {
+                    invocation.with_text(&text.replacen(&label_name, &format!("{}@{}", label_name, expansion), 1))
+                },
+                None if expression::is_expression(&text) => {
+                    invocation.with_text(&expression::replace_names(&text, &|name| {
+                        match self.params.iter().position(|param| param == name) {
+                            // the argument is put between parentheses, so `N*2` with `X+1` is `(X+1)*2`
+                            Some(index) if expression::is_expression(&arguments[index].get_token()) => Some(format!("({})", arguments[index].get_token())),
+                            Some(index) => Some(arguments[index].get_token()),
+                            None if local_labels.iter().any(|label| label == name) => Some(format!("{}@{}", name, expansion)),
+                            None => None,
+                        }
+                    }))
+                },
+                None => invocation.with_text(&text),
+            };
+            token.macro_origin = Some(Box::new(MacroOrigin {
+                name: self.name.clone(),
+                file: body_token.file,
+                line: body_token.line,
+                col: body_token.col,
+            }));
+            token
+        }).collect()
+    }
+
+    fn definition_location(&self) -> RelatedLocation {
+        self.directive.related_location(format!("macro '{}' is defined here", self.name).as_str())
+    }
+}
+
+/// Removes the `.macro` ... `.endm` definitions and replaces every invocation by the body of its macro.
+/// A macro is invoked by its name at the start of a line, optionally after labels, followed by its
+/// comma separated arguments, e.g. `PRINT_VALUE X, 2`. The labels defined inside a macro are local
+/// to each invocation, so a macro with a loop can be used more than once.
+pub fn expand_macros(tokens: &[Token]) -> Result<Vec<Token>, IguanaError> {
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut program_tokens = Vec::new();
+
+    let mut token_counter = 0;
+    while let Some(token) = tokens.get(token_counter) {
+        match token.get_token().as_str() {
+            ".macro" => {
+                let (definition, next_token_counter) = get_macro_definition(tokens, token_counter)?;
+                if let Some(previous_definition) = macros.get(&definition.name) {
+                    return Err(
+                        definition.directive.error(AssemblyErrorKind::InvalidMacro, format!("Macro '{}' is already defined.", definition.name).as_str())
+                            .with_related(previous_definition.definition_location())
+                    );
+                }
+                macros.insert(definition.name.clone(), definition);
+                token_counter = next_token_counter;
+            },
+            ".endm" => {
+                return Err(token.error(AssemblyErrorKind::InvalidMacro, "Found '.endm' without a '.macro' to close."));
+            },
+            _ => {
+                program_tokens.push(token.clone());
+                token_counter += 1;
+            },
+        }
+    }
+
+    if macros.is_empty() {
+        return Ok(program_tokens);
+    }
+    let mut expansion_counter = 0;
+    expand_macro_invocations(&program_tokens, &macros, &mut expansion_counter, 0)
+}
+
+/// Reads the definition starting at the `.macro` token at `offset` and returns it with the index of the token after its `.endm`.
+fn get_macro_definition(tokens: &[Token], offset: usize) -> Result<(Macro, usize), IguanaError> {
+    let directive = &tokens[offset];
+    let header = get_rest_of_line(tokens, offset);
+    let name_token = match header.first() {
+        Some(name_token) => name_token,
+        None => {
+            return Err(directive.error(AssemblyErrorKind::InvalidMacro, "Expected a macro name after '.macro'."));
+        }
+    };
+
+    let name = name_token.get_token();
+    if !is_valid_name(&name) {
+        return Err(name_token.error(
+            AssemblyErrorKind::InvalidMacro,
+            format!("Invalid macro name '{}', names must start with a letter or '_' and contain only letters, digits or '_'.", name).as_str(),
+        ));
+    }
+    if Opcode::from_str(&name).is_some() {
+        return Err(name_token.error(
+            AssemblyErrorKind::InvalidMacro,
+            format!("Macro '{}' cannot have the same name of an instruction.", name).as_str(),
+        ));
+    }
+
+    let mut params: Vec<String> = Vec::new();
+    for param_token in get_macro_arguments(&header[1..], name_token)? {
+        let param = param_token.get_token();
+        if !is_valid_name(&param) {
+            return Err(param_token.error(AssemblyErrorKind::InvalidMacro, format!("Invalid parameter name '{}' in macro '{}'.", param, name).as_str()));
+        }
+        if params.contains(&param) {
+            return Err(param_token.error(AssemblyErrorKind::InvalidMacro, format!("Parameter '{}' is repeated in macro '{}'.", param, name).as_str()));
+        }
+        params.push(param);
+    }
+
+    let body_start = offset + 1 + header.len();
+    let mut body_end = body_start;
+    loop {
+        match tokens.get(body_end).map(|token| token.get_token()).as_deref() {
+            Some(".endm") => break,
+            Some(".macro") => {
+                return Err(tokens[body_end].error(AssemblyErrorKind::InvalidMacro, "Macros cannot be defined inside other macros."));
+            },
+            Some(_) => body_end += 1,
+            None => {
+                return Err(directive.error(AssemblyErrorKind::InvalidMacro, format!("Expected '.endm' to close macro '{}'.", name).as_str()));
+            },
+        }
+    }
+
+    let definition = Macro {
+        name,
+        params,
+        body: tokens[body_start..body_end].to_vec(),
+        directive: directive.clone(),
+    };
+    Ok((definition, body_end + 1))
+}
+
+fn expand_macro_invocations(tokens: &[Token], macros: &HashMap<String, Macro>, expansion_counter: &mut u32, depth: usize) -> Result<Vec<Token>, IguanaError> {
+    let mut expanded_tokens = Vec::new();
+    let mut is_instruction_position = true; // only labels were found since the start of the line
 
-    new_tokens_vector 
+    let mut token_counter = 0;
+    while let Some(token) = tokens.get(token_counter) {
+        if token_counter == 0 || !is_same_line(&tokens[token_counter - 1], token) {
+            is_instruction_position = true;
+        }
+
+        match macros.get(&token.get_token()) {
+            Some(definition) if is_instruction_position => {
+                if depth >= MAX_MACRO_DEPTH {
+                    return Err(token.error(
+                        AssemblyErrorKind::InvalidMacro,
+                        format!("Too many nested expansions of macro '{}', check if it invokes itself.", definition.name).as_str(),
+                    ));
+                }
+
+                let arguments_tokens = get_rest_of_line(tokens, token_counter);
+                let arguments = get_macro_arguments(arguments_tokens, token)?;
+                if arguments.len() != definition.params.len() {
+                    return Err(
+                        token.error(
+                            AssemblyErrorKind::InvalidMacro,
+                            format!("Macro '{}' expects {} argument(s), but {} were given.", definition.name, definition.params.len(), arguments.len()).as_str(),
+                        )
+                        .with_related(definition.definition_location())
+                    );
+                }
+
+                *expansion_counter += 1;
+                let body = definition.expand(token, &arguments, *expansion_counter);
+                expanded_tokens.extend(expand_macro_invocations(&body, macros, expansion_counter, depth + 1)?);
+                token_counter += 1 + arguments_tokens.len();
+            },
+            _ => {
+                is_instruction_position = is_instruction_position && token.is_label();
+                expanded_tokens.push(token.clone());
+                token_counter += 1;
+            },
+        }
+    }
+
+    Ok(expanded_tokens)
+}
+
+/// Tokens after the one at `offset` that are on the same line.
+fn get_rest_of_line(tokens: &[Token], offset: usize) -> &[Token] {
+    let length = tokens[offset + 1..].iter()
+        .take_while(|token| is_same_line(&tokens[offset], token))
+        .count();
+    &tokens[offset + 1..offset + 1 + length]
+}
+
+/// Expanded tokens all have the line of the invocation, so their lines in the definition are compared too.
+fn is_same_line(a: &Token, b: &Token) -> bool {
+    let origin_line = |token: &Token| token.macro_origin.as_ref().map(|origin| (origin.file, origin.line));
+    a.file == b.file && a.line == b.line && origin_line(a) == origin_line(b)
+}
+
+/// Values of a comma separated list such as `X, 2, 'a'`, each one a single token.
+fn get_macro_arguments(tokens: &[Token], macro_token: &Token) -> Result<Vec<Token>, IguanaError> {
+    let mut arguments = Vec::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let is_comma = token.get_token() == ",";
+        if is_comma != (i % 2 == 1) {
+            return Err(token.error(
+                AssemblyErrorKind::InvalidMacro,
+                format!("Expected the values after '{}' to be separated by commas, e.g. '{} X, Y'.", macro_token.get_token(), macro_token.get_token()).as_str(),
+            ));
+        }
+        if !is_comma {
+            arguments.push(token.clone());
+        }
+    }
+    if let Some(last_token) = tokens.last().filter(|token| token.get_token() == ",") {
+        return Err(last_token.error(AssemblyErrorKind::InvalidMacro, "Expected a value after ','."));
+    }
+    Ok(arguments)
+}
+
+/// Names of macros, their parameters and constants: a letter or `_` followed by letters, digits or `_`.
+pub fn is_valid_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(c) if c.is_alphabetic() || c == '_')
+    && chars.all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Scope of the local labels defined before the first global label, like the code outside of functions.
+pub const MAIN_SCOPE: &str = "main";
+
+/// Gives the local and numeric labels their full names, which are the ones shown by errors and the debugger:
+/// `.loop:` after `FIBONACCI:` is `FIBONACCI.loop`, used as `.loop` until the next global label or as `FIBONACCI.loop` anywhere,
+/// and the second `1:` after `FIBONACCI:` is `FIBONACCI.1@2`, used as `1f` before it and `1b` after it.
+/// Labels defined inside macros do not start a new scope.
+pub fn qualify_local_labels(tokens: &[Token]) -> Result<Vec<Token>, IguanaError> {
+    let mut scopes = Vec::with_capacity(tokens.len()); // scope of each token
+    let mut local_labels = HashSet::new(); // full names
+    let mut numeric_labels: Vec<(usize, String, String)> = Vec::new(); // (token index, number, full name)
+    let mut numeric_label_counts: HashMap<(String, String), u32> = HashMap::new();
+
+    let mut scope = MAIN_SCOPE.to_string();
+    for (index, token) in tokens.iter().enumerate() {
+        let name = token.get_token().trim_end_matches(':').to_string();
+        if token.is_global_label() && token.macro_origin.is_none() {
+            scope = name;
+        } else if token.is_local_label() {
+            local_labels.insert(format!("{}{}", scope, name));
+        } else if token.is_numeric_label() {
+            let count = numeric_label_counts.entry((scope.clone(), name.clone())).or_insert(0);
+            *count += 1;
+            numeric_labels.push((index, name.clone(), format!("{}.{}@{}", scope, name, count)));
+        }
+        scopes.push(scope.clone());
+    }
+
+    let mut qualified_tokens = Vec::with_capacity(tokens.len());
+    for (index, token) in tokens.iter().enumerate() {
+        let text = token.get_token();
+        if token.is_local_label() {
+            qualified_tokens.push(token.with_text(&format!("{}{}", scopes[index], text)));
+            continue;
+        }
+        if token.is_numeric_label() {
+            let (_, _, name) = numeric_labels.iter().find(|(label_index, _, _)| *label_index == index).unwrap();
+            qualified_tokens.push(token.with_text(&format!("{}:", name)));
+            continue;
+        }
+        if token.is_string_literal() {
+            qualified_tokens.push(token.clone());
+            continue;
+        }
+
+        let unresolved_reference = RefCell::new(None);
+        let qualified_text = expression::replace_names(&text, &|word| {
+            if word.starts_with('.') {
+                let name = format!("{}{}", scopes[index], word);
+                return local_labels.contains(&name).then_some(name);
+            }
+            let (number, is_forward) = get_numeric_label_reference(word)?;
+            let label = match is_forward {
+                true => numeric_labels.iter().find(|(label_index, label_number, _)| *label_index > index && label_number == number),
+                false => numeric_labels.iter().rev().find(|(label_index, label_number, _)| *label_index < index && label_number == number),
+            };
+            if label.is_none() {
+                *unresolved_reference.borrow_mut() = Some((number.to_string(), is_forward));
+            }
+            label.map(|(_, _, name)| name.clone())
+        });
+
+        if let Some((number, is_forward)) = unresolved_reference.into_inner() {
+            let message = match is_forward {
+                true => format!("Expected a label '{}:' after the reference '{}f'.", number, number),
+                false => format!("Expected a label '{}:' before the reference '{}b'.", number, number),
+            };
+            return Err(token.error(AssemblyErrorKind::UndefinedLabel, &message));
+        }
+        qualified_tokens.push(token.with_text(&qualified_text));
+    }
+    Ok(qualified_tokens)
+}
+
+/// `1f` is `("1", true)` and `1b` is `("1", false)`.
+fn get_numeric_label_reference(word: &str) -> Option<(&str, bool)> {
+    let number = word.strip_suffix(['f', 'b'])?;
+    if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    Some((number, word.ends_with('f')))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::error::ErrorKind;
+
+    fn texts(tokens: &[Token]) -> Vec<String> {
+        tokens.iter().map(|token| token.get_token()).collect()
+    }
+
+    #[test]
+    fn numeric_label_references() {
+        assert_eq!(get_numeric_label_reference("12f"), Some(("12", true)));
+        assert_eq!(get_numeric_label_reference("1b"), Some(("1", false)));
+        assert_eq!(get_numeric_label_reference("f"), None);
+        assert_eq!(get_numeric_label_reference("x1f"), None);
+        assert_eq!(get_numeric_label_reference("1é"), None);
+        assert_eq!(get_numeric_label_reference("café"), None);
+    }
+
+    #[test]
+    fn qualifies_non_ascii_operands() {
+        let tokens = qualify_local_labels(&tokenize(".text\n    LOCO café\n    LOCO 1ñ\n")).unwrap();
+        assert_eq!(texts(&tokens), [".text", "LOCO", "café", "LOCO", "1ñ"]);
+    }
+
+    fn assemble_with(source: &str, interpreter_mode: InterpreterMode, symbols: &[(&str, i16)]) -> Result<Vec<i16>, IguanaError> {
+        let mut assembler = Assembler::new(interpreter_mode);
+        for (name, value) in symbols {
+            assembler.define(name, *value);
+        }
+        assembler.assemble(source).map(|program| program.instructions.iter().map(|instruction| instruction.arg).collect())
+    }
+
+    #[test]
+    fn conditions_see_symbols_and_earlier_constants() {
+        let source = ".equ N, 3\n.text\n.if N*2==6\n    LOCO 1\n.else\n    LOCO 9\n.endif\n.ifdef DEBUG\n    LOCO 2\n.endif\n    HALT\n";
+        assert_eq!(assemble_with(source, InterpreterMode::Execute, &[]).unwrap(), [1, 0]);
+        assert_eq!(assemble_with(source, InterpreterMode::Execute, &[("DEBUG", 1)]).unwrap(), [1, 2, 0]);
+
+        let nested = ".text\n.ifdef A\n.if B\n    LOCO 1\n.endif\n.endif\n    HALT\n";
+        assert_eq!(assemble_with(nested, InterpreterMode::Execute, &[]).unwrap(), [0]); // B is not evaluated
+    }
+
+    #[test]
+    fn undefined_names_in_if_are_errors() {
+        let error = assemble_with(".text\n.if LEVEL>1\n.endif\n    HALT\n", InterpreterMode::Execute, &[]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::UndefinedLabel));
+        let error = assemble_with(".data\n    X: .word 1\n.equ A, X\n.text\n.if A\n.endif\n    HALT\n", InterpreterMode::Execute, &[]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::UndefinedLabel));
+    }
+
+    #[test]
+    fn unbalanced_blocks_are_errors() {
+        for source in [".text\n.ifdef A\n    HALT\n", ".text\n    HALT\n.endif\n", ".text\n.ifdef A\n.else\n.else\n.endif\n    HALT\n"] {
+            let error = assemble_with(source, InterpreterMode::Execute, &[]).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidConditional), "{}", source);
+        }
+    }
+
+    #[test]
+    fn binary_mode_rejects_system_calls_outside_of_ifndef_binary() {
+        let error = assemble_with(".text\n    PRINTLNAC\n    HALT\n", InterpreterMode::Binary, &[]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::UnsupportedInstruction));
+        let source = ".text\n    LOCO 1\n.ifndef BINARY\n    PRINTLNAC\n.endif\n    HALT\n";
+        assert_eq!(assemble_with(source, InterpreterMode::Binary, &[]).unwrap().len(), 2);
+        assert_eq!(assemble_with(source, InterpreterMode::Execute, &[]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn examples_export_to_binaries() {
+        for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/examples")).unwrap() {
+            let path = entry.unwrap().path();
+            let result = Assembler::new(InterpreterMode::Binary).assemble_file(path.to_str().unwrap());
+            assert!(result.is_ok(), "{}: {}", path.display(), result.unwrap_err());
+        }
+    }
+
+    const COUNT_DOWN: &str = ".macro COUNT_DOWN FROM, STEP
+    LOCO FROM
+LOOP:
+    SUBD STEP
+    JPOS LOOP
+.endm
+.data
+    ONE: .word 1
+.text
+    COUNT_DOWN 3, ONE
+START: COUNT_DOWN 2*2, ONE
+    HALT
+";
+
+    #[test]
+    fn macros_are_replaced_by_their_bodies_with_local_labels() {
+        let tokens = expand_macros(&tokenize(COUNT_DOWN)).unwrap();
+        assert_eq!(texts(&tokens), [
+            ".data", "ONE:", ".word", "1", ".text",
+            "LOCO", "3", "LOOP@1:", "SUBD", "ONE", "JPOS", "LOOP@1",
+            "START:", "LOCO", "2*2", "LOOP@2:", "SUBD", "ONE", "JPOS", "LOOP@2",
+            "HALT",
+        ]);
+
+        // the expanded tokens are at the invocation and remember their line in the macro
+        let jump = &tokens[10];
+        assert_eq!(jump.line, 10);
+        let origin = jump.macro_origin.as_ref().unwrap();
+        assert_eq!((origin.name.as_str(), origin.line), ("COUNT_DOWN", 5));
+
+        let program = Assembler::new(InterpreterMode::Execute).assemble(COUNT_DOWN).unwrap();
+        assert_eq!(program.instructions.len(), 7);
+    }
+
+    #[test]
+    fn expression_arguments_are_put_between_parentheses() {
+        let source = ".macro TWICE N\n    LOCO N*2\n.endm\n.text\n    TWICE 1+2\n    HALT\n";
+        assert_eq!(texts(&expand_macros(&tokenize(source)).unwrap()), [".text", "LOCO", "(1+2)*2", "HALT"]);
+        assert_eq!(assemble_with(source, InterpreterMode::Execute, &[]).unwrap(), [6, 0]);
+    }
+
+    #[test]
+    fn nested_macros_get_their_own_expansions() {
+        let source = ".macro WAIT\nAGAIN:\n    JUMP AGAIN\n.endm\n.macro WAIT_TWICE\n    WAIT\n    WAIT\n.endm\n.text\n    WAIT_TWICE\n";
+        assert_eq!(texts(&expand_macros(&tokenize(source)).unwrap()), [
+            ".text", "AGAIN@2:", "JUMP", "AGAIN@2", "AGAIN@3:", "JUMP", "AGAIN@3",
+        ]);
+    }
+
+    #[test]
+    fn invalid_macros_are_errors() {
+        let sources = [
+            ".macro\n.endm\n",
+            ".macro LOCO\n.endm\n",
+            ".macro A X, X\n.endm\n",
+            ".macro A\n    HALT\n",
+            ".text\n.endm\n",
+            ".macro A\n.endm\n.macro A\n.endm\n",
+            ".macro A X\n    LOCO X\n.endm\n.text\n    A 1, 2\n",
+            ".macro A X\n    LOCO X\n.endm\n.text\n    A 1 2\n",
+            ".macro A\n    A\n.endm\n.text\n    A\n",
+        ];
+        for source in sources {
+            let error = expand_macros(&tokenize(source)).unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Assembly(AssemblyErrorKind::InvalidMacro), "{}", source);
+        }
+
+        let error = expand_macros(&tokenize(sources[6])).unwrap_err();
+        assert_eq!(error.message, "Macro 'A' expects 1 argument(s), but 2 were given.");
+        assert_eq!((error.line, error.related.as_ref().unwrap().line), (5, 1));
+        let error = expand_macros(&tokenize(sources[8])).unwrap_err();
+        assert_eq!(error.message, "Too many nested expansions of macro 'A', check if it invokes itself.");
+    }
+
+    #[test]
+    fn non_ascii_operand_is_an_assembly_error() {
+        assert!(Assembler::new(InterpreterMode::Execute).assemble(".text\n    LOCO café\n").is_err());
+        assert!(Assembler::new(InterpreterMode::Binary).assemble(".text\n    LOCO café\n    HALT\n").is_err());
+    }
 }
@@ -1,1576 +1,903 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::i16;
-use std::io::{self, Write};
-
-use super::token::Token;
-use super::tokenizer;
-use super::{instruction::Instruction, opcode::Opcode};
-use super::super::logkit;
-
-const STACK_SIZE: usize = 32768;
+use std::io;
+use std::time::{Duration, Instant};
+
+use super::error::{IguanaError, LimitKind, RuntimeErrorKind, EXIT_SUCCESS};
+use super::history::{History, UndoRecord, UndoWrite};
+use super::instruction::Instruction;
+use super::io_device::{IoDevice, StdioDevice};
+use super::limits::{ExecutionLimits, ResourceUsage};
+use super::loop_detector::LoopDetector;
+use super::opcode::Opcode;
+use super::program::{MemoryFill, Program, STACK_SIZE};
+use super::watchpoint::{WatchHit, WatchKind, Watchpoint};
+
+/// Result of executing a single instruction with `VirtualMachine::step`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StepOutcome {
+    Continued,
+    Halted, // HALT, EXIT, HALTAC or the end of the program
+    Trapped(IguanaError),
+    WaitingForInput, // the INPUT* operation can be stepped again once the device has input
+}
 
-pub enum InterpreterMode {
-    Execute,
-    Binary,
+/// A function call made by CALL, removed by the RETN that returns from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    pub call_pc: u32, // instruction index of the CALL
+    pub target_pc: u32, // first instruction of the called function
+    pub sp_at_entry: i16, // points to the return address
 }
 
-enum Section {
-    Data,
-    Text,
+/// What to do when a memory word is read before anything was written to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UninitializedReadCheck {
+    #[default]
+    Off,
+    Warn, // keeps running, the first read is available through `first_uninitialized_read`
+    Fatal, // stops with a `RuntimeErrorKind::UninitializedRead` error
 }
 
-pub struct VirtualMachine {
-    file_path: String,
-    output_path: String,
+pub struct VirtualMachine<D: IoDevice = StdioDevice> {
+    program: Program,
+    io_device: D, // Used by the PRINT* and INPUT* operations
+    memory_fill: MemoryFill, // Initial contents of the memory not set by the data section
+
     ac: i16, // Accumulator
     pc: u32, // Program Counter
-    
+
     sp: i16, // Stack Pointer
     stack: [i16; STACK_SIZE ], // Stack
+    initialized: [bool; STACK_SIZE], // Shadow of the stack, true once the word was written by the data section or the program
+
+    uninitialized_read_check: UninitializedReadCheck,
+    first_uninitialized_read: Option<IguanaError>,
 
-    memory: Vec<Instruction>, // Memory, used to store the instructions
+    watchpoints: Vec<Watchpoint>,
+    next_watchpoint_id: u32,
+    watch_hits: Vec<WatchHit>, // hits not taken yet by `take_watch_hits`
 
-    symbol_table: HashMap<String, u32>, // Symbol Table, used to store the address of labels
+    history: History, // Undo log for `reverse_step`, disabled by default
+    call_stack: Vec<CallFrame>, // Shadow of the calls made by CALL
+    checks_return_addresses: bool, // Disabled by default, as RETN is also used to jump through a pushed address
+
+    limits: ExecutionLimits,
+    usage: ResourceUsage, // What was used of the limits since the last reset
+    loop_detector: Option<LoopDetector>, // Disabled by default
+
+    exit_code: i32, // Set by EXIT and HALTAC
+    is_halted: bool,
+    instruction_count: u64, // Instructions executed since the last reset
 }
 
-#[allow(dead_code)]
 impl VirtualMachine {
-    pub fn new(file_path: &str, output_path: &str) -> VirtualMachine {
+    pub fn new(program: &Program) -> VirtualMachine {
+        VirtualMachine::with_io_device(program, StdioDevice)
+    }
+}
+
+impl<D: IoDevice> VirtualMachine<D> {
+    pub fn with_io_device(program: &Program, io_device: D) -> VirtualMachine<D> {
         let mut vm = VirtualMachine {
-            file_path: file_path.to_string(),
-            output_path: output_path.to_string(),
+            program: Program::default(),
+            io_device,
+            memory_fill: MemoryFill::default(),
             ac: 0,
             pc: 0,
             sp: (STACK_SIZE - 1) as i16,
-            memory: Vec::new(),
             stack: [0; STACK_SIZE],
-            symbol_table: HashMap::new(),
+            initialized: [false; STACK_SIZE],
+            uninitialized_read_check: UninitializedReadCheck::default(),
+            first_uninitialized_read: None,
+            watchpoints: Vec::new(),
+            next_watchpoint_id: 1,
+            watch_hits: Vec::new(),
+            history: History::default(),
+            call_stack: Vec::new(),
+            checks_return_addresses: false,
+            limits: ExecutionLimits::default(),
+            usage: ResourceUsage::default(),
+            loop_detector: None,
+            exit_code: EXIT_SUCCESS,
+            is_halted: false,
+            instruction_count: 0,
         };
-
-        // randomize the stack
-        for i in 0..vm.stack.len() {
-            vm.stack[i] = rand::random::<i16>();
-        }
+        vm.load(program);
         vm
     }
 
-    pub fn run(&mut self, interpreter_mode: InterpreterMode) {
-        let tokens = tokenizer::tokenize(&self.file_path);
-        match interpreter_mode {
-            InterpreterMode::Execute => {
-                let _ = self.first_pass(&tokens, &interpreter_mode);
-                self.second_pass(&tokens);
-                self.resolve_branch_addresses();
-                self.execute();
-            }
-            InterpreterMode::Binary => {
-                let _ = self.first_pass(&tokens, &interpreter_mode);
-                self.second_pass(&tokens);
-                self.resolve_branch_addresses();
-
-                let removed_system_call_tokens = tokenizer::get_removed_system_call_tokens(&tokens);
-
-                let is_data_memory_initialized = self.first_pass(&removed_system_call_tokens, &interpreter_mode);
-                self.second_pass(&removed_system_call_tokens);
-                self.resolve_branch_addresses();
-                self.generate_binary(is_data_memory_initialized);
-                
-            }
-        }
-        //self.print_stack();
-        //self.print_symbol_table();
-        //self.print_memory();
+    /// Replaces the loaded program and resets the machine to its initial state.
+    pub fn load(&mut self, program: &Program) {
+        self.program = program.clone();
+        self.reset();
     }
 
-    fn print_stack(&self) {
-        println!("======== Stack ========");
-        for i in ((self.sp as usize) .. STACK_SIZE).rev() {
-            println!("Stack[{}]: {} --- {}", i, self.stack[i], self.stack[i] as u8 as char);
+    /// Puts the machine back to the state right after loading the program.
+    pub fn reset(&mut self) {
+        self.ac = 0;
+        self.pc = 0;
+        self.exit_code = EXIT_SUCCESS;
+        self.is_halted = false;
+        self.instruction_count = 0;
+
+        self.stack.copy_from_slice(&self.program.initial_memory(self.memory_fill));
+        self.sp = self.program.initial_sp();
+
+        self.initialized.fill(false);
+        for (i, value) in self.program.data.iter().enumerate() {
+            self.initialized[Program::data_address(i)] = value.is_some();
         }
-        println!("=======================");
+        self.first_uninitialized_read = None;
+        self.watch_hits.clear();
+        self.history.clear();
+        self.call_stack.clear();
+        self.usage = ResourceUsage::default();
+        self.restart_loop_detector();
     }
 
-    fn print_symbol_table(&self) {
-        println!("======== Symbol Table ========");
-        for i in 0..self.symbol_table.len() {
-            println!("{} --> {} :: {:?}", i, self.symbol_table.keys().nth(i).unwrap(), self.symbol_table.values().nth(i).unwrap());
-        }
-        println!("=============================");
+    /// Changes how the memory is filled before the data section is loaded, and resets the machine.
+    pub fn set_memory_fill(&mut self, memory_fill: MemoryFill) {
+        self.memory_fill = memory_fill;
+        self.reset();
+    }
+
+    pub fn set_uninitialized_read_check(&mut self, check: UninitializedReadCheck) {
+        self.uninitialized_read_check = check;
+    }
+
+    /// First read of a word that was never written, recorded while the check is not `Off`.
+    pub fn first_uninitialized_read(&self) -> Option<&IguanaError> {
+        self.first_uninitialized_read.as_ref()
+    }
+
+    /// Watches the addresses from `first_address` to `last_address`, returns the id of the watchpoint.
+    pub fn add_watchpoint(&mut self, first_address: u32, last_address: u32, kind: WatchKind) -> u32 {
+        let id = self.next_watchpoint_id;
+        self.next_watchpoint_id += 1;
+        self.watchpoints.push(Watchpoint { id, first_address, last_address, kind });
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: u32) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| watchpoint.id != id);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Hits of the watchpoints not taken yet.
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        &self.watch_hits
+    }
+
+    /// Hits of the watchpoints since the last call, in execution order.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Bounds the instructions, time, sleep and output of the following runs, exceeding one of them
+    /// traps with an `ErrorKind::LimitExceeded` error.
+    pub fn set_execution_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+    }
+
+    pub fn execution_limits(&self) -> &ExecutionLimits {
+        &self.limits
+    }
+
+    /// What was used of the limits since the last reset.
+    pub fn resource_usage(&self) -> &ResourceUsage {
+        &self.usage
+    }
+
+    /// Stops the program with a `RuntimeErrorKind::InfiniteLoop` error when it comes back to the same pc, ac, sp,
+    /// memory and calls without reading input in between, as it would then repeat the same instructions forever.
+    pub fn set_loop_detection(&mut self, is_enabled: bool) {
+        self.loop_detector = match is_enabled {
+            true => Some(LoopDetector::new(&self.stack)),
+            false => None,
+        };
+    }
+
+    /// Stops the program with a `RuntimeErrorKind::ReturnAddressMismatch` error when RETN would not return to the
+    /// instruction after the CALL of the current function. Otherwise such a RETN is a jump and ends the call.
+    pub fn set_return_address_check(&mut self, is_enabled: bool) {
+        self.checks_return_addresses = is_enabled;
     }
 
-    fn print_memory(&self) {
-        println!("======== Memory ========");
-        for i in 0..self.memory.len() {
-            println!("{} --> {:?}", i, self.memory[i]);
+    /// Records up to `max_length` executed instructions (0 disables it) using at most
+    /// `memory_budget` bytes, so they can be undone with `reverse_step`.
+    pub fn set_history_limits(&mut self, max_length: usize, memory_budget: usize) {
+        self.history.set_limits(max_length, memory_budget);
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// Undoes the last recorded instruction, `false` when there is nothing left to undo.
+    /// The input already read and the output already written are not undone.
+    pub fn reverse_step(&mut self) -> bool {
+        let record = match self.history.pop() {
+            Some(record) => record,
+            None => return false,
+        };
+        for write in record.writes.iter().rev() {
+            self.stack[write.address as usize] = write.old_value;
+            self.initialized[write.address as usize] = write.was_initialized;
+        }
+        self.ac = record.ac;
+        self.sp = record.sp;
+        self.pc = record.pc;
+        self.exit_code = record.exit_code;
+        self.call_stack.truncate(record.call_stack_len);
+        if let Some(frame) = record.popped_frame {
+            self.call_stack.push(frame);
         }
-        println!("=======================");
+        self.is_halted = false;
+        self.instruction_count -= 1;
+        self.restart_loop_detector();
+        true
     }
 
-    fn print_tokens(&self, tokens: &Vec<Token>) {
-        println!("======== Tokens ========");
-        for i in 0..tokens.len() {
-            println!("{} --> {:?}", i, tokens[i]);
+    pub fn run(&mut self) -> Result<(), IguanaError> {
+        self.run_with(|_, _| {})
+    }
+
+    /// Like `run`, calling `on_step` with the machine and the outcome after every step.
+    pub fn run_with<F: FnMut(&mut Self, &StepOutcome)>(&mut self, mut on_step: F) -> Result<(), IguanaError> {
+        loop {
+            let outcome = self.step();
+            on_step(self, &outcome);
+            match outcome {
+                StepOutcome::Continued => {},
+                StepOutcome::Halted => break,
+                StepOutcome::Trapped(error) => return Err(error),
+                StepOutcome::WaitingForInput => {
+                    let location = self.program.source_map.location(self.pc).unwrap();
+                    let error = IguanaError::runtime(RuntimeErrorKind::Io, "Error reading input.", location.line, location.col).in_file(location.file);
+                    return Err(self.add_error_context(error));
+                }
+            }
         }
-        println!("=======================");
+        if self.io_device.write_text("\n").is_err() {
+            return Err(IguanaError::runtime(RuntimeErrorKind::Io, "Error writing output.", 0, 0));
+        }
+        Ok(())
     }
 
-    fn first_pass(&mut self, raw_tokens_vector: &Vec<Token>, interpreter_mode: &InterpreterMode) -> bool {
-        // Randomize the stack
-        for i in 0..self.stack.len() {
-            self.stack[i] = rand::random::<i16>();
+    /// Executes exactly one instruction.
+    pub fn step(&mut self) -> StepOutcome {
+        if self.is_halted {
+            return StepOutcome::Halted;
         }
-        self.sp = (STACK_SIZE - 1) as i16;
-        // ==== PRIMEIRA PASSAGEM ====
-        let mut section = Section::Text;
-        let mut last_line_initialized = 0;
-        let mut is_data_memory_initialized = false;
-
-        let mut token_counter = 0;
-        'token_counter_loop: while token_counter < raw_tokens_vector.len() {
-            let actual_raw_token_option = raw_tokens_vector.get(token_counter).cloned();
-
-            match actual_raw_token_option {
-                Some(actual_raw_token) => {
-                    match actual_raw_token.get_token().as_str() {
-                        ".data" => { section = Section::Data; },
-                        ".text" => { section = Section::Text; },
-                        _ => {
-                            match section {
-                                Section::Data => {
-                                    /* logica
-                                     * pegar o token atual, ver se ele é uma label, se for:
-                                     *    pegar o próximo token, ver se ele é .word, .byte, .ascii ou .asciiz
-                                     *    se for ver se o proximo token é um valor valido e se tem virgula após ele,
-                                     *    se for, ir adicionando os valores numa Vec<i8> de acordo com o tipo do .word, .byte, .ascii ou .asciiz
-                                     *    caso não tenha virgula, adicionar apenas o único valor no Vec<i8> e ir para o próximo token, que pode ser uma nova label
-                                     */
-
-                                    
-                                    if actual_raw_token.is_label() {
-                                        let label = actual_raw_token.get_token()[..actual_raw_token.get_token().len()-1].to_string();
-                                        
-                                        // Isso serve para impedir que uma label tenha o mesmo nome de uma instrução
-                                        if Opcode::from_str(label.as_str()).is_some() {
-                                            logkit::exit_with_positional_error_message(
-                                                format!("Label '{}' cannot have the same name of an instruction.", label).as_str(),
-                                                actual_raw_token.line,
-                                                actual_raw_token.col,
-                                            );
-                                        }
-                                        
-                                        let next_raw_token_option = get_nth_token(&raw_tokens_vector, token_counter+1);
-                                        if next_raw_token_option.is_none() {
-                                            logkit::exit_with_positional_error_message(
-                                                format!("Expected '.word', '.byte', '.space', '.ascii', or '.asciiz' after label '{}'.", label).as_str(),
-                                                actual_raw_token.line,
-                                                actual_raw_token.col,
-                                            );
-                                        } else {
-                                            
-                                            let next_raw_token = next_raw_token_option.unwrap();
-                                            match next_raw_token.get_token().as_str() {
-                                                ".word" | ".byte" => {
-                                                    /*
-                                                     * ir pegando todos os valores até não ter mais virgula
-                                                     * nesse momento:
-                                                     *      actual_raw_token = Label
-                                                     *      next_raw_token = .word ou .byte
-                                                     *      next_next_raw_token = <valor>
-                                                     */
-
-                                                    let aux_value_counter = token_counter + 2; // <valor>
-                                                    let mut values: Vec<i16> = Vec::new();
-                                                    
-                                                    if next_raw_token.get_token() == ".word" {
-                                                        values = get_comma_separated_values(&raw_tokens_vector, aux_value_counter, false);
-                                                    } else if next_raw_token.get_token() == ".byte" {
-                                                        values = get_comma_separated_values(&raw_tokens_vector, aux_value_counter, true);
-                                                    }
-
-                                                    if values.len() == 0 {
-                                                        logkit::exit_with_positional_error_message(
-                                                            format!("Expected at least one valid value after '{}', but found none.", next_raw_token.get_token()).as_str(),
-                                                            actual_raw_token.line,
-                                                            actual_raw_token.col,
-                                                        );
-                                                    } else {
-
-                                                        let mut aux_sp_address = match self.sp.checked_sub(1) {
-                                                            Some(v) => {
-                                                                if v < 0 {
-                                                                    logkit::exit_with_positional_error_message(
-                                                                        "Stack overflow: insufficient space to insert values.",
-                                                                        actual_raw_token.line,
-                                                                        actual_raw_token.col,
-                                                                    );
-                                                                    0
-                                                                } else {
-                                                                    v
-                                                                }
-                                                            },
-                                                            None => {
-                                                                logkit::exit_with_positional_error_message(
-                                                                    "Stack overflow: insufficient space to insert values.",
-                                                                    next_raw_token.line,
-                                                                    next_raw_token.col,
-                                                                );
-                                                                0
-                                                            }
-                                                        };
-
-                                                        aux_sp_address = if is_data_memory_initialized {
-                                                            aux_sp_address
-                                                        } else {
-                                                            self.sp
-                                                        };
-
-                                                        self.symbol_table.insert(
-                                                            label,
-                                                            aux_sp_address as u32,
-                                                        );
-
-                                                        for v in &values {
-                                                            let aux_sp_value = match self.sp.checked_sub(1) {
-                                                                Some(v) => v,
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message(
-                                                                        "Stack overflow: insufficient space to insert values.",
-                                                                        actual_raw_token.line,
-                                                                        actual_raw_token.col,
-                                                                    );
-                                                                    0
-                                                                }
-                                                            }; 
-                                                            
-                                                            if is_data_memory_initialized == true {
-                                                                self.sp = aux_sp_value;
-                                                            }
-
-                                                            match self.stack.get_mut(self.sp as usize) {
-                                                                Some(stack_item) => {
-                                                                    *stack_item = v.clone();
-                                                                    is_data_memory_initialized = true;
-                                                                    
-                                                                },
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message(
-                                                                        "Stack overflow: insufficient space to insert values.",
-                                                                        actual_raw_token.line,
-                                                                        actual_raw_token.col,
-                                                                    );
-                                                                }
-                                                            }
-                                                        }
-
-                                                        token_counter += values.len()*2 + 1;
-                                                        continue;
-                                                    }
-
-                                                },
-                                                ".ascii" | ".asciiz" => {
-                                                    let next_next_raw_token_option = get_nth_token(&raw_tokens_vector, token_counter+2);
-                                                    match next_next_raw_token_option {
-                                                        Some(next_next_raw_token) => {
-                                                            if next_next_raw_token.is_string_literal()  {
-                                                                match next_next_raw_token.to_string_literal() {
-                                                                    Some(string_literal) => {
-                                                                        let mut aux_sp_address = match self.sp.checked_sub(1) {
-                                                                            Some(v) => {
-                                                                                if v < 0 {
-                                                                                    logkit::exit_with_positional_error_message("Stack overflow: no space left to insert string literal", next_next_raw_token.line, next_next_raw_token.col);
-                                                                                    0
-                                                                                } else {
-                                                                                    v
-                                                                                }
-                                                                            },
-                                                                            None => {
-                                                                                logkit::exit_with_positional_error_message("Stack overflow: no space left to insert string literal", next_next_raw_token.line, next_next_raw_token.col);
-                                                                                0
-                                                                            }
-                                                                        };
-
-                                                                        aux_sp_address = if is_data_memory_initialized {
-                                                                            aux_sp_address
-                                                                        } else {
-                                                                            self.sp
-                                                                        };
-
-                                                                        self.symbol_table.insert(
-                                                                            label,
-                                                                            aux_sp_address as u32,
-                                                                        );
-                                                                        
-                                                                        
-                                                                        let mut string_literal_bytes: Vec<u8> = string_literal.as_bytes().to_vec();
-                                                                    
-                                                                        if next_raw_token.get_token() == ".asciiz" {
-                                                                            string_literal_bytes.push(0);
-                                                                        }
-                                                                        /* 
-                                                                         *  NÃO SEI SE ISSO É NECESSÁRIO, POIS NÃO SEI SE A ORDEM DOS BYTES IMPORTA
-                                                                         *  O mais convencional é que e.g.:
-                                                                         *  "abc" -> [97, 98, 99]
-                                                                         *  stack:
-                                                                         *    127 -> 99 (c)
-                                                                         *    126 -> 98 (b)
-                                                                         *    125 -> 97 (a)
-                                                                         * 
-                                                                         *  ou seja, o primeiro byte da string literal é o último a ser colocado na stack (a stack cresce de cima para baixo)
-                                                                         */
-
-
-                                                                         for b in string_literal_bytes {
-                                                                            if self.sp <= 0 {
-                                                                                logkit::exit_with_positional_error_message("Stack overflow: no space left to insert string literal", next_next_raw_token.line, next_next_raw_token.col);
-                                                                            }
-
-                                                                            let aux_sp_value = match self.sp.checked_sub(1) {
-                                                                                Some(v) => v,
-                                                                                None => {
-                                                                                    logkit::exit_with_positional_error_message("Stack overflow: no space left to insert string literal", next_next_raw_token.line, next_next_raw_token.col);
-                                                                                    0
-                                                                                }
-                                                                            };
-
-                                                                            if is_data_memory_initialized == true {
-                                                                                self.sp = aux_sp_value;
-                                                                            }
-
-                                                                            match self.stack.get_mut(self.sp as usize) {
-                                                                                Some(stack_item) => {
-                                                                                    
-                                                                                    *stack_item = b as i16;
-                                                                                    is_data_memory_initialized = true;
-                                                                                },
-                                                                                None => {
-                                                                                    logkit::exit_with_positional_error_message("Stack overflow: no space left to insert string literal", next_next_raw_token.line, next_next_raw_token.col);
-                                                                                }
-                                                                            }
-                                                                        }
-
-                                                                        token_counter += 3;
-                                                                        continue;
-                                                                    },
-                                                                    None => {
-                                                                        logkit::exit_with_positional_error_message("Expect a valid string after .ascii or asciiz", next_next_raw_token.line, next_next_raw_token.col);
-                                                                    }
-                                                                }
-                                                            } else {
-                                                                logkit::exit_with_positional_error_message("Expect a valid string after .ascii or asciiz", next_next_raw_token.line, next_next_raw_token.col);
-                                                            }
-                                                        },
-                                                        None => {
-                                                            logkit::exit_with_positional_error_message("Expect a valid string after .ascii or asciiz", next_raw_token.line, next_raw_token.col);
-                                                        }
-                                                    }
-                                                },
-                                                ".space" => {
-                                                    /*
-                                                     *  Na minha ISA, o .space aloca espaço na memoria (stack) e não inicializa com nenhum valor
-                                                     *  então, o valor do .space é a quantidade de bytes que serão alocados na stack
-                                                     *  Não é possivel que o argumento do .space seja um numero negativo nem impar, pois a stack é de 16 bits
-                                                     *  e.g.:
-                                                     *      AREA: .space 10 ou .space 0xa -> aloca 10 bytes na stack
-                                                     *      AREA: .space 11 -> erro, pois a stack é de 16 bits e não pode alocar um número ímpar de bytes
-                                                     *      AREA: .space -10 -> erro, pois a stack não pode alocar um número negativo de bytes
-                                                     *      AREA: .space 0b1010 -> aloca 10 bytes na stack
-                                                     *      AREA: .space 0x0a -> aloca 10 bytes na stack
-                                                     */
-                                                    let next_next_raw_token_option = get_nth_token(&raw_tokens_vector, token_counter+2);
-                                                    match next_next_raw_token_option {
-                                                        Some(next_next_raw_token) => {
-                                                            let value: i32 = match next_next_raw_token.to_i32_value() {
-                                                                Some(v) => v,
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message("Expected a valid value after .space", next_next_raw_token.line, next_next_raw_token.col);
-                                                                    0
-                                                                }
-                                                            };
-                                                            
-                                                            if value < 0 {
-                                                                logkit::exit_with_positional_error_message("Expected a positive value after .space", next_next_raw_token.line, next_next_raw_token.col);
-                                                            }
-                                                            if value % 2 != 0 {
-                                                                logkit::exit_with_positional_error_message("Expected an even (multiple of 2) value after .space, this ISA only supports 16 bits stack values", next_next_raw_token.line, next_next_raw_token.col);
-                                                            }
-                                                            
-                                                            let mut aux_sp_address = match self.sp.checked_sub(1) {
-                                                                Some(v) => {
-                                                                    if v < 0 {
-                                                                        logkit::exit_with_positional_error_message("Stack overflow: no space left to insert .space", next_next_raw_token.line, next_next_raw_token.col);
-                                                                        0
-                                                                    } else {
-                                                                        v
-                                                                    }
-                                                                },
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message("Stack overflow: no space left to insert .space", next_next_raw_token.line, next_next_raw_token.col );
-                                                                    0
-                                                                }
-                                                            };
-
-                                                            aux_sp_address = if is_data_memory_initialized {
-                                                                aux_sp_address
-                                                            } else {
-                                                                self.sp
-                                                            };
-                                                            
-                                                            self.symbol_table.insert(
-                                                                label,
-                                                                aux_sp_address as u32,
-                                                            );
-
-                                                            for _ in 0..(value/2) {
-                                                                if self.sp <= 0 {
-                                                                    logkit::exit_with_positional_error_message("Stack overflow: no space left to insert .space", next_next_raw_token.line, next_next_raw_token.col);
-                                                                }
-                                                                
-                                                                if is_data_memory_initialized {
-                                                                    self.sp = match self.sp.checked_sub(1) {
-                                                                        Some(v) => v,
-                                                                        None => {
-                                                                            logkit::exit_with_positional_error_message("Stack overflow: no space left to insert .space", next_next_raw_token.line, next_next_raw_token.col);
-                                                                            0
-                                                                        }
-                                                                    };
-                                                                } else {
-                                                                    is_data_memory_initialized = true;
-                                                                }
-                                                            }
-                                                            
-                                                            token_counter += 3;
-                                                            continue;
-                                                            
-                                                        },
-                                                        None => {
-                                                            logkit::exit_with_positional_error_message("Expected a valid value after .space", next_raw_token.line, next_raw_token.col);
-                                                        }
-                                                    }
-                                                }
-                                                _ => {
-                                                    logkit::exit_with_positional_error_message("Expected .word, .byte, .ascii or .asciiz after label", next_raw_token.line, next_raw_token.col);
-                                                }
-                                            }
-                                
-                                        }
-                                    } else {
-                                        logkit::exit_with_positional_error_message("Expected a valid label or a valid value, labels cannot start with numbers", actual_raw_token.line, actual_raw_token.col);
-                                    }
-                                },
-                                Section::Text => {
-                                    /*  logica
-                                     *  primeiro, ver se o token atual é uma label, se for:
-                                     *      adicionar a label na symbol_table com o valor do ''pc'', 
-                                     *      raw_token.label <- label,
-                                     *      
-                                     */
-                                    if actual_raw_token.is_label() {
-
-                                        match get_next_closest_instruction_line_by_token_counter( &raw_tokens_vector, token_counter ) {
-                                            Some(next_closest_instruction_line) => {
-                                                if last_line_initialized >= next_closest_instruction_line {
-                                                    logkit::exit_with_positional_error_message("You cannot initialize labels this way. Do not put instructions after multiple labels declarations at the same line", actual_raw_token.line, actual_raw_token.col); 
-                                                }
-                                            },
-                                            None => {
-                                                match interpreter_mode {
-                                                    InterpreterMode::Execute => {
-                                                        logkit::exit_with_positional_error_message("Expected an instruction after label", actual_raw_token.line, actual_raw_token.col);
-                                                    }
-                                                    InterpreterMode::Binary => {
-                                                        logkit::exit_with_positional_error_message("Expected an instruction after label, remember that syscall instructions are removed in binary mode", actual_raw_token.line, actual_raw_token.col);
-                                                    }
-                                                }
-                                            }
-                                        }
-
-                                        let label: String = actual_raw_token.get_token()[..actual_raw_token.get_token().len()-1].to_string();
-
-                                        // Isso serve para impedir que uma label tenha o mesmo nome de uma instrução
-                                        if Opcode::from_str(label.as_str()).is_some() {
-                                            logkit::exit_with_positional_error_message(
-                                                format!("Label '{}' cannot have the same name of an instruction.", label).as_str(),
-                                                actual_raw_token.line,
-                                                actual_raw_token.col,
-                                            );
-                                        }
-                                        
-                                        let next_closest_instruction_line_option: Option<u32> = get_next_closest_instruction_line_by_token_counter(&raw_tokens_vector, token_counter + 1);
-                                        match next_closest_instruction_line_option {
-                                            Some(next_closest_instruction_line) => {
-                                                self.symbol_table.insert(
-                                                    label,
-                                                    next_closest_instruction_line,
-                                                );
-                                            },
-                                            None => {
-                                                match interpreter_mode {
-                                                    InterpreterMode::Execute => {
-                                                        logkit::exit_with_positional_error_message("Expected an instruction after label", actual_raw_token.line, actual_raw_token.col);
-                                                    }
-                                                    InterpreterMode::Binary => {
-                                                        logkit::exit_with_positional_error_message("Expected an instruction after label, remember that syscall instructions are removed in binary mode", actual_raw_token.line, actual_raw_token.col);
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    } else {
-                                        match get_next_closest_instruction_line_by_token_counter( &raw_tokens_vector, token_counter ) {
-                                            Some(next_closest_instruction_line) => {
-                                                last_line_initialized = next_closest_instruction_line;
-                                            },
-                                            None => {
-                                                logkit::exit_with_positional_error_message("Expected an instruction or a label before an instruction", actual_raw_token.line, actual_raw_token.col);
-                                            }
-                                        }
-                                        let next_opcode = Opcode::from_str(actual_raw_token.get_token().as_str());
-                                        if next_opcode.is_some() {
-                                            if Opcode::is_argumented(next_opcode.unwrap()) {
-                                                token_counter += 2;
-                                            } else {
-                                                token_counter += 1;
-                                            }
-
-                                        } else {
-                                            token_counter += 1;
-                                        }
-                                
-                                        continue;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                },
-                None => { 
-                    break 'token_counter_loop; 
-                }
+        let instruction = match self.program.instructions.get(self.pc as usize) {
+            Some(instruction) => *instruction,
+            None => {
+                self.is_halted = true;
+                return StepOutcome::Halted;
             }
-            token_counter += 1;
+        };
+
+        if self.usage.started_at.is_none() {
+            self.usage.started_at = Some(Instant::now());
+        }
+        if let Err(error) = self.check_limits(&instruction) {
+            return StepOutcome::Trapped(self.add_error_context(error));
         }
 
-        is_data_memory_initialized
-    }
-
-    fn second_pass(&mut self, raw_tokens: &Vec<Token>) {
-        self.memory.clear();
-        let mut section = Section::Text;
-        let mut token_counter = 0;
-        'token_counter_loop: while token_counter < raw_tokens.len() {
-            let actual_raw_token_option = raw_tokens.get(token_counter).cloned();
-            match actual_raw_token_option {
-                Some(actual_raw_token) => {
-                    match actual_raw_token.get_token().as_str() {
-                        ".data" => { section = Section::Data; token_counter += 1; },
-                        ".text" => { section = Section::Text; token_counter += 1; },
-                        _ => {
-                            match section {
-                                Section::Data => { token_counter += 1; },
-                                Section::Text => {
-                                    if Opcode::from_str(actual_raw_token.get_token().as_str()).is_some() {
-                                        match Opcode::from_str(actual_raw_token.get_token().as_str()) {
-                                            Some(opcode) => {
-                                                if Opcode::is_argumented(opcode) {
-                                                    match opcode {
-                                                        Opcode::Jpos | Opcode::Jzer | Opcode::Jump | Opcode::Jneg | Opcode::Jnze | Opcode::Call | Opcode::Printlninstruction | Opcode::Printinstruction => {
-                                                            match get_nth_token(&raw_tokens, token_counter + 1) {
-                                                                Some(next_raw_token) => {
-                                                                    let raw_argument: u32 = if self.symbol_table.contains_key(&next_raw_token.get_token()) {
-                                                                        let label = next_raw_token.get_token();
-                                                                        match self.symbol_table.get(&label) {
-                                                                            Some(label_line_address) => {
-                                                                                *label_line_address
-                                                                            },
-                                                                            None => {
-                                                                                logkit::exit_with_positional_error_message(
-                                                                                    format!("Label '{}' not found in the symbol table.", label).as_str(),
-                                                                                    next_raw_token.line,
-                                                                                    next_raw_token.col,
-                                                                                );
-                                                                                0
-                                                                            }
-                                                                        }
-                                                                        } else {
-                                                                            match next_raw_token.to_u32_value() {
-                                                                                Some(v) => v,
-                                                                                None => {
-                                                                                    logkit::exit_with_positional_error_message("Expected a label or a valid positive value after instruction", next_raw_token.line, next_raw_token.col);
-                                                                                    0
-                                                                                }
-                                                                            }
-                                                                        };
-
-                                                                    let offsetted_argument: i64 = raw_argument as i64 - actual_raw_token.line as i64;
-
-                                                                    if offsetted_argument < i16::MIN as i64 || offsetted_argument > i16::MAX as i64 {
-                                                                        logkit::exit_with_positional_error_message(format!("Processed control flow instruction with argument {} out of i16 bounds", offsetted_argument).as_str(), next_raw_token.line, next_raw_token.col);
-                                                                    }
-
-                                                                    self.memory.push(
-                                                                        Instruction {
-                                                                            opcode: opcode,
-                                                                            arg: offsetted_argument as i16,
-                                                                            line: actual_raw_token.line,
-                                                                            col: actual_raw_token.col,
-                                                                        }
-                                                                    );
-                                                                },
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message("Expected a label or a value after instruction", actual_raw_token.line, actual_raw_token.col);
-                                                                }
-                                                            }
-                                                            token_counter += 2;
-                                                        },
-                                                        _ => {
-                                                            let next_raw_token_option = get_nth_token(&raw_tokens, token_counter + 1);
-                                                            match next_raw_token_option {
-                                                                Some(next_raw_token) => {
-                                                                    if self.symbol_table.contains_key(&next_raw_token.get_token()) {
-                                                                        let label = next_raw_token.get_token();
-                                                                        let label_address_option = self.symbol_table.get(&label);
-                                                                        match label_address_option {
-                                                                            Some(label_address) => {
-                                                                                self.memory.push(
-                                                                                    Instruction {
-                                                                                        opcode: opcode,
-                                                                                        arg: *label_address as i16,
-                                                                                        line: actual_raw_token.line,
-                                                                                        col: actual_raw_token.col,
-                                                                                    }
-                                                                                );
-
-                                                                                token_counter += 2;
-                                                                            },
-                                                                            None => {
-                                                                                logkit::exit_with_positional_error_message(format!("Label {} not found in symbol table", label).as_str(), next_raw_token.line, next_raw_token.col);
-                                                                            }
-                                                                        }
-                                                                    } else {
-                                                                        if next_raw_token.is_hex_literal() {
-                                                                            let value_result = next_raw_token.from_hex_to_i16();
-                                                                            match value_result {
-                                                                                Some(value) => {
-                                                                                    self.memory.push(
-                                                                                        Instruction {
-                                                                                            opcode: opcode,
-                                                                                            arg: value,
-                                                                                            line: actual_raw_token.line,
-                                                                                            col: actual_raw_token.col,
-                                                                                        }
-                                                                                    );
-
-                                                                                    token_counter += 2;
-                                                                                },
-                                                                                None => {
-                                                                                    logkit::exit_with_positional_error_message("Expected a label or a valid value in range of (-32768...32767) after instruction", next_raw_token.line, next_raw_token.col);
-                                                                                }
-                                                                            }
-                                                                        } else if next_raw_token.is_binary_literal() {
-                                                                            let value_result = next_raw_token.from_binary_to_i16();
-                                                                            match value_result {
-                                                                                Some(value) => {
-                                                                                    self.memory.push(
-                                                                                        Instruction {
-                                                                                            opcode: opcode,
-                                                                                            arg: value,
-                                                                                            line: actual_raw_token.line,
-                                                                                            col: actual_raw_token.col,
-                                                                                        }
-                                                                                    );
-                                                                                    
-                                                                                    token_counter += 2;
-                                                                                },
-                                                                                None => {
-                                                                                    logkit::exit_with_positional_error_message("Expected a label or a valid value in range of (-32768...32767) after instruction", next_raw_token.line, next_raw_token.col);
-                                                                                }
-                                                                            }
-                                                                        } else {
-                                                                            let value_result = next_raw_token.get_token().parse::<i16>();
-                                                                            match value_result {
-                                                                                Ok(value) => {
-                                                                                    self.memory.push(
-                                                                                        Instruction {
-                                                                                            opcode: opcode,
-                                                                                            arg: value,
-                                                                                            line: actual_raw_token.line,
-                                                                                            col: actual_raw_token.col,
-                                                                                        }
-                                                                                    );
-                                                                                    
-                                                                                    token_counter += 2;
-                                                                                },
-                                                                                Err(_) => {
-                                                                                    logkit::exit_with_positional_error_message("Expected a label or a valid value in range of (-32768...32767) after instruction", next_raw_token.line, next_raw_token.col);
-                                                                                }
-                                                                            }
-                                                                        }
-                                                                    }
-                                                                },
-                                                                None => {
-                                                                    logkit::exit_with_positional_error_message("Expected a label or a value after instruction", actual_raw_token.line, actual_raw_token.col);
-                                                                }
-                                                            }
-                                                        }
-                                                    }
-                                                } else { // caso não seja uma instrução com argumentos
-                                                    self.memory.push(
-                                                        Instruction {
-                                                            opcode: opcode,
-                                                            arg: 0,
-                                                            line: actual_raw_token.line,
-                                                            col: actual_raw_token.col,
-                                                        }
-                                                    );
-                                            
-                                                    token_counter += 1;
-                                                }
-                                            },
-                                            None => {
-                                                logkit::exit_with_positional_error_message("Expected an valid instruction", actual_raw_token.line, actual_raw_token.col);
-                                            }
-                                        }
-                                    } else if actual_raw_token.is_label() {
-                                        token_counter += 1;
-                                    } else {
-                                        logkit::exit_with_positional_error_message("Expected an valid instruction", actual_raw_token.line, actual_raw_token.col);
-                                    }
-                                },
-                            }
-                        },
-                    }
-                },
-                None => { break 'token_counter_loop; }
+        if self.history.is_enabled() {
+            self.history.push(UndoRecord {
+                ac: self.ac,
+                sp: self.sp,
+                pc: self.pc,
+                exit_code: self.exit_code,
+                call_stack_len: self.call_stack.len(),
+                popped_frame: None,
+                writes: Vec::new(),
+            });
+        }
+
+        let executed_pc = self.pc;
+        let result = self.execute(instruction);
+        if result != Ok(StepOutcome::WaitingForInput) {
+            self.instruction_count += 1;
+        }
+        if result == Ok(StepOutcome::Continued) {
+            if let Some(error) = self.detect_loop(executed_pc, &instruction) {
+                return StepOutcome::Trapped(self.add_error_context(error));
             }
         }
-        
+        match result {
+            Ok(StepOutcome::WaitingForInput) => {
+                if self.history.is_enabled() {
+                    self.history.pop(); // nothing was executed
+                }
+                StepOutcome::WaitingForInput
+            },
+            Ok(StepOutcome::Halted) => {
+                self.is_halted = true;
+                StepOutcome::Halted
+            },
+            Ok(StepOutcome::Continued) if self.pc as usize >= self.program.instructions.len() => {
+                self.is_halted = true;
+                StepOutcome::Halted
+            },
+            Ok(outcome) => outcome,
+            Err(error) => StepOutcome::Trapped(self.add_error_context(error)),
+        }
     }
 
-    fn resolve_branch_addresses(&mut self) {
-        for (i, instr) in self.memory.clone().iter_mut().enumerate() {
-            match instr.opcode {
-                Opcode::Jpos | Opcode::Jzer | Opcode::Jump | Opcode::Jneg | Opcode::Jnze | Opcode::Call | Opcode::Printlninstruction | Opcode::Printinstruction => {
-                    let targer_instruction_line = instr.line as i64 + instr.arg as i64;
-                    if targer_instruction_line < 0 {
-                        logkit::exit_with_positional_error_message("Expected a positive line value", instr.line, instr.col);
-                    }
+    pub fn ac(&self) -> i16 {
+        self.ac
+    }
 
-                    match self.get_closest_instruction_index_by_line(targer_instruction_line as u32) {
-                        Some(target_instruction_index) => {
-                            match i16::try_from( target_instruction_index as i64 - i as i64 ) {
-                                Ok(offset) => {
-                                    self.memory[i].arg = offset;
-                                }
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message("Branch instruction out of bounds.", instr.line, instr.col);
-                                }
-                            }
-                        }
-                        None => {
-                            self.memory[i].arg = i16::MAX;
-                        }
-                    }
-                },
-                _ => {}
-            }
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> i16 {
+        self.sp
+    }
+
+    pub fn stack(&self) -> &[i16] {
+        &self.stack
+    }
+
+    pub fn set_ac(&mut self, ac: i16) {
+        self.ac = ac;
+        self.restart_loop_detector();
+    }
+
+    pub fn set_sp(&mut self, sp: i16) {
+        self.sp = sp;
+        self.restart_loop_detector();
+    }
+
+    pub fn set_pc(&mut self, pc: u32) {
+        self.pc = pc;
+        self.is_halted = false;
+        self.restart_loop_detector();
+    }
+
+    /// Changes M[address] from outside the program, e.g. from a debugger, without triggering the watchpoints.
+    pub fn set_stack_word(&mut self, address: usize, value: i16) -> bool {
+        match self.stack.get_mut(address) {
+            Some(word) => {
+                *word = value;
+                self.initialized[address] = true;
+                self.restart_loop_detector();
+                true
+            },
+            None => false,
         }
     }
 
-    fn execute(&mut self) {
-        loop {
-            let instruction_option = self.memory.get(self.pc as usize).cloned();
-            match instruction_option {
-                Some(instruction) => {
-                    match instruction.opcode {
-                        Opcode::Lodd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Stod => {
-                            match self.set_stack_value(instruction.arg as i64, self.ac) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Addd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    if *value != 0 {
-                                        let aux_option = self.ac.checked_add(*value);
-                                        match aux_option {
-                                            Some(aux) => {
-                                                self.ac = aux;
-                                            },
-                                            None => {
-                                                logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                            }
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },    
-                        Opcode::Subd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    if *value != 0 {
-                                        let aux_option = self.ac.checked_sub(*value);
-                                        match aux_option {
-                                            Some(aux) => {
-                                                self.ac = aux;
-                                            },
-                                            None => {
-                                                logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                            }
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Jpos => {
-                            let target_instruction_pc = self.pc as i64 + instruction.arg as i64;
-                            if target_instruction_pc < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-
-                            if self.ac > 0 {
-                                self.pc = target_instruction_pc as u32;
-                            } else {
-                                self.pc += 1;
-                            }
-                        },
-                        Opcode::Jzer => {
-                            let target_instruction_pc = self.pc as i64 + instruction.arg as i64;
-                            if target_instruction_pc < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-                            if self.ac == 0 {
-                                self.pc = target_instruction_pc as u32;
-                            } else {
-                                self.pc += 1;
-                            }
-                        },
-                        Opcode::Jump => {
-                            let target_instruction_pc = self.pc as i64 + instruction.arg as i64;
-                            if target_instruction_pc < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-                            self.pc = target_instruction_pc as u32;
-                        },
-                        Opcode::Loco => {
-                            self.ac = instruction.arg;
-                            self.pc += 1;
-                        },
-                        Opcode::Lodl => {
-                            match self.stack.get((self.sp as i64 + instruction.arg as i64) as usize) {
-                                Some(value) => {
-                                    self.ac = *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Stol => {
-                            match self.set_stack_value(self.sp as i64 + instruction.arg as i64, self.ac) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Addl => {
-                            match self.stack.get((self.sp as i64 + instruction.arg as i64) as usize) {
-                                Some(value) => {
-                                    let aux_option = self.ac.checked_add(*value);
-                                    match aux_option {
-                                        Some(aux) => {
-                                            self.ac = aux;
-                                        },
-                                        None => {
-                                            logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64 ).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Subl => {
-                            match self.stack.get((self.sp as i64 + instruction.arg as i64) as usize) {
-                                Some(value) => {
-                                    let aux_option = self.ac.checked_sub(*value);
-                                    match aux_option {
-                                        Some(aux) => {
-                                            self.ac = aux;
-                                        },
-                                        None => {
-                                            logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Jneg => {
-                            let target_instruction_index = self.pc as i64 + instruction.arg as i64;
-
-                            if target_instruction_index < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-
-                            if self.ac < 0 {
-                                self.pc = target_instruction_index as u32;
-                            } else {
-                                self.pc += 1;
-                            }
-
-                        },
-                        Opcode::Jnze => {
-                            let targe_instruction_index = self.pc as i64 + instruction.arg as i64;
-                            if targe_instruction_index < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-                            if self.ac != 0 {
-                                self.pc = targe_instruction_index as u32;
-                            } else {
-                                self.pc += 1;
-                            }
-
-                        },
-                        Opcode::Call => {
-                            let target_instruction_index = self.pc as i64 + instruction.arg as i64;
-                            if target_instruction_index < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-
-                            match self.sp.checked_sub(1) {
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-
-                            let next_pc = match self.pc.checked_add(1) {
-                                Some(aux) => {
-                                    if aux >= i16::MAX as u32 {
-                                        logkit::exit_with_positional_error_message("PC out of bounds for insertion in stack", instruction.line, instruction.col);
-                                        0
-                                    } else {
-                                        match i16::try_from(aux) {
-                                            Ok(aux) => aux,
-                                            Err(_) => {
-                                                logkit::exit_with_positional_error_message("PC out of bounds for insertion in stack", instruction.line, instruction.col);
-                                                0
-                                            }
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("PC out of bounds", instruction.line, instruction.col);
-                                    0
-                                }
-                            };
-
-                            match self.set_stack_value( self.sp as i64, next_pc ) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc = target_instruction_index as u32;
-
-                        },
-                        Opcode::Pshi => {
-                            match self.sp.checked_sub(1) {
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-                            let aux = match self.stack.get(self.ac as usize) {
-                                Some(value) => *value,
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.ac).as_str(), instruction.line, instruction.col);
-                                    0
-                                }
-                            };
-
-                            match self.set_stack_value(self.sp as i64, aux) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Popi => {
-                            let aux = match self.get_stack_value(self.sp as i64) {
-                                Some(value) => value,
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                    0
-                                }
-                            };
-                            match self.set_stack_value(self.ac as i64, aux) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.ac).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            match self.sp.checked_add(1) { // decrementa o sp
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Push => {
-                            match self.sp.checked_sub(1) {
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-                            match self.set_stack_value(self.sp as i64, self.ac) {
-                                Ok(_) => {},
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Pop => {
-                            self.ac = match self.stack.get(self.sp as usize) {
-                                Some(value) => *value,
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                    0
-                                }
-                            };
-                            match self.sp.checked_add(1) { // decrementa o sp
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-
-                        },
-                        Opcode::Retn => {   
-
-                            if instruction.arg < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive value", instruction.line, instruction.col);
-                            }                      
-                            
-                            let sp_value = match self.stack.get(self.sp as usize) {
-                                Some(value) => *value,
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp).as_str(), instruction.line, instruction.col);
-                                    0
-                                }
-                            };
-
-                            self.pc = sp_value as u32;
-                            match self.sp.checked_add(1) { // decrementa o sp
-                                Some(aux) => {
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-
-                        },
-                        Opcode::Swap => {
-                            if self.ac < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive value in AC to swap with SP", instruction.line, instruction.col);
-                            }
-                            let tmp = self.ac;
-                            self.ac = self.sp;
-                            self.sp = tmp;
-                            self.pc += 1;
-                            
-                        },
-                        Opcode::Desp => {
-                            match (self.sp).checked_sub(instruction.arg) {
-                                Some(aux) => {
-                                    if aux < 0 {
-                                        logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                    }
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc += 1;
-                        },
-                        Opcode::Insp => {
-                            match (self.sp).checked_add(instruction.arg) {
-                                Some(aux) => {
-                                    if aux < 0 {
-                                        logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                    }
-                                    self.sp = aux;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc += 1;
-                        },
-                        
-                        /* 
-                         * APARTIR DAQUI, SÃO OPERAÇÕES QUE EU CRIEI
-                         */
-                        Opcode::Halt => {
-                            break;
-                        },
-
-                        /*
-                         *  DEBUG PRINTS
-                         */
-                        Opcode::Printlnac => {
-                            println!("{}", self.ac);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        },
-                        Opcode::Printac => {
-                            print!("{}", self.ac);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        }
-
-                        Opcode::Printlnacchar => {
-                            println!("{}", self.ac as u8 as char);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        }
-                        Opcode::Printacchar => {
-                            print!("{}", self.ac as u8 as char);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        }
-
-                        Opcode::Printsp => {
-                            print!("{}", self.sp);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        },
-
-                        Opcode::Printlnsp => {
-                            println!("{}", self.sp);
-                            io::stdout().flush().unwrap();
-                            self.pc += 1;
-                        },
-
-                        Opcode::Printinstruction => {
-                            let target_instruction_index = self.pc as i64 + instruction.arg as i64;
-
-                            if target_instruction_index < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-
-                            match self.memory.get(target_instruction_index as usize) {
-                                Some(instruction) => {
-                                    print!("{}", instruction.to_format());
-                                    io::stdout().flush().unwrap();
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(
-                                        format!("Instruction at line {} not found or out of bounds.", instruction.arg).as_str(),
-                                        instruction.line,
-                                        instruction.col,
-                                    );
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Printlninstruction => {
-                            let target_instruction_index = self.pc as i64 + instruction.arg as i64;
-                            if target_instruction_index < 0 {
-                                logkit::exit_with_positional_error_message("Expected a positive pc value", instruction.line, instruction.col);
-                            }
-                            match self.memory.get(target_instruction_index as usize) {
-                                Some(instruction) => {
-                                    println!("{}", instruction.to_format());
-                                    io::stdout().flush().unwrap();
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(
-                                        format!("Instruction at line {} not found or out of bounds.", instruction.arg).as_str(),
-                                        instruction.line,
-                                        instruction.col,
-                                    );
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Andi => {
-                            self.ac = self.ac & instruction.arg;
-                            self.pc += 1;
-                        },
-                        Opcode::Ori => {
-                            self.ac = self.ac | instruction.arg;
-                            self.pc += 1;
-                        },
-                        Opcode::Xori => {
-                            self.ac = self.ac ^ instruction.arg;  
-                            self.pc += 1;
-                        },
-                        Opcode::Not => {
-                            self.ac = !self.ac;
-                            self.pc += 1;  
-                        },
-                        Opcode::Shfli => {
-                            self.ac = self.ac << instruction.arg;
-                            self.pc += 1;
-                                
-                        },
-                        Opcode::Shfri => {
-                            self.ac = self.ac >> instruction.arg;
-                            self.pc += 1;
-                        },
-                        
-                        Opcode::Andd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = self.ac & *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Ord => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = self.ac | *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Xord => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = self.ac ^ *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Notd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = !*value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Shfrd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = self.ac >> *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Shfld => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    self.ac = self.ac << *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Muld => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    let aux_option = self.ac.checked_mul(*value);
-                                    match aux_option {
-                                        Some(aux) => {
-                                            self.ac = aux;
-                                        },
-                                        None => {
-                                            logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Divd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    if *value == 0 {
-                                        logkit::exit_with_positional_error_message("Division by zero is not allowed.", instruction.line, instruction.col);
-                                    }
-                                    self.ac = self.ac / *value;
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Mull => {
-                            match self.stack.get((self.sp as i64 + instruction.arg as i64) as usize) {
-                                Some(value) => {
-                                    let aux_option = self.ac.checked_mul(*value);
-                                    match aux_option {
-                                        Some(aux) => {
-                                            self.ac = aux;
-                                        },
-                                        None => {
-                                            logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64 ).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Divl => {
-                            match self.stack.get((self.sp as i64 + instruction.arg as i64) as usize) {
-                                Some(value) => {
-                                    let aux_option = self.ac.checked_div(*value);
-                                    match aux_option {
-                                        Some(aux) => {
-                                            self.ac = aux;
-                                        },
-                                        None => {
-                                            logkit::exit_with_positional_error_message("Value range exceeded (-32768...32767)", instruction.line, instruction.col);
-                                        }
-                                    }
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", self.sp as i64 + instruction.arg as i64).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-
-                        Opcode::Sleepd => {
-                            match self.stack.get(instruction.arg as usize) {
-                                Some(value) => {
-                                    if *value < 0 {
-                                        logkit::exit_with_positional_error_message("Sleep time cannot be negative", instruction.line, instruction.col);
-                                    }
-                                    std::thread::sleep(std::time::Duration::from_millis(*value as u64));
-                                },
-                                None => {
-                                    logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg).as_str(), instruction.line, instruction.col);
-                                }
-                            }
-                            self.pc += 1;
-                        },
-                        Opcode::Sleepi => {
-                            if instruction.arg < 0 {
-                                logkit::exit_with_positional_error_message("Sleep time cannot be negative", instruction.line, instruction.col);
-                            }
-                            std::thread::sleep(std::time::Duration::from_millis(instruction.arg as u64));
-                            self.pc += 1;
-                        }
-
-                        Opcode::Inputac => {
-                            let mut input = String::new();
-                            match io::stdin().read_line(&mut input) {
-                                Ok(_) => {
-                                    match input.trim().parse::<i16>() {
-                                        Ok(value) => {
-                                            self.ac = value;
-                                        },
-                                        Err(_) => {
-                                            logkit::exit_with_positional_error_message("Invalid input. Expected a valid 16 bits number.", instruction.line, instruction.col);
-                                        }
-                                    }
-                                }
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message("Error reading input.", instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc += 1;
-                        }
-
-                        Opcode::Inputacchar => {
-                            let mut input = String::new();
-                            match io::stdin().read_line(&mut input) {
-                                Ok(_) => {
-                                    input = input.trim().to_string();
-                                    if input.len() != 1 {
-                                        logkit::exit_with_positional_error_message("Invalid input. Expected a single character.", instruction.line, instruction.col);
-                                    }
-
-                                    let value = input.chars().nth(0).unwrap() as i16;
-
-                                    self.ac = value;
-                                }
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message("Error reading input.", instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc += 1;
-                        }
-
-                        Opcode::Inputstring => {
-                            let mut input = String::new();
-
-                            match io::stdin().read_line(&mut input) {
-                                Ok(_) => {
-                                    input = input.trim().to_string();
-                                    let mut input_values_vector: Vec<i16> = Vec::new();
-                                    for ch in input.chars() {
-                                        input_values_vector.push(ch as i16);
-                                    }
-                                    input_values_vector.push(0);
-                                    for (i, ch) in input_values_vector.iter().enumerate() {
-                                        match self.set_stack_value( instruction.arg as i64 - i as i64, *ch ) {
-                                            Ok(_) => {},
-                                            Err(_) => {
-                                                logkit::exit_with_positional_error_message(format!("Address {} out of stack bounds", instruction.arg as i64 + i as i64).as_str(), instruction.line, instruction.col);
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(_) => {
-                                    logkit::exit_with_positional_error_message("Error reading input.", instruction.line, instruction.col);
-                                }
-                            }
-
-                            self.pc += 1
-                        }
+    /// Whether M[address] was written by the data section or the program since the last reset.
+    pub fn is_initialized(&self, address: usize) -> bool {
+        self.initialized.get(address).copied().unwrap_or(false)
+    }
+
+    /// Calls not returned yet, the innermost last.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    /// Names of the functions being executed, from the innermost to `main`.
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut backtrace = self.call_stack.iter().rev()
+            .map(|frame| self.program.function_name(frame.target_pc))
+            .collect::<Vec<String>>();
+        backtrace.push("main".to_string());
+        backtrace
+    }
+
+    /// Adds the backtrace, when a function is being executed, and the path of the file of an error raised by the current instruction.
+    fn add_error_context(&self, error: IguanaError) -> IguanaError {
+        let error = match self.call_stack.is_empty() {
+            true => error,
+            false => error.with_backtrace(self.backtrace()),
+        };
+        error.with_file_paths(self.program.source_map.file_paths())
+    }
+
+    /// Instructions executed since the last reset, including the one that trapped.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.is_halted
+    }
+
+    /// Instruction that will be executed next, `None` once the program ran past its end.
+    pub fn current_instruction(&self) -> Option<Instruction> {
+        self.program.instructions.get(self.pc as usize).copied()
+    }
+
+    /// Source line of the instruction that will be executed next.
+    pub fn current_line(&self) -> Option<u32> {
+        self.program.source_map.location(self.pc).map(|location| location.line)
+    }
+
+    pub fn io_device(&self) -> &D {
+        &self.io_device
+    }
+
+    pub fn io_device_mut(&mut self) -> &mut D {
+        &mut self.io_device
+    }
+
+    /// Exit code chosen by the program through `EXIT X` or `HALTAC`, `EXIT_SUCCESS` otherwise.
+    pub fn exit_code(&self) -> i32 {
+        self.exit_code
+    }
+
+    /// Executes the instruction at `pc`.
+    fn execute(&mut self, instruction: Instruction) -> Result<StepOutcome, IguanaError> {
+        match instruction.opcode {
+            Opcode::Lodd => {
+                self.ac = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Stod => {
+                self.write_memory(instruction.arg as i64, self.ac, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Addd => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_add(value), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Subd => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_sub(value), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Jpos => {
+                let target_instruction_pc = self.get_target_pc(&instruction)?;
+                if self.ac > 0 {
+                    self.pc = target_instruction_pc;
+                } else {
+                    self.pc += 1;
+                }
+            },
+            Opcode::Jzer => {
+                let target_instruction_pc = self.get_target_pc(&instruction)?;
+                if self.ac == 0 {
+                    self.pc = target_instruction_pc;
+                } else {
+                    self.pc += 1;
+                }
+            },
+            Opcode::Jump => {
+                self.pc = self.get_target_pc(&instruction)?;
+            },
+            Opcode::Loco => {
+                self.ac = instruction.arg;
+                self.pc += 1;
+            },
+            Opcode::Lodl => {
+                self.ac = self.read_memory(self.sp as i64 + instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Stol => {
+                self.write_memory(self.sp as i64 + instruction.arg as i64, self.ac, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Addl => {
+                let value = self.read_memory(self.sp as i64 + instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_add(value), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Subl => {
+                let value = self.read_memory(self.sp as i64 + instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_sub(value), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Jneg => {
+                let target_instruction_pc = self.get_target_pc(&instruction)?;
+                if self.ac < 0 {
+                    self.pc = target_instruction_pc;
+                } else {
+                    self.pc += 1;
+                }
+            },
+            Opcode::Jnze => {
+                let target_instruction_pc = self.get_target_pc(&instruction)?;
+                if self.ac != 0 {
+                    self.pc = target_instruction_pc;
+                } else {
+                    self.pc += 1;
+                }
+            },
+            Opcode::Call => {
+                let target_instruction_pc = self.get_target_pc(&instruction)?;
+                self.decrement_sp(&instruction)?;
+
+                let next_pc = self.pc + 1;
+                if next_pc >= i16::MAX as u32 {
+                    return Err(runtime_error(RuntimeErrorKind::PcOutOfBounds, "PC out of bounds for insertion in stack", &instruction));
+                }
+                self.write_memory(self.sp as i64, next_pc as i16, &instruction)?;
+
+                self.call_stack.push(CallFrame {
+                    call_pc: self.pc,
+                    target_pc: target_instruction_pc,
+                    sp_at_entry: self.sp,
+                });
+                self.pc = target_instruction_pc;
+            },
+            Opcode::Pshi => {
+                self.decrement_sp(&instruction)?;
+                let value = self.read_memory(self.ac as i64, &instruction)?;
+                self.write_memory(self.sp as i64, value, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Popi => {
+                let value = self.read_memory(self.sp as i64, &instruction)?;
+                self.write_memory(self.ac as i64, value, &instruction)?;
+                self.increment_sp(&instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Push => {
+                self.decrement_sp(&instruction)?;
+                self.write_memory(self.sp as i64, self.ac, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Pop => {
+                self.ac = self.read_memory(self.sp as i64, &instruction)?;
+                self.increment_sp(&instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Retn => {
+                let return_address = self.read_memory(self.sp as i64, &instruction)?;
+                if let Some(frame) = self.call_stack.last().filter(|_| self.checks_return_addresses) {
+                    if return_address as i64 != frame.call_pc as i64 + 1 {
+                        return Err(self.return_address_mismatch_error(return_address, frame, &instruction));
+                    }
+                }
+                self.pc = return_address as u32;
+                self.increment_sp(&instruction)?;
+
+                if let Some(frame) = self.call_stack.pop() {
+                    self.history.record_popped_frame(frame);
+                }
+            },
+            Opcode::Swap => {
+                if self.ac < 0 {
+                    return Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Expected a positive value in AC to swap with SP", &instruction));
+                }
+                std::mem::swap(&mut self.ac, &mut self.sp);
+                self.pc += 1;
+            },
+            Opcode::Desp => {
+                match self.sp.checked_sub(instruction.arg) {
+                    Some(aux) if aux >= 0 => {
+                        self.sp = aux;
+                    },
+                    _ => {
+                        return Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Stack pointer out of bounds", &instruction));
                     }
-                    
-                    if self.sp < 0 {
-                        logkit::exit_with_positional_error_message("Stack pointer out of bounds", instruction.line, instruction.col);
+                }
+                self.pc += 1;
+            },
+            Opcode::Insp => {
+                match self.sp.checked_add(instruction.arg) {
+                    Some(aux) if aux >= 0 => {
+                        self.sp = aux;
+                    },
+                    _ => {
+                        return Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Stack pointer out of bounds", &instruction));
                     }
-                },
-                None => {
-                    break;
                 }
+                self.pc += 1;
+            },
+
+            /*
+             * APARTIR DAQUI, SÃO OPERAÇÕES QUE EU CRIEI
+             */
+            Opcode::Halt => {
+                return Ok(StepOutcome::Halted);
+            },
+            Opcode::Exit => {
+                self.exit_code = instruction.arg as i32;
+                return Ok(StepOutcome::Halted);
+            },
+            Opcode::Haltac => {
+                self.exit_code = self.ac as i32;
+                return Ok(StepOutcome::Halted);
+            },
+
+            /*
+             *  DEBUG PRINTS
+             */
+            Opcode::Printlnac => {
+                self.write_output(format!("{}\n", self.ac).as_str(), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Printac => {
+                self.write_output(format!("{}", self.ac).as_str(), &instruction)?;
+                self.pc += 1;
             }
-        }
-        println!();
-        io::stdout().flush().unwrap();
-    }
 
+            Opcode::Printlnacchar => {
+                self.write_output(format!("{}\n", self.ac as u8 as char).as_str(), &instruction)?;
+                self.pc += 1;
+            }
+            Opcode::Printacchar => {
+                self.write_output(format!("{}", self.ac as u8 as char).as_str(), &instruction)?;
+                self.pc += 1;
+            }
 
-    fn generate_binary(&mut self, is_data_memory_initialized: bool) {
-        match File::create( self.output_path.clone() ) {
-            Ok(mut output_file) => {
+            Opcode::Printsp => {
+                self.write_output(format!("{}", self.sp).as_str(), &instruction)?;
+                self.pc += 1;
+            },
 
-                match output_file.write(".text\n".as_bytes()) {
-                    Ok(_) => {},
+            Opcode::Printlnsp => {
+                self.write_output(format!("{}\n", self.sp).as_str(), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Printinstruction => {
+                let target_instruction = self.get_target_instruction(&instruction)?;
+                self.write_output(target_instruction.to_format().as_str(), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Printlninstruction => {
+                let target_instruction = self.get_target_instruction(&instruction)?;
+                self.write_output(format!("{}\n", target_instruction.to_format()).as_str(), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Andi => {
+                self.ac &= instruction.arg;
+                self.pc += 1;
+            },
+            Opcode::Ori => {
+                self.ac |= instruction.arg;
+                self.pc += 1;
+            },
+            Opcode::Xori => {
+                self.ac ^= instruction.arg;
+                self.pc += 1;
+            },
+            Opcode::Not => {
+                self.ac = !self.ac;
+                self.pc += 1;
+            },
+            Opcode::Shfli => {
+                self.ac = checked_shift(self.ac.checked_shl(instruction.arg as u32), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Shfri => {
+                self.ac = checked_shift(self.ac.checked_shr(instruction.arg as u32), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Andd => {
+                self.ac &= self.read_memory(instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Ord => {
+                self.ac |= self.read_memory(instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Xord => {
+                self.ac ^= self.read_memory(instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Notd => {
+                self.ac = !self.read_memory(instruction.arg as i64, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Shfrd => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_shift(self.ac.checked_shr(value as u32), &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Shfld => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_shift(self.ac.checked_shl(value as u32), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Muld => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_mul(value), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Divd => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.ac = checked_division(self.ac, value, &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Mull => {
+                let value = self.read_memory(self.sp as i64 + instruction.arg as i64, &instruction)?;
+                self.ac = checked_value(self.ac.checked_mul(value), &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Divl => {
+                let value = self.read_memory(self.sp as i64 + instruction.arg as i64, &instruction)?;
+                self.ac = checked_division(self.ac, value, &instruction)?;
+                self.pc += 1;
+            },
+
+            Opcode::Sleepd => {
+                let value = self.read_memory(instruction.arg as i64, &instruction)?;
+                self.sleep(value, &instruction)?;
+                self.pc += 1;
+            },
+            Opcode::Sleepi => {
+                self.sleep(instruction.arg, &instruction)?;
+                self.pc += 1;
+            }
+
+            Opcode::Inputac => {
+                let input = match self.read_input_line(&instruction)? {
+                    Some(input) => input,
+                    None => return Ok(StepOutcome::WaitingForInput),
+                };
+                match input.trim().parse::<i16>() {
+                    Ok(value) => {
+                        self.ac = value;
+                    },
                     Err(_) => {
-                        logkit::exit_with_error_message("Error writing in the output file.");
+                        return Err(runtime_error(RuntimeErrorKind::InvalidInput, "Invalid input. Expected a valid 16 bits number.", &instruction));
                     }
                 }
 
-                for instr in self.memory.iter() {
-                    let instr_in_binary = instr.to_format();
-                    match output_file.write( format!( "{}\n", instr_in_binary ).as_bytes() ) {
-                        Ok(_) => {},
-                        Err(_) => {
-                            logkit::exit_with_error_message("Error writing in the output file.");
-                        }
-                    }
-                }
+                self.pc += 1;
+            }
 
-                match output_file.write(".data\n".as_bytes()) {
-                    Ok(_) => {},
+            Opcode::Inputacchar => {
+                match self.io_device.read_char() {
+                    Ok(Some(c)) => {
+                        self.ac = c as i16;
+                    },
+                    Ok(None) => {
+                        return Err(runtime_error(RuntimeErrorKind::InvalidInput, "Invalid input. Expected a single character.", &instruction));
+                    },
+                    Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                        return Ok(StepOutcome::WaitingForInput);
+                    },
                     Err(_) => {
-                        logkit::exit_with_error_message("Error writing in the output file.");
+                        return Err(runtime_error(RuntimeErrorKind::Io, "Error reading input.", &instruction));
                     }
                 }
 
-                if is_data_memory_initialized {
-                    let mut i = STACK_SIZE-1;
-                    while i >= self.sp as usize {
-                        let number_in_binary = format!("{:016b}", self.stack[i as usize]);
-                        match output_file.write( format!( "{}\n", number_in_binary ).as_bytes() ) {
-                            Ok(_) => {},
-                            Err(_) => {
-                                logkit::exit_with_error_message("Error writing in the output file.");
-                            }
-                        }
-                        i -= 1;
-                    }
+                self.pc += 1;
+            }
+
+            Opcode::Inputstring => {
+                let input = match self.read_input_line(&instruction)? {
+                    Some(input) => input,
+                    None => return Ok(StepOutcome::WaitingForInput),
+                };
+                let mut input_values_vector: Vec<i16> = input.trim().chars().map(|ch| ch as i16).collect();
+                input_values_vector.push(0);
+                for (i, ch) in input_values_vector.iter().enumerate() {
+                    self.write_memory(instruction.arg as i64 - i as i64, *ch, &instruction)?;
                 }
 
-                
+                self.pc += 1
+            }
+        }
+
+        if self.sp < 0 {
+            return Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Stack pointer out of bounds", &instruction));
+        }
+        Ok(StepOutcome::Continued)
+    }
+
 
+    /// Reads one line of input, `None` when the device has no input available yet.
+    fn read_input_line(&mut self, instruction: &Instruction) -> Result<Option<String>, IguanaError> {
+        match self.io_device.read_line() {
+            Ok(input) => Ok(Some(input)),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(_) => Err(runtime_error(RuntimeErrorKind::Io, "Error reading input.", instruction)),
+        }
+    }
+
+    /// Writes the output, or the part of it that fits in the output limit.
+    fn write_output(&mut self, text: &str, instruction: &Instruction) -> Result<(), IguanaError> {
+        let allowed_bytes = match self.limits.max_output_bytes {
+            Some(max_output_bytes) => max_output_bytes.saturating_sub(self.usage.output_bytes).min(text.len() as u64) as usize,
+            None => text.len(),
+        };
+        let mut end = allowed_bytes;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        if self.io_device.write_text(&text[..end]).is_err() {
+            return Err(runtime_error(RuntimeErrorKind::Io, "Error writing output.", instruction));
+        }
+        self.usage.output_bytes += end as u64;
+        if end < text.len() {
+            let message = format!("Output limit of {} bytes exceeded", self.limits.max_output_bytes.unwrap_or(0));
+            return Err(limit_error(LimitKind::Output, &message, instruction));
+        }
+        Ok(())
+    }
+
+    /// Sleeps for SLEEPD and SLEEPI, never past the sleep limit nor the timeout.
+    fn sleep(&mut self, milliseconds: i16, instruction: &Instruction) -> Result<(), IguanaError> {
+        if milliseconds < 0 {
+            return Err(runtime_error(RuntimeErrorKind::InvalidOperand, "Sleep time cannot be negative", instruction));
+        }
+        let duration = Duration::from_millis(milliseconds as u64);
+
+        if let Some(max_sleep) = self.limits.max_sleep {
+            if self.usage.sleep + duration > max_sleep {
+                let message = format!("Sleep limit of {} ms exceeded", max_sleep.as_millis());
+                return Err(limit_error(LimitKind::Sleep, &message, instruction));
+            }
+        }
+        if let Some(timeout) = self.limits.timeout {
+            let time_left = timeout.saturating_sub(self.usage.elapsed());
+            if duration > time_left {
+                std::thread::sleep(time_left);
+                return Err(timeout_error(timeout, instruction));
+            }
+        }
+
+        std::thread::sleep(duration);
+        self.usage.sleep += duration;
+        Ok(())
+    }
+
+    /// Error when the state after the instruction at `executed_pc` was already seen, reading input starts over.
+    fn detect_loop(&mut self, executed_pc: u32, instruction: &Instruction) -> Option<IguanaError> {
+        let loop_detector = self.loop_detector.as_mut()?;
+        if matches!(instruction.opcode, Opcode::Inputac | Opcode::Inputacchar | Opcode::Inputstring) {
+            loop_detector.restart(&self.stack);
+            return None;
+        }
+        let detected = loop_detector.check(executed_pc, self.pc, self.ac, self.sp, &self.stack, &self.call_stack)?;
+
+        let line = |pc: u32| self.program.source_map.location(pc).map_or(0, |location| location.line);
+        let message = format!(
+            "Infinite loop detected: the program comes back to the same state every {} instructions without reading input, between lines {} and {}",
+            detected.period,
+            line(detected.first_pc),
+            line(detected.last_pc),
+        );
+        Some(runtime_error(RuntimeErrorKind::InfiniteLoop, &message, instruction))
+    }
+
+    fn restart_loop_detector(&mut self) {
+        if let Some(loop_detector) = self.loop_detector.as_mut() {
+            loop_detector.restart(&self.stack);
+        }
+    }
+
+    /// Checked before every instruction.
+    fn check_limits(&self, instruction: &Instruction) -> Result<(), IguanaError> {
+        if let Some(max_steps) = self.limits.max_steps {
+            if self.instruction_count >= max_steps {
+                let message = format!("Step limit of {} instructions exceeded", max_steps);
+                return Err(limit_error(LimitKind::Steps, &message, instruction));
             }
-            Err(_) => {
-                logkit::exit_with_error_message("Error creating the output file.");
+        }
+        if let Some(timeout) = self.limits.timeout {
+            if self.usage.elapsed() > timeout {
+                return Err(timeout_error(timeout, instruction));
             }
         }
+        Ok(())
     }
 
     fn get_stack_value(&self, address: i64) -> Option<i16> {
@@ -1590,100 +917,542 @@ impl VirtualMachine {
         }
     }
 
-    fn get_closest_instruction_index_by_line(&self, line: u32) -> Option<u32> {
-        let mut closest_option = None;
-        for (index, instruction) in self.memory.iter().enumerate() {
-            if instruction.line >= line {
-                closest_option = Some(index as u32);
-                break;
+    /// Reads M[address], failing with the position of `instruction` when the address is out of the stack.
+    fn read_memory(&mut self, address: i64, instruction: &Instruction) -> Result<i16, IguanaError> {
+        let value = match self.get_stack_value(address) {
+            Some(value) => value,
+            None => return Err(runtime_error(RuntimeErrorKind::AddressOutOfBounds, format!("Address {} out of stack bounds", address).as_str(), instruction)),
+        };
+        if self.uninitialized_read_check != UninitializedReadCheck::Off && !self.initialized[address as usize] {
+            self.check_uninitialized_read(address, instruction)?;
+        }
+        if !self.watchpoints.is_empty() {
+            self.record_watch_hits(address as u32, value, value, false, instruction);
+        }
+        Ok(value)
+    }
+
+    fn check_uninitialized_read(&mut self, address: i64, instruction: &Instruction) -> Result<(), IguanaError> {
+        let error = runtime_error(
+            RuntimeErrorKind::UninitializedRead,
+            format!("Read of uninitialized memory at address {}", address).as_str(),
+            instruction,
+        );
+        if self.uninitialized_read_check == UninitializedReadCheck::Fatal {
+            return Err(error);
+        }
+        if self.first_uninitialized_read.is_none() {
+            self.first_uninitialized_read = Some(error.with_file_paths(self.program.source_map.file_paths()));
+        }
+        Ok(())
+    }
+
+    /// Writes M[address], failing with the position of `instruction` when the address is out of the stack.
+    fn write_memory(&mut self, address: i64, value: i16, instruction: &Instruction) -> Result<(), IguanaError> {
+        if let Some(old_value) = self.get_stack_value(address) {
+            if !self.watchpoints.is_empty() {
+                self.record_watch_hits(address as u32, old_value, value, true, instruction);
+            }
+            if let Some(loop_detector) = self.loop_detector.as_mut() {
+                loop_detector.record_write(address as usize, old_value, value);
+            }
+            if self.history.is_enabled() {
+                self.history.record_write(UndoWrite {
+                    address: address as u32,
+                    old_value,
+                    was_initialized: self.initialized[address as usize],
+                });
+            }
+        }
+        match self.set_stack_value(address, value) {
+            Ok(_) => {
+                self.initialized[address as usize] = true;
+                Ok(())
+            },
+            Err(_) => Err(runtime_error(RuntimeErrorKind::AddressOutOfBounds, format!("Address {} out of stack bounds", address).as_str(), instruction)),
+        }
+    }
+
+    fn record_watch_hits(&mut self, address: u32, old_value: i16, new_value: i16, is_write: bool, instruction: &Instruction) {
+        for watchpoint in self.watchpoints.iter().filter(|watchpoint| watchpoint.is_triggered_by(address, is_write)) {
+            self.watch_hits.push(WatchHit {
+                watchpoint_id: watchpoint.id,
+                address,
+                old_value,
+                new_value,
+                is_write,
+                file: instruction.file,
+                line: instruction.line,
+                col: instruction.col,
+            });
+        }
+    }
+
+    fn decrement_sp(&mut self, instruction: &Instruction) -> Result<(), IguanaError> {
+        match self.sp.checked_sub(1) {
+            Some(aux) => {
+                self.sp = aux;
+                Ok(())
+            },
+            None => Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Stack pointer out of bounds", instruction)),
+        }
+    }
+
+    fn increment_sp(&mut self, instruction: &Instruction) -> Result<(), IguanaError> {
+        match self.sp.checked_add(1) {
+            Some(aux) => {
+                self.sp = aux;
+                Ok(())
+            },
+            None => Err(runtime_error(RuntimeErrorKind::StackPointerOutOfBounds, "Stack pointer out of bounds", instruction)),
+        }
+    }
+
+    /// RETN found a return address that is not the one pushed by the CALL of the current function.
+    fn return_address_mismatch_error(&self, return_address: i16, frame: &CallFrame, instruction: &Instruction) -> IguanaError {
+        let source_map = &self.program.source_map;
+        let describe = |pc: i64| match u32::try_from(pc).ok().and_then(|pc| source_map.location(pc)) {
+            Some(location) => format!("instruction {} (line {})", pc, location.line),
+            None => format!("instruction {}", pc),
+        };
+        let mut message = format!(
+            "RETN would return to {}, but {} was called by the CALL at line {}, which returns to {}.",
+            describe(return_address as i64),
+            self.program.function_name(frame.target_pc),
+            source_map.location(frame.call_pc).map_or(0, |location| location.line),
+            describe(frame.call_pc as i64 + 1),
+        );
+        if self.sp != frame.sp_at_entry {
+            message.push_str(format!(" The return address was pushed at M[{}], but sp is {}.", frame.sp_at_entry, self.sp).as_str());
+        }
+        runtime_error(RuntimeErrorKind::ReturnAddressMismatch, &message, instruction)
+    }
+
+    /// Instruction index that a control flow instruction points to.
+    fn get_target_pc(&self, instruction: &Instruction) -> Result<u32, IguanaError> {
+        let target_instruction_pc = self.pc as i64 + instruction.arg as i64;
+        if target_instruction_pc < 0 {
+            return Err(runtime_error(RuntimeErrorKind::PcOutOfBounds, "Expected a positive pc value", instruction));
+        }
+        Ok(target_instruction_pc as u32)
+    }
+
+    fn get_target_instruction(&self, instruction: &Instruction) -> Result<Instruction, IguanaError> {
+        let target_instruction_pc = self.get_target_pc(instruction)?;
+        match self.program.instructions.get(target_instruction_pc as usize) {
+            Some(target_instruction) => Ok(*target_instruction),
+            None => {
+                Err(runtime_error(
+                    RuntimeErrorKind::PcOutOfBounds,
+                    format!("Instruction at line {} not found or out of bounds.", instruction.arg).as_str(),
+                    instruction,
+                ))
             }
         }
-        closest_option
     }
 
 }
 
-fn get_nth_token(raw_tokens: &Vec<Token>, n: usize) -> Option<Token> {
-    raw_tokens.get(n).cloned()
+fn runtime_error(kind: RuntimeErrorKind, message: &str, instruction: &Instruction) -> IguanaError {
+    IguanaError::runtime(kind, message, instruction.line, instruction.col).in_file(instruction.file)
 }
 
-fn get_comma_separated_values(vector: &Vec<Token>, offset: usize, is_dot_byte: bool) -> Vec<i16> {
-    let mut values = Vec::new();
-    let mut aux_value_counter = offset;
-    'aux_value_counter_loop: while aux_value_counter < vector.len() {
-        let aux_raw_token_option = vector.get(aux_value_counter).cloned();
-        match aux_raw_token_option {
-            Some(aux_raw_token) => {
-                match aux_raw_token.get_token().as_str() {
-                    "," => {
-                        aux_value_counter += 1;
-                    },
-                    _ => {
-                        
-                        match aux_raw_token.to_i16_value() {
-                            Some(value) => {
-                                if is_dot_byte {
-                                    if value < 0 || value > 255 {
-                                        logkit::exit_with_positional_error_message("Value out of range (0...255)", aux_raw_token.line, aux_raw_token.col);
-                                    }
-                                }
-                                values.push(value);
-                                aux_value_counter += 1;
-                            },
-                            None => {
-                                if vector.get(aux_value_counter - 1).unwrap().get_token().as_str() == "," {
-                                    if is_dot_byte {
-                                        logkit::exit_with_positional_error_message("Expected a valid value in range of 0...255", aux_raw_token.line, aux_raw_token.col);
-                                    } else {
-                                        logkit::exit_with_positional_error_message("Expected a valid value in range of -32768...32767", aux_raw_token.line, aux_raw_token.col);
-                                    }
-                                } else {
-                                    break 'aux_value_counter_loop;
-                                }
-                                break 'aux_value_counter_loop;
-                            }
-                        }
+fn checked_value(value_option: Option<i16>, instruction: &Instruction) -> Result<i16, IguanaError> {
+    match value_option {
+        Some(value) => Ok(value),
+        None => Err(runtime_error(RuntimeErrorKind::Overflow, "Value range exceeded (-32768...32767)", instruction)),
+    }
+}
 
-                    }
-                }
-            },
-            None => { break 'aux_value_counter_loop; },
-        }
+fn checked_shift(value_option: Option<i16>, instruction: &Instruction) -> Result<i16, IguanaError> {
+    match value_option {
+        Some(value) => Ok(value),
+        None => Err(runtime_error(RuntimeErrorKind::Overflow, "Shift amount out of range (0...15)", instruction)),
     }
-    
-    values
 }
 
-fn get_next_closest_instruction_line_by_token_counter(raw_tokens: &Vec<Token>, offset: usize) -> Option<u32> {
-    let mut found_line: Option<u32> = None;
-    let mut section = Section::Text;
+fn checked_division(dividend: i16, divisor: i16, instruction: &Instruction) -> Result<i16, IguanaError> {
+    if divisor == 0 {
+        return Err(runtime_error(RuntimeErrorKind::DivisionByZero, "Division by zero is not allowed.", instruction));
+    }
+    checked_value(dividend.checked_div(divisor), instruction)
+}
 
-    for i in offset..raw_tokens.len() {
-        let actual_token = raw_tokens.get(i).unwrap();
-        match actual_token.get_token().as_str() {
-            ".data" => {
-                section = Section::Data;
-                continue;
-            }
-            ".text" => {
-                section = Section::Text;
-                continue;
-            }
-            _ => {
-                match section {
-                    Section::Text => {
-                        if Opcode::from_str(actual_token.get_token().as_str()).is_some() {
-                            found_line = Some(actual_token.line);
-                            break;
-                        }
-                    }
-                    _ => {
-                        continue;
-                    }
-                }
-            }
+fn limit_error(kind: LimitKind, message: &str, instruction: &Instruction) -> IguanaError {
+    IguanaError::limit_exceeded(kind, message, instruction.line, instruction.col).in_file(instruction.file)
+}
+
+fn timeout_error(timeout: Duration, instruction: &Instruction) -> IguanaError {
+    limit_error(LimitKind::Timeout, format!("Time limit of {} ms exceeded", timeout.as_millis()).as_str(), instruction)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+    use crate::interpreter::error::ErrorKind;
+    use crate::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+    use crate::interpreter::io_device::BufferDevice;
+
+    fn create_vm(source: &str) -> VirtualMachine<BufferDevice> {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+        VirtualMachine::with_io_device(&program, BufferDevice::new(""))
+    }
+
+    #[test]
+    fn programs_choose_their_exit_code_with_exit_and_haltac() {
+        let mut vm = create_vm("    LOCO 7\n    HALT\n");
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), EXIT_SUCCESS);
+
+        let mut vm = create_vm("    EXIT 42\n    EXIT 1\n");
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), 42);
+        assert!(vm.is_halted());
+
+        let mut vm = create_vm("    LOCO 0\n    SUBD ONE\n    HALTAC\n.data\n    ONE: .word 1\n");
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), -1);
+
+        vm.reset();
+        assert_eq!(vm.exit_code(), EXIT_SUCCESS);
+    }
+
+    #[test]
+    fn input_and_output_go_through_the_io_device() {
+        let source = "
+.data
+    BUFFER: .space 8
+.text
+    INPUTAC
+    PRINTLNAC
+    INPUTACCHAR
+    PRINTACCHAR
+    INPUTSTRING BUFFER
+    LODD BUFFER-1
+    PRINTACCHAR
+";
+        let program = Assembler::new(InterpreterMode::Execute).assemble(source).unwrap();
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new("-12\nz\nok"));
+        vm.run().unwrap();
+        assert_eq!(vm.io_device().output(), "-12\nzk\n");
+        assert_eq!((0..3).map(|i| vm.stack()[Program::data_address(0) - i]).collect::<Vec<i16>>(), [b'o' as i16, b'k' as i16, 0]);
+    }
+
+    #[test]
+    fn step_executes_one_instruction_at_a_time() {
+        let mut vm = create_vm("    LOCO 5\n    PUSH\n    HALT\n");
+        assert_eq!((vm.pc(), vm.current_line()), (0, Some(1)));
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert_eq!((vm.ac(), vm.pc(), vm.instruction_count()), (5, 1, 1));
+        let sp = vm.sp();
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert_eq!(vm.sp(), sp - 1);
+        assert_eq!(vm.stack()[vm.sp() as usize], 5);
+        assert_eq!(vm.step(), StepOutcome::Halted);
+        assert!(vm.is_halted());
+        assert_eq!(vm.step(), StepOutcome::Halted);
+        assert_eq!(vm.instruction_count(), 3);
+    }
+
+    #[test]
+    fn step_waits_for_input_without_executing() {
+        let mut vm = create_vm("    INPUTAC\n    HALTAC\n");
+        assert_eq!(vm.step(), StepOutcome::WaitingForInput);
+        assert_eq!((vm.pc(), vm.instruction_count()), (0, 0));
+
+        vm.io_device_mut().push_input("9");
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert_eq!(vm.step(), StepOutcome::Halted);
+        assert_eq!(vm.exit_code(), 9);
+    }
+
+    #[test]
+    fn step_traps_with_the_error_and_run_fails_without_input() {
+        let mut vm = create_vm("    LOCO 1\n    INPUTAC\n");
+        assert_eq!(vm.run().unwrap_err().kind, ErrorKind::Runtime(RuntimeErrorKind::Io));
+
+        let mut vm = create_vm("    INPUTAC\n");
+        vm.io_device_mut().push_input("abc");
+        match vm.step() {
+            StepOutcome::Trapped(error) => assert_eq!(error.kind, ErrorKind::Runtime(RuntimeErrorKind::InvalidInput)),
+            outcome => panic!("expected a trap, got {:?}", outcome),
         }
     }
-    
-    found_line
-}
\ No newline at end of file
+
+    #[test]
+    fn the_end_of_the_program_halts() {
+        let mut vm = create_vm("    LOCO 1\n");
+        assert_eq!(vm.step(), StepOutcome::Halted);
+        assert_eq!(vm.current_instruction(), None);
+    }
+
+    const UNINITIALIZED_READS: &str = "
+.data
+    X: .word 1
+    Y: .space 2
+.text
+    LODD X
+    STOD Y
+    LODD Y
+    LODD Y-1
+    LODD Y-1
+    HALT
+";
+
+    #[test]
+    fn uninitialized_reads_are_ignored_by_default() {
+        let mut vm = create_vm(UNINITIALIZED_READS);
+        vm.run().unwrap();
+        assert!(vm.first_uninitialized_read().is_none());
+    }
+
+    #[test]
+    fn uninitialized_read_warning_keeps_the_first_read() {
+        let mut vm = create_vm(UNINITIALIZED_READS);
+        vm.set_uninitialized_read_check(UninitializedReadCheck::Warn);
+        vm.run().unwrap();
+        let warning = vm.first_uninitialized_read().unwrap();
+        assert_eq!(warning.kind, ErrorKind::Runtime(RuntimeErrorKind::UninitializedRead));
+        assert_eq!(warning.line, 9);
+        assert!(vm.is_initialized(Program::data_address(1)));
+        assert!(!vm.is_initialized(Program::data_address(2)));
+
+        vm.reset();
+        assert!(vm.first_uninitialized_read().is_none());
+        assert!(!vm.is_initialized(Program::data_address(1)));
+    }
+
+    #[test]
+    fn fatal_uninitialized_read_stops_the_program() {
+        let mut vm = create_vm(UNINITIALIZED_READS);
+        vm.set_uninitialized_read_check(UninitializedReadCheck::Fatal);
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime(RuntimeErrorKind::UninitializedRead));
+        assert_eq!(error.line, 9);
+
+        let mut vm = create_vm("    LOCO 3\n    PUSH\n    POP\n    POP\n");
+        vm.set_uninitialized_read_check(UninitializedReadCheck::Fatal);
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert_eq!(vm.step(), StepOutcome::Continued); // pops the pushed word
+        assert!(matches!(vm.step(), StepOutcome::Trapped(error) if error.kind == ErrorKind::Runtime(RuntimeErrorKind::UninitializedRead)));
+    }
+
+    #[test]
+    fn watchpoints_record_writes_and_accesses_in_order() {
+        let source = "
+.data
+    COUNT: .word 5
+    OTHER: .word 0
+.text
+    LODD COUNT
+    ADDD COUNT
+    STOD COUNT
+    STOD OTHER
+";
+        let mut vm = create_vm(source);
+        let write = vm.add_watchpoint(32767, 32767, WatchKind::Write);
+        let access = vm.add_watchpoint(32766, 32767, WatchKind::Access);
+        vm.run().unwrap();
+        let hits = vm.take_watch_hits();
+        let summary = hits.iter().map(|hit| (hit.watchpoint_id, hit.address, hit.old_value, hit.new_value, hit.is_write, hit.line)).collect::<Vec<_>>();
+        assert_eq!(summary, [
+            (access, 32767, 5, 5, false, 6),
+            (access, 32767, 5, 5, false, 7),
+            (write, 32767, 5, 10, true, 8),
+            (access, 32767, 5, 10, true, 8),
+            (access, 32766, 0, 10, true, 9),
+        ]);
+        assert!(vm.watch_hits().is_empty());
+
+        assert!(vm.remove_watchpoint(access));
+        assert!(!vm.remove_watchpoint(access));
+        vm.reset();
+        vm.run().unwrap();
+        assert_eq!(vm.take_watch_hits().len(), 1);
+    }
+
+    const JUMP_TABLE: &str = "
+.data
+    HANDLERS: .word ON_HELLO, ON_BYE
+.text
+    CALL DISPATCH
+    HALT
+DISPATCH:
+    LODD HANDLERS-1
+    PUSH
+    RETN
+ON_HELLO:
+    LOCO 1
+    HALTAC
+ON_BYE:
+    LOCO 2
+    HALTAC
+";
+
+    #[test]
+    fn step_limit_stops_before_the_next_instruction() {
+        let mut vm = create_vm("LOOP:\n    JUMP LOOP\n");
+        vm.set_execution_limits(ExecutionLimits::default().with_max_steps(10));
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::LimitExceeded(LimitKind::Steps));
+        assert_eq!(error.message, "Step limit of 10 instructions exceeded");
+        assert_eq!(vm.instruction_count(), 10);
+    }
+
+    #[test]
+    fn timeout_stops_running_and_sleeping_programs() {
+        let mut vm = create_vm("LOOP:\n    JUMP LOOP\n");
+        vm.set_execution_limits(ExecutionLimits::default().with_timeout(Duration::from_millis(20)));
+        assert_eq!(vm.run().unwrap_err().kind, ErrorKind::LimitExceeded(LimitKind::Timeout));
+
+        let mut vm = create_vm("    SLEEPI 10000\n");
+        vm.set_execution_limits(ExecutionLimits::default().with_timeout(Duration::from_millis(20)));
+        assert_eq!(vm.run().unwrap_err().kind, ErrorKind::LimitExceeded(LimitKind::Timeout));
+        assert!(vm.resource_usage().elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn sleep_limit_counts_every_sleep() {
+        let mut vm = create_vm("    SLEEPI 5\n    SLEEPI 5\n    SLEEPI 5\n");
+        vm.set_execution_limits(ExecutionLimits::default().with_max_sleep(Duration::from_millis(12)));
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::LimitExceeded(LimitKind::Sleep));
+        assert_eq!(error.line, 3);
+        assert_eq!(vm.resource_usage().sleep, Duration::from_millis(10));
+    }
+
+    #[test]
+    fn output_limit_truncates_at_a_char_boundary() {
+        // 'A' is 1 byte and 'é' (233) is 2 bytes in UTF-8
+        let source = "    LOCO 65\n    PRINTACCHAR\n    LOCO 233\n    PRINTACCHAR\n    HALT\n";
+        let mut vm = create_vm(source);
+        vm.set_execution_limits(ExecutionLimits::default().with_max_output_bytes(2));
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::LimitExceeded(LimitKind::Output));
+        assert_eq!(error.line, 4);
+        assert_eq!(vm.io_device().output(), "A");
+        assert_eq!(vm.resource_usage().output_bytes, 1);
+
+        let mut vm = create_vm(source);
+        vm.set_execution_limits(ExecutionLimits::default().with_max_output_bytes(3));
+        vm.run().unwrap();
+        assert_eq!(vm.io_device().output(), "Aé\n"); // run ends the output with a new line
+    }
+
+    const WAIT_FOR_FLAG: &str = "
+.data
+    FLAG: .word 0
+.text
+LOOP:
+    LODD FLAG
+    JZER LOOP
+    HALT
+";
+
+    #[test]
+    fn loop_detection_stops_a_program_repeating_the_same_state() {
+        let mut vm = create_vm(WAIT_FOR_FLAG);
+        vm.set_loop_detection(true);
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime(RuntimeErrorKind::InfiniteLoop));
+        assert_eq!(
+            error.message,
+            "Infinite loop detected: the program comes back to the same state every 2 instructions without reading input, between lines 6 and 7",
+        );
+
+        // a loop changing memory is not infinite
+        let mut vm = create_vm(".data\n    X: .word 0\n    ONE: .word 1\n.text\nLOOP:\n    LODD X\n    ADDD ONE\n    STOD X\n    JUMP LOOP\n");
+        vm.set_loop_detection(true);
+        vm.set_execution_limits(ExecutionLimits::default().with_max_steps(1000));
+        assert_eq!(vm.run().unwrap_err().kind, ErrorKind::LimitExceeded(LimitKind::Steps));
+    }
+
+    #[test]
+    fn loop_detection_starts_over_after_reading_input() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble("LOOP:\n    INPUTAC\n    JZER LOOP\n    HALT\n").unwrap();
+        let mut vm = VirtualMachine::with_io_device(&program, BufferDevice::new("0\n0\n0\n0\n0\n0\n1\n"));
+        vm.set_loop_detection(true);
+        vm.run().unwrap();
+        assert_eq!(vm.ac(), 1);
+    }
+
+    #[test]
+    fn loop_detection_starts_over_after_changes_from_a_debugger() {
+        let mut vm = create_vm(WAIT_FOR_FLAG);
+        vm.set_loop_detection(true);
+        vm.step();
+        vm.set_stack_word(Program::data_address(0), 1);
+        vm.run().unwrap();
+        assert!(vm.is_halted());
+
+        // going back to a state before the checkpoint and executing it again is not a loop
+        let mut vm = create_vm("    LOCO 1\n    LOCO 2\n    HALT\n");
+        vm.set_history_limits(10, DEFAULT_HISTORY_MEMORY_BUDGET);
+        vm.set_loop_detection(true);
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        assert!(vm.reverse_step());
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        vm.set_pc(0);
+        assert_eq!(vm.step(), StepOutcome::Continued);
+        vm.run().unwrap();
+    }
+
+    #[test]
+    fn the_call_stack_follows_call_and_retn() {
+        let mut vm = create_vm("    CALL OUTER\n    HALT\nOUTER:\n    CALL INNER\n    RETN\nINNER:\n    LOCO 1\n    RETN\n");
+        assert!(vm.call_stack().is_empty());
+        assert_eq!(vm.backtrace(), ["main"]);
+
+        vm.step();
+        let outer_sp = vm.sp();
+        vm.step();
+        assert_eq!(vm.call_stack(), [
+            CallFrame { call_pc: 0, target_pc: 2, sp_at_entry: outer_sp },
+            CallFrame { call_pc: 2, target_pc: 4, sp_at_entry: vm.sp() },
+        ]);
+        assert_eq!(vm.stack()[vm.sp() as usize], 3); // return address of INNER
+        assert_eq!(vm.backtrace(), ["INNER", "OUTER", "main"]);
+
+        vm.step();
+        vm.step();
+        assert_eq!(vm.call_stack(), [CallFrame { call_pc: 0, target_pc: 2, sp_at_entry: outer_sp }]);
+        assert_eq!(vm.backtrace(), ["OUTER", "main"]);
+
+        vm.run().unwrap();
+        assert!(vm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn retn_to_a_pushed_address_ends_the_call_by_default() {
+        let mut vm = create_vm(JUMP_TABLE);
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), 2);
+        assert!(vm.call_stack().is_empty());
+    }
+
+    #[test]
+    fn return_address_check_stops_retn_to_another_address() {
+        let mut vm = create_vm(JUMP_TABLE);
+        vm.set_return_address_check(true);
+        let error = vm.run().unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime(RuntimeErrorKind::ReturnAddressMismatch));
+        assert_eq!(error.line, 10);
+        assert_eq!(error.backtrace, ["DISPATCH", "main"]);
+
+        let mut vm = create_vm("    CALL FN\n    LOCO 3\n    HALTAC\nFN:\n    RETN\n");
+        vm.set_return_address_check(true);
+        vm.run().unwrap();
+        assert_eq!(vm.exit_code(), 3);
+    }
+
+    #[test]
+    fn errors_have_a_backtrace_only_inside_calls() {
+        let error = create_vm(".data\n    ZERO: .word 0\n.text\n    DIVD ZERO\n").run().unwrap_err();
+        assert!(error.backtrace_text().is_none());
+
+        let error = create_vm(".data\n    ZERO: .word 0\n.text\n    CALL OUTER\nOUTER:\n    CALL INNER\nINNER:\n    DIVD ZERO\n").run().unwrap_err();
+        assert_eq!(error.backtrace_text().unwrap(), "INNER <- OUTER <- main");
+    }
+}
@@ -0,0 +1,84 @@
+use super::program::Program;
+
+/// Which accesses to the watched addresses are reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Access, // reads and writes
+}
+
+/// Watches the stack addresses from `first_address` to `last_address`, both included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub id: u32,
+    pub first_address: u32,
+    pub last_address: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    pub fn is_triggered_by(&self, address: u32, is_write: bool) -> bool {
+        (self.first_address..=self.last_address).contains(&address)
+            && (is_write || self.kind == WatchKind::Access)
+    }
+}
+
+/// A watched address accessed by the instruction at `line` and `col`. Reads have `old_value == new_value`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint_id: u32,
+    pub address: u32,
+    pub old_value: i16,
+    pub new_value: i16,
+    pub is_write: bool,
+    pub file: u32, // of the instruction, as in `Instruction::file`
+    pub line: u32,
+    pub col: u32,
+}
+
+impl WatchHit {
+    /// e.g. `Watchpoint 1: M[32767] (COUNT) 5 -> 6`
+    pub fn describe(&self, program: &Program) -> String {
+        let name = match program.describe_address(self.address) {
+            Some(name) => format!(" ({})", name),
+            None => String::new(),
+        };
+        if self.is_write {
+            format!("Watchpoint {}: M[{}]{} {} -> {}", self.watchpoint_id, self.address, name, self.old_value, self.new_value)
+        } else {
+            format!("Watchpoint {}: M[{}]{} read {}", self.watchpoint_id, self.address, name, self.old_value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::assembler::{Assembler, InterpreterMode};
+
+    #[test]
+    fn write_watchpoints_ignore_reads_and_ranges_include_both_ends() {
+        let write = Watchpoint { id: 1, first_address: 10, last_address: 12, kind: WatchKind::Write };
+        assert!(write.is_triggered_by(10, true));
+        assert!(write.is_triggered_by(12, true));
+        assert!(!write.is_triggered_by(11, false));
+        assert!(!write.is_triggered_by(13, true));
+
+        let access = Watchpoint { kind: WatchKind::Access, ..write };
+        assert!(access.is_triggered_by(11, false));
+        assert!(!access.is_triggered_by(9, false));
+    }
+
+    #[test]
+    fn hits_are_described_with_the_data_label() {
+        let program = Assembler::new(InterpreterMode::Execute).assemble(".data\n    COUNT: .word 5\n    BUFFER: .space 4\n.text\n    HALT\n").unwrap();
+        let hit = WatchHit { watchpoint_id: 1, address: 32767, old_value: 5, new_value: 6, is_write: true, file: 0, line: 1, col: 1 };
+        assert_eq!(hit.describe(&program), "Watchpoint 1: M[32767] (COUNT) 5 -> 6");
+        let hit = WatchHit { address: 32765, is_write: false, ..hit };
+        assert_eq!(hit.describe(&program), "Watchpoint 1: M[32765] (BUFFER-1) read 5");
+        assert_eq!(program.resolve_address_range("BUFFER"), Some((32765, 32766)));
+        assert_eq!(program.resolve_address_range("32767..32765"), Some((32765, 32767)));
+        assert_eq!(program.resolve_address_range("0x10"), Some((16, 16)));
+        assert_eq!(program.resolve_address_range("MISSING"), None);
+    }
+}
@@ -1,2 +1,5 @@
+pub mod analysis;
+pub mod debugger;
 pub mod interpreter;
-pub mod logkit;
\ No newline at end of file
+pub mod logkit;
+pub mod lsp;
\ No newline at end of file
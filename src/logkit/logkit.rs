@@ -1,4 +1,5 @@
 use colored::Colorize;
+use crate::interpreter::error::{position_text, IguanaError};
 use supports_color::Stream;
 
 const INTERPRETER_NAME : &str = "IGUANA";
@@ -30,14 +31,64 @@ fn error_piece() -> String {
     }
 }
 
-fn line_col_piece(line: u32, col: u32) -> String {
-    let line_col_piece = {
-        if col == 0 {
-            format!("[LINE: {}]", line)
+fn warning_piece() -> String {
+    let warning_piece = "[WARNING]";
+    if let Some(color_level) = supports_color::on(Stream::Stdout) {
+        if color_level.has_16m {
+            warning_piece.bold().yellow().to_string()
+        } else {
+            warning_piece.to_string()
+        }
+    } else {
+        warning_piece.to_string()
+    }
+}
+
+fn watch_piece() -> String {
+    let watch_piece = "[WATCH]";
+    if let Some(color_level) = supports_color::on(Stream::Stdout) {
+        if color_level.has_16m {
+            watch_piece.bold().magenta().to_string()
+        } else {
+            watch_piece.to_string()
+        }
+    } else {
+        watch_piece.to_string()
+    }
+}
+
+fn backtrace_piece() -> String {
+    let backtrace_piece = "[BACKTRACE]";
+    if let Some(color_level) = supports_color::on(Stream::Stdout) {
+        if color_level.has_16m {
+            backtrace_piece.bold().cyan().to_string()
         } else {
-            format!("[LINE: {}, COL: {}]", line, col)
+            backtrace_piece.to_string()
         }
-    };
+    } else {
+        backtrace_piece.to_string()
+    }
+}
+
+fn note_piece() -> String {
+    let note_piece = "[NOTE]";
+    if let Some(color_level) = supports_color::on(Stream::Stdout) {
+        if color_level.has_16m {
+            note_piece.bold().cyan().to_string()
+        } else {
+            note_piece.to_string()
+        }
+    } else {
+        note_piece.to_string()
+    }
+}
+
+fn line_col_piece(line: u32, col: u32) -> String {
+    position_piece(None, line, col)
+}
+
+fn position_piece(file_path: Option<&str>, line: u32, col: u32) -> String {
+    let line_col_piece = position_text(file_path, line, col);
     if let Some(color_level) = supports_color::on(Stream::Stdout) {
         if color_level.has_16m {
             line_col_piece.bold().cyan().to_string()
@@ -59,34 +110,90 @@ pub fn message(message: &str) {
 }
 
 
-pub fn exit_with_positional_error_message(message: &str, line: u32, col: u32) {  
+pub fn positional_error_message(message: &str, line: u32, col: u32) {
     println!(
         "\n{} {} {} {}",
-        interpreter_name_piece(), 
+        interpreter_name_piece(),
         error_piece(),
-        message, 
+        message,
         line_col_piece(line, col),
     );
-
-    std::process::exit(0);
 }
 
 
-pub fn exit_with_error_message(message: &str) {
+pub fn error_message(message: &str) {
     println!(
         "\n{} {} {}",
         interpreter_name_piece(),
         error_piece(),
         message
-    ); 
-    std::process::exit(0);
+    );
 }
 
-pub fn message_wrong_program_arguments() {
+pub fn iguana_error_message(error: &IguanaError) {
+    if error.is_positional() {
+        println!(
+            "\n{} {} {} {}",
+            interpreter_name_piece(),
+            error_piece(),
+            error.message,
+            position_piece(error.file_path.as_deref(), error.line, error.col),
+        );
+    } else {
+        error_message(&error.message);
+    }
+    if let Some(related) = &error.related {
+        println!(
+            "{} {} {} {}",
+            interpreter_name_piece(),
+            note_piece(),
+            related.message,
+            position_piece(related.file_path.as_deref(), related.line, related.col),
+        );
+    }
+    if let Some(backtrace) = error.backtrace_text() {
+        println!(
+            "{} {} {}",
+            interpreter_name_piece(),
+            backtrace_piece(),
+            backtrace
+        );
+    }
+}
+
+pub fn iguana_warning_message(warning: &IguanaError) {
+    if warning.is_positional() {
+        println!(
+            "\n{} {} {} {}",
+            interpreter_name_piece(),
+            warning_piece(),
+            warning.message,
+            position_piece(warning.file_path.as_deref(), warning.line, warning.col),
+        );
+    } else {
+        println!(
+            "\n{} {} {}",
+            interpreter_name_piece(),
+            warning_piece(),
+            warning.message
+        );
+    }
+}
+
+/// `file_path` is `None` for the assembled file, as in `SourceMap::file_path`.
+pub fn watch_message(message: &str, file_path: Option<&str>, line: u32, col: u32) {
     println!(
-        "{} {}",
+        "\n{} {} {} {}",
         interpreter_name_piece(),
-        "Invalid arguments. Usage: 'info' or 'run <file>' or 'binary <file> <output>'."
+        watch_piece(),
+        message,
+        position_piece(file_path, line, col),
     );
-    std::process::exit(0);
-}
\ No newline at end of file
+}
+
+pub fn wrong_program_arguments_message() {
+    println!(
+        "{} Invalid arguments. Usage: 'info' or 'run <file> [options]' or 'debug <file> [options]' or 'profile <file> [options]' or 'gdbserver <[host]:port> <file> [options]' or 'dap' or 'lsp' or 'binary <file> <output> [options]'.",
+        interpreter_name_piece(),
+    );
+}
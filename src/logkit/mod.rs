@@ -1,2 +1,3 @@
+#[allow(clippy::module_inception)]
 pub mod logkit;
 pub use logkit::*;
\ No newline at end of file
@@ -0,0 +1,2 @@
+pub mod server;
+pub mod symbols;
\ No newline at end of file
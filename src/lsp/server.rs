@@ -0,0 +1,431 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::debugger::dap::{read_message, write_message};
+use crate::interpreter::assembler::{Assembler, InterpreterMode};
+use crate::interpreter::error::{position_text, IguanaError};
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::program::Program;
+use super::symbols::{LabelSection, SourceIndex, Word, WordKind};
+
+const DIRECTIVES: [&str; 16] = [
+    ".data", ".text", ".word", ".byte", ".ascii", ".asciiz", ".space", ".equ", ".macro", ".endm", ".include",
+    ".if", ".ifdef", ".ifndef", ".else", ".endif",
+];
+
+// JSON-RPC and LSP enumerations
+const METHOD_NOT_FOUND: i64 = -32601;
+const SEVERITY_ERROR: i64 = 1;
+const COMPLETION_FUNCTION: i64 = 3;
+const COMPLETION_VARIABLE: i64 = 6;
+const COMPLETION_KEYWORD: i64 = 14;
+const SYMBOL_FUNCTION: i64 = 12;
+const SYMBOL_VARIABLE: i64 = 13;
+
+/// An open file, assembled again on every change.
+struct Document {
+    source: String,
+    index: SourceIndex,
+    program: Result<Program, IguanaError>,
+}
+
+impl Document {
+    /// The `.include`s of the document are found relative to its path.
+    fn new(uri: &str, source: String) -> Document {
+        let path = uri.strip_prefix("file://").unwrap_or("");
+        Document {
+            index: SourceIndex::new(&source),
+            program: Assembler::new(InterpreterMode::Execute).assemble_at(&source, path),
+            source,
+        }
+    }
+}
+
+/// Language Server Protocol server for MAC assembly, reading from `reader` and writing to `writer`.
+pub struct LspServer<R: BufRead, W: Write> {
+    reader: R,
+    writer: W,
+    documents: HashMap<String, Document>,
+}
+
+impl<R: BufRead, W: Write> LspServer<R, W> {
+    pub fn new(reader: R, writer: W) -> LspServer<R, W> {
+        LspServer {
+            reader,
+            writer,
+            documents: HashMap::new(),
+        }
+    }
+
+    /// Serves the editor until the `exit` notification or the end of the input.
+    pub fn run(&mut self) -> io::Result<()> {
+        while let Some(message) = read_message(&mut self.reader)? {
+            let method = message["method"].as_str().unwrap_or("").to_string();
+            let params = &message["params"];
+            if method == "exit" {
+                break;
+            }
+
+            match message.get("id") {
+                Some(id) => {
+                    let response = match self.handle_request(&method, params) {
+                        Some(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                        None => json!({
+                            "jsonrpc": "2.0",
+                            "id": id,
+                            "error": { "code": METHOD_NOT_FOUND, "message": format!("Unsupported method '{}'.", method) },
+                        }),
+                    };
+                    write_message(&mut self.writer, &response)?;
+                },
+                None => self.handle_notification(&method, params)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Result of a request, `None` when the method is not supported.
+    fn handle_request(&self, method: &str, params: &Value) -> Option<Value> {
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1, // the whole text is sent on every change
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "completionProvider": {},
+                    "documentSymbolProvider": true,
+                },
+                "serverInfo": { "name": "iguana", "version": env!("CARGO_PKG_VERSION") },
+            }),
+            "shutdown" => Value::Null,
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/completion" => self.completion(params),
+            "textDocument/documentSymbol" => self.document_symbols(params),
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Value) -> io::Result<()> {
+        let uri = params["textDocument"]["uri"].as_str().unwrap_or("").to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let source = params["textDocument"]["text"].as_str().unwrap_or("").replace('\r', "");
+                self.documents.insert(uri.clone(), Document::new(&uri, source));
+                self.publish_diagnostics(&uri)
+            },
+            "textDocument/didChange" => {
+                // full synchronization, the last change has the whole text
+                let changes = params["contentChanges"].as_array();
+                if let Some(source) = changes.and_then(|changes| changes.last()).and_then(|change| change["text"].as_str()) {
+                    self.documents.insert(uri.clone(), Document::new(&uri, source.replace('\r', "")));
+                }
+                self.publish_diagnostics(&uri)
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// The assembler stops at the first error, so there is at most one diagnostic.
+    fn publish_diagnostics(&mut self, uri: &str) -> io::Result<()> {
+        let diagnostics = match self.documents.get(uri) {
+            Some(Document { program: Err(error), source, index }) if error.is_positional() => {
+                let location = |file_path: Option<&str>, line: u32, col: u32| match file_path {
+                    Some(path) => json!({ "uri": file_uri(path), "range": position_range(line, col) }),
+                    None => json!({ "uri": uri, "range": error_range(line, col, source, index) }),
+                };
+                // e.g. the line of the macro an instruction was expanded from
+                let mut related_information = error.related.iter().map(|related| json!({
+                    "location": location(related.file_path.as_deref(), related.line, related.col),
+                    "message": related.message,
+                })).collect::<Vec<Value>>();
+
+                // an error in an included file is shown at the first .include of the document
+                let (range, message) = match error.file_path.as_deref() {
+                    Some(path) => {
+                        related_information.insert(0, json!({ "location": location(Some(path), error.line, error.col), "message": error.message }));
+                        let include = index.words().iter().find(|word| word.text == ".include");
+                        let message = format!("{} {}", error.message, position_text(Some(path), error.line, error.col));
+                        (include.map_or(position_range(1, 0), range), message)
+                    },
+                    None => (error_range(error.line, error.col, source, index), error.message.clone()),
+                };
+                vec![json!({
+                    "range": range,
+                    "severity": SEVERITY_ERROR,
+                    "source": "iguana",
+                    "message": message,
+                    "relatedInformation": related_information,
+                })]
+            },
+            _ => Vec::new(),
+        };
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        });
+        write_message(&mut self.writer, &notification)
+    }
+
+    /// Document and word under the cursor of a positional request.
+    fn word_at<'a>(&'a self, params: &'a Value) -> Option<(&'a str, &'a Document, &'a Word)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let document = self.documents.get(uri)?;
+        let line = params["position"]["line"].as_u64()? as u32 + 1;
+        let col = params["position"]["character"].as_u64()? as u32 + 1;
+        Some((uri, document, document.index.word_at(line, col)?))
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let definition = self.word_at(params).and_then(|(uri, document, word)| {
+            Some((uri, document.index.definition(&word.label_name()?)?))
+        });
+        match definition {
+            Some((uri, definition)) => location(uri, definition),
+            None => Value::Null,
+        }
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let (uri, document, word) = match self.word_at(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let name = match word.label_name() {
+            Some(name) => name,
+            None => return Value::Null,
+        };
+
+        let mut locations = Vec::new();
+        if params["context"]["includeDeclaration"].as_bool().unwrap_or(true) {
+            locations.extend(document.index.definition(&name).map(|definition| location(uri, definition)));
+        }
+        locations.extend(document.index.references(&name).map(|reference| location(uri, reference)));
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (_, document, word) = match self.word_at(params) {
+            Some(found) => found,
+            None => return Value::Null,
+        };
+        let text = match word.kind {
+            WordKind::Mnemonic(opcode) => {
+                let operand = if Opcode::is_argumented(opcode) { " X" } else { "" };
+                format!("**{}{}**\n\n`{}`", opcode, operand, Opcode::pseudo_behavior(opcode))
+            },
+            WordKind::LabelDefinition(_) | WordKind::LabelReference => {
+                let name = word.label_name().unwrap_or_default();
+                match describe_label(&name, document) {
+                    Some(text) => text,
+                    None => return Value::Null,
+                }
+            },
+            WordKind::Other => return Value::Null,
+        };
+        json!({ "contents": { "kind": "markdown", "value": text }, "range": range(word) })
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        let mut items = Opcode::ALL.iter().map(|&opcode| json!({
+            "label": opcode.to_string(),
+            "kind": COMPLETION_KEYWORD,
+            "detail": Opcode::pseudo_behavior(opcode),
+        })).collect::<Vec<Value>>();
+        items.extend(DIRECTIVES.iter().map(|directive| json!({ "label": directive, "kind": COMPLETION_KEYWORD })));
+
+        let document = params["textDocument"]["uri"].as_str().and_then(|uri| self.documents.get(uri));
+        if let Some(document) = document {
+            items.extend(document.index.definitions().map(|definition| {
+                let (kind, detail) = match definition.kind {
+                    WordKind::LabelDefinition(LabelSection::Data) => (COMPLETION_VARIABLE, "data label"),
+                    _ => (COMPLETION_FUNCTION, "text label"),
+                };
+                json!({ "label": definition.label_name(), "kind": kind, "detail": detail })
+            }));
+        }
+        json!(items)
+    }
+
+    fn document_symbols(&self, params: &Value) -> Value {
+        let document = match params["textDocument"]["uri"].as_str().and_then(|uri| self.documents.get(uri)) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        let symbols = document.index.definitions().map(|definition| {
+            let (kind, detail) = match definition.kind {
+                WordKind::LabelDefinition(LabelSection::Data) => (SYMBOL_VARIABLE, ".data"),
+                _ => (SYMBOL_FUNCTION, ".text"),
+            };
+            json!({
+                "name": definition.label_name(),
+                "detail": detail,
+                "kind": kind,
+                "range": range(definition),
+                "selectionRange": range(definition),
+            })
+        }).collect::<Vec<Value>>();
+        json!(symbols)
+    }
+}
+
+/// What the label stands for, from the symbol table of the assembled program, or its section when the file has errors.
+fn describe_label(name: &str, document: &Document) -> Option<String> {
+    let definition = document.index.definition(name)?;
+    let program = document.program.as_ref().ok();
+
+    let text = match definition.kind {
+        WordKind::LabelDefinition(LabelSection::Data) => match program.and_then(|program| program.data_symbol(name)) {
+            Some(symbol) if symbol.words == 1 => format!("**{}**: data label, `M[{}]`", name, symbol.address),
+            Some(symbol) => format!("**{}**: data label, `M[{}..{}]` ({} words)", name, symbol.address, symbol.last_address(), symbol.words),
+            None => format!("**{}**: data label, line {}", name, definition.line),
+        },
+        _ => match program.and_then(|program| program.text_label_line(name)) {
+            Some(line) => format!("**{}**: text label, instruction at line {}", name, line),
+            None => format!("**{}**: text label, line {}", name, definition.line),
+        },
+    };
+    Some(text)
+}
+
+/// The word at an error position, or the whole line when the column is unknown.
+fn error_range(line: u32, col: u32, source: &str, index: &SourceIndex) -> Value {
+    let word = index.words().iter().find(|word| word.line == line && (col == 0 || word.col == col));
+    match (word, col) {
+        (Some(word), col) if col != 0 => range(word),
+        _ => {
+            let line_text = source.lines().nth(line as usize - 1).unwrap_or("");
+            let first = line_text.chars().take_while(|c| c.is_whitespace()).count();
+            let last = line_text.trim_end().chars().count().max(first);
+            json!({
+                "start": { "line": line - 1, "character": first },
+                "end": { "line": line - 1, "character": last },
+            })
+        },
+    }
+}
+
+/// An empty range at a position of a file that is not open.
+fn position_range(line: u32, col: u32) -> Value {
+    let position = json!({ "line": line.saturating_sub(1), "character": col.saturating_sub(1) });
+    json!({ "start": position, "end": position })
+}
+
+fn file_uri(path: &str) -> String {
+    let path = std::fs::canonicalize(path).map_or(path.to_string(), |path| path.display().to_string());
+    format!("file://{}", path)
+}
+
+fn range(word: &Word) -> Value {
+    json!({
+        "start": { "line": word.line - 1, "character": word.col - 1 },
+        "end": { "line": word.line - 1, "character": word.col - 1 + word.length },
+    })
+}
+
+fn location(uri: &str, word: &Word) -> Value {
+    json!({ "uri": uri, "range": range(word) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const URI: &str = "file:///iguana/test.asm";
+
+    const SOURCE: &str = ".data
+    X: .word 5
+.text
+    LODD X
+    JUMP END
+END:
+    HALT
+";
+
+    /// Sends `messages` to a server and returns everything it writes back.
+    fn serve(messages: &[Value]) -> Vec<Value> {
+        let mut input = Vec::new();
+        for message in messages {
+            let mut message = message.clone();
+            message["jsonrpc"] = json!("2.0");
+            write_message(&mut input, &message).unwrap();
+        }
+        let mut output = Vec::new();
+        LspServer::new(Cursor::new(input), &mut output).run().unwrap();
+
+        let mut reader = Cursor::new(output);
+        let mut replies = Vec::new();
+        while let Some(reply) = read_message(&mut reader).unwrap() {
+            replies.push(reply);
+        }
+        replies
+    }
+
+    fn open(text: &str) -> Value {
+        json!({ "method": "textDocument/didOpen", "params": { "textDocument": { "uri": URI, "languageId": "mac", "version": 1, "text": text } } })
+    }
+
+    fn at(id: i64, method: &str, line: u32, character: u32) -> Value {
+        json!({ "id": id, "method": method, "params": { "textDocument": { "uri": URI }, "position": { "line": line, "character": character } } })
+    }
+
+    #[test]
+    fn answers_requests_about_an_open_document() {
+        let replies = serve(&[
+            json!({ "id": 1, "method": "initialize", "params": {} }),
+            open(SOURCE),
+            at(2, "textDocument/definition", 3, 9),
+            at(3, "textDocument/hover", 3, 9),
+            at(4, "textDocument/references", 5, 1),
+            json!({ "id": 5, "method": "textDocument/formatting", "params": {} }),
+            json!({ "id": 6, "method": "shutdown" }),
+            json!({ "method": "exit" }),
+        ]);
+        assert_eq!(replies.len(), 7);
+        assert_eq!(replies[0]["id"], 1);
+        assert_eq!(replies[0]["result"]["capabilities"]["definitionProvider"], true);
+        assert_eq!(replies[1]["method"], "textDocument/publishDiagnostics");
+        assert_eq!(replies[1]["params"]["diagnostics"], json!([]));
+
+        assert_eq!(replies[2]["id"], 2);
+        assert_eq!(replies[2]["result"]["uri"], URI);
+        assert_eq!(replies[2]["result"]["range"]["start"], json!({ "line": 1, "character": 4 }));
+        assert_eq!(replies[3]["result"]["contents"]["value"], "**X**: data label, `M[32767]`");
+        let reference_lines = replies[4]["result"].as_array().unwrap().iter()
+            .map(|location| location["range"]["start"]["line"].as_u64().unwrap())
+            .collect::<Vec<u64>>();
+        assert_eq!(reference_lines, [5, 4]);
+
+        assert_eq!(replies[5]["id"], 5);
+        assert_eq!(replies[5]["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(replies[6], json!({ "jsonrpc": "2.0", "id": 6, "result": null }));
+    }
+
+    #[test]
+    fn publishes_the_assembler_error_until_it_is_fixed() {
+        let replies = serve(&[
+            open(".text\n    LODD Y\n"),
+            json!({ "method": "textDocument/didChange", "params": { "textDocument": { "uri": URI }, "contentChanges": [{ "text": SOURCE }] } }),
+        ]);
+        assert_eq!(replies.len(), 2);
+        let diagnostics = replies[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["severity"], SEVERITY_ERROR);
+        assert_eq!(diagnostics[0]["range"]["start"]["line"], 1);
+        assert_eq!(diagnostics[0]["range"]["start"]["character"], 9);
+        assert_eq!(diagnostics[0]["message"], "Expected a label or a valid value in range of (-32768...32767) after instruction");
+        assert_eq!(replies[1]["params"]["diagnostics"], json!([]));
+    }
+}
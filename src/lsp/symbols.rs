@@ -0,0 +1,139 @@
+use crate::interpreter::opcode::Opcode;
+use crate::interpreter::tokenizer;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LabelSection {
+    Data,
+    Text,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordKind {
+    Mnemonic(Opcode),
+    LabelDefinition(LabelSection),
+    LabelReference,
+    Other,
+}
+
+/// A token of the source with its real position, `line` and `col` start at 1 and `col` counts characters.
+/// The tokenizer reports the column 0 on indented lines, so the positions are looked up again in the line text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Word {
+    pub text: String,
+    pub kind: WordKind,
+    pub scope: String, // last global label before the word, which local labels like `.loop` belong to
+    pub line: u32,
+    pub col: u32,
+    pub length: u32,
+}
+
+impl Word {
+    /// Full name of the label defined or referenced by the word, e.g. `FIBONACCI.loop` for `.loop`.
+    pub fn label_name(&self) -> Option<String> {
+        let name = match self.kind {
+            WordKind::LabelDefinition(_) => self.text.strip_suffix(':')?,
+            WordKind::LabelReference => &self.text,
+            _ => return None,
+        };
+        match name.starts_with('.') {
+            true => Some(format!("{}{}", self.scope, name)),
+            false => Some(name.to_string()),
+        }
+    }
+
+    pub fn contains(&self, line: u32, col: u32) -> bool {
+        self.line == line && col >= self.col && col <= self.col + self.length
+    }
+}
+
+/// Words of a source text, indexed to answer the editor's questions without assembling it,
+/// so that the labels can be found even while the file has errors.
+pub struct SourceIndex {
+    words: Vec<Word>,
+}
+
+impl SourceIndex {
+    pub fn new(source: &str) -> SourceIndex {
+        let lines = source.lines().collect::<Vec<&str>>();
+        let mut words = Vec::new();
+        let mut section = LabelSection::Text;
+        let mut line_cursor = (0, 0); // (line, char index after the last word found on it)
+        let mut scope = tokenizer::MAIN_SCOPE.to_string();
+
+        for token in tokenizer::tokenize(source) {
+            let text = token.get_token();
+            let kind = match text.as_str() {
+                ".data" => { section = LabelSection::Data; WordKind::Other },
+                ".text" => { section = LabelSection::Text; WordKind::Other },
+                _ if token.is_global_label() => {
+                    scope = text.trim_end_matches(':').to_string();
+                    WordKind::LabelDefinition(section)
+                },
+                _ if token.is_local_label() => WordKind::LabelDefinition(section),
+                _ => match Opcode::from_str(&text) {
+                    Some(opcode) => WordKind::Mnemonic(opcode),
+                    None => WordKind::Other,
+                },
+            };
+
+            if line_cursor.0 != token.line {
+                line_cursor = (token.line, 0);
+            }
+            let line_text = lines.get(token.line as usize - 1).copied().unwrap_or("");
+            let col = find_word(line_text, &text, line_cursor.1).unwrap_or(line_cursor.1);
+            let length = text.chars().count() as u32;
+            line_cursor.1 = col + length;
+
+            words.push(Word { text, kind, scope: scope.clone(), line: token.line, col: col + 1, length });
+        }
+
+        // as labels can be used before their definition, references are only known once every definition is
+        let labels = words.iter().filter_map(|word| word.label_name()).collect::<Vec<String>>();
+        for word in words.iter_mut().filter(|word| word.kind == WordKind::Other) {
+            let name = match word.text.starts_with('.') {
+                true => format!("{}{}", word.scope, word.text),
+                false => word.text.clone(),
+            };
+            if labels.contains(&name) {
+                word.kind = WordKind::LabelReference;
+            }
+        }
+
+        SourceIndex { words }
+    }
+
+    pub fn words(&self) -> &[Word] {
+        &self.words
+    }
+
+    pub fn word_at(&self, line: u32, col: u32) -> Option<&Word> {
+        self.words.iter().find(|word| word.contains(line, col))
+    }
+
+    pub fn definitions(&self) -> impl Iterator<Item = &Word> {
+        self.words.iter().filter(|word| matches!(word.kind, WordKind::LabelDefinition(_)))
+    }
+
+    pub fn definition(&self, name: &str) -> Option<&Word> {
+        self.definitions().find(|word| word.label_name().as_deref() == Some(name))
+    }
+
+    pub fn references<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Word> {
+        self.words.iter().filter(move |word| word.kind == WordKind::LabelReference && word.label_name().as_deref() == Some(name))
+    }
+}
+
+/// Character index of the first whole `word` in `line` at or after `start`.
+fn find_word(line: &str, word: &str, start: u32) -> Option<u32> {
+    let chars = line.chars().collect::<Vec<char>>();
+    let word = word.chars().collect::<Vec<char>>();
+    let is_boundary = |index: usize| index >= chars.len() || chars[index].is_whitespace() || chars[index] == ',';
+
+    (start as usize..chars.len().saturating_sub(word.len() - 1))
+        .find(|&index| {
+            chars[index..index + word.len()] == word[..]
+            && (index == 0 || is_boundary(index - 1))
+            && is_boundary(index + word.len())
+        })
+        .map(|index| index as u32)
+}
@@ -1,41 +1,307 @@
-use iguana::interpreter::virtual_machine::{InterpreterMode, VirtualMachine};
+use std::fs::File;
+use std::io::BufWriter;
+use std::time::Duration;
+
+use iguana::analysis::coverage::Coverage;
+use iguana::analysis::profile::Profiler;
+use iguana::analysis::trace::{TraceFormat, Tracer};
+use iguana::debugger::console::Console;
+use iguana::debugger::dap::DapServer;
+use iguana::debugger::gdbserver::GdbServer;
+use iguana::debugger::session::{DebugSession, DEFAULT_HISTORY_LENGTH};
+use iguana::interpreter::history::DEFAULT_HISTORY_MEMORY_BUDGET;
+use iguana::interpreter::limits::ExecutionLimits;
+use iguana::interpreter::error::{IguanaError, EXIT_USAGE};
+use iguana::interpreter::assembler::{Assembler, InterpreterMode};
+use iguana::interpreter::tokenizer;
+use iguana::interpreter::program::{MemoryFill, Program};
+use iguana::interpreter::virtual_machine::{UninitializedReadCheck, VirtualMachine};
+use iguana::interpreter::watchpoint::WatchKind;
 use iguana::logkit;
+use iguana::lsp::server::LspServer;
+
 
+/// Options given after the command, e.g. `iguana run <file> --seed 42`.
+#[derive(Default)]
+struct Options {
+    memory_fill: MemoryFill,
+    uninitialized_read_check: UninitializedReadCheck,
+    watches: Vec<(String, WatchKind)>, // data label, address or range
+    history_length: Option<usize>, // instructions recorded for reverse execution in the debugger
+    trace_path: Option<String>,
+    trace_format: Option<TraceFormat>,
+    trace_filters: Vec<String>, // lines, line ranges or labels
+    folded_path: Option<String>, // folded stacks written by the profiler
+    coverage_path: Option<String>, // lcov tracefile
+    limits: ExecutionLimits,
+    detect_loops: bool,
+    check_returns: bool,
+    symbols: Vec<(String, i16)>, // -D NAME or -D NAME=VALUE, seen by the conditional directives
+}
 
 fn main() {
-    let args = std::env::args().collect::<Vec<String>>();
-    
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let (args, options) = match parse_options(&args) {
+        Ok(parsed) => parsed,
+        Err(message) => {
+            logkit::error_message(&message);
+            logkit::wrong_program_arguments_message();
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+
     match args.len() {
-        1 => {
-            logkit::message_wrong_program_arguments();
-        },
-        2 => {
-            if args[1] == "info" {
-                logkit::message("Iguana MAC Interpreter");
-                logkit::message("Version: 1.6.1");
-                logkit::message("Developed by: github.com/joeCavZero");
-            } else {
-                logkit::message_wrong_program_arguments();
-            }
+        1 if args[0] == "info" => {
+            logkit::message("Iguana MAC Interpreter");
+            logkit::message("Version: 1.6.1");
+            logkit::message("Developed by: github.com/joeCavZero");
         },
-        3 => {
-            if args[1] == "run" {
-                let mut vm = VirtualMachine::new(&args[2], "out.txt");
-                vm.run(InterpreterMode::Execute);
-            } else {
-                logkit::message_wrong_program_arguments();
+        2 if args[0] == "run" => {
+            let program = exit_on_error(create_assembler(InterpreterMode::Execute, &options).assemble_file(&args[1]));
+            let mut vm = create_virtual_machine(&program, &options);
+            let mut tracer = options.trace_path.as_ref().map(|path| create_tracer(path, &program, &options));
+            let mut coverage = options.coverage_path.as_ref().map(|_| Coverage::new(&program));
+            if tracer.is_some() || coverage.is_some() {
+                vm.set_history_limits(1, DEFAULT_HISTORY_MEMORY_BUDGET);
+            }
+            let result = vm.run_with(|vm, _| {
+                if let Some(tracer) = tracer.as_mut() {
+                    tracer.record(vm);
+                }
+                if let Some(coverage) = coverage.as_mut() {
+                    coverage.record(vm);
+                }
+                for hit in vm.take_watch_hits() {
+                    logkit::watch_message(&hit.describe(vm.program()), vm.program().source_map.file_path(hit.file), hit.line, hit.col);
+                }
+            });
+            if let Some(mut tracer) = tracer {
+                exit_on_error(tracer.finish().map_err(|_| IguanaError::io("Error writing in the trace file.")));
             }
+            if let (Some(coverage), Some(path)) = (coverage, options.coverage_path.as_ref()) {
+                let source_path = std::fs::canonicalize(&args[1]).map_or(args[1].clone(), |path| path.display().to_string());
+                let mut file = exit_on_error(File::create(path).map_err(|_| IguanaError::io("Error creating the coverage file.")));
+                exit_on_error(coverage.write_lcov(&mut file, &source_path, &program).map_err(|_| IguanaError::io("Error writing in the coverage file.")));
+            }
+            if let Some(warning) = vm.first_uninitialized_read() {
+                logkit::iguana_warning_message(warning);
+            }
+            exit_on_error(result);
+            std::process::exit(vm.exit_code());
+        },
+        2 if args[0] == "debug" => {
+            let program = exit_on_error(create_assembler(InterpreterMode::Execute, &options).assemble_file(&args[1]));
+            let mut vm = create_virtual_machine(&program, &options);
+            vm.set_history_limits(options.history_length.unwrap_or(DEFAULT_HISTORY_LENGTH), DEFAULT_HISTORY_MEMORY_BUDGET);
+            Console::new(DebugSession::new(vm)).run();
         },
-        4 => {
-            if args[1] == "binary" {
-                let mut vm = VirtualMachine::new(&args[2], &args[3]);
-                vm.run(InterpreterMode::Binary);
-            } else {
-                logkit::message_wrong_program_arguments();
+        2 if args[0] == "profile" => {
+            let program = exit_on_error(create_assembler(InterpreterMode::Execute, &options).assemble_file(&args[1]));
+            let mut vm = create_virtual_machine(&program, &options);
+            vm.set_history_limits(1, DEFAULT_HISTORY_MEMORY_BUDGET);
+            let mut profiler = Profiler::new(&program);
+            let result = vm.run_with(|vm, _| profiler.record(vm));
+
+            exit_on_error(profiler.write_report(&mut std::io::stdout(), &program).map_err(|_| IguanaError::io("Error writing the profile.")));
+            if let Some(path) = options.folded_path.as_ref() {
+                let mut file = exit_on_error(File::create(path).map_err(|_| IguanaError::io("Error creating the folded stacks file.")));
+                exit_on_error(profiler.write_folded(&mut file).map_err(|_| IguanaError::io("Error writing in the folded stacks file.")));
             }
+            exit_on_error(result);
+            std::process::exit(vm.exit_code());
+        },
+        3 if args[0] == "gdbserver" => {
+            let address = match args[1].starts_with(':') {
+                true => format!("127.0.0.1{}", args[1]),
+                false => args[1].clone(),
+            };
+            let program = exit_on_error(create_assembler(InterpreterMode::Execute, &options).assemble_file(&args[2]));
+            let mut vm = create_virtual_machine(&program, &options);
+            vm.set_history_limits(options.history_length.unwrap_or(DEFAULT_HISTORY_LENGTH), DEFAULT_HISTORY_MEMORY_BUDGET);
+            let mut server = GdbServer::new(DebugSession::new(vm));
+            exit_on_error(server.listen(&address).map_err(|error| IguanaError::io(format!("GDB connection error: {}.", error).as_str())));
+        },
+        1 if args[0] == "dap" => {
+            let stdin = std::io::stdin();
+            let mut server = DapServer::new(stdin.lock(), std::io::stdout());
+            exit_on_error(server.run().map_err(|error| IguanaError::io(format!("DAP connection error: {}.", error).as_str())));
+        },
+        1 if args[0] == "lsp" => {
+            let stdin = std::io::stdin();
+            let mut server = LspServer::new(stdin.lock(), std::io::stdout());
+            exit_on_error(server.run().map_err(|error| IguanaError::io(format!("LSP connection error: {}.", error).as_str())));
+        },
+        3 if args[0] == "binary" => {
+            let program = exit_on_error(create_assembler(InterpreterMode::Binary, &options).assemble_file(&args[1]));
+            exit_on_error(program.write_binary(&args[2], options.memory_fill));
         },
         _ => {
-            logkit::message_wrong_program_arguments();
+            logkit::wrong_program_arguments_message();
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+}
+
+fn create_assembler(interpreter_mode: InterpreterMode, options: &Options) -> Assembler {
+    let mut assembler = Assembler::new(interpreter_mode);
+    for (name, value) in options.symbols.iter() {
+        assembler.define(name, *value);
+    }
+    assembler
+}
+
+/// Virtual machine for the `run` and `debug` commands, set up as the options say.
+fn create_virtual_machine(program: &Program, options: &Options) -> VirtualMachine {
+    let mut vm = VirtualMachine::new(program);
+    vm.set_memory_fill(options.memory_fill);
+    vm.set_uninitialized_read_check(options.uninitialized_read_check);
+    vm.set_execution_limits(options.limits);
+    vm.set_loop_detection(options.detect_loops);
+    vm.set_return_address_check(options.check_returns);
+    for (target, kind) in options.watches.iter() {
+        match program.resolve_address_range(target) {
+            Some((first_address, last_address)) => {
+                vm.add_watchpoint(first_address, last_address, *kind);
+            },
+            None => {
+                logkit::error_message(format!("Invalid watch '{}', expected a data label, an address or a range like 100..200.", target).as_str());
+                std::process::exit(EXIT_USAGE);
+            }
+        }
+    }
+    vm
+}
+
+fn create_tracer(path: &str, program: &Program, options: &Options) -> Tracer<BufWriter<File>> {
+    let file = exit_on_error(File::create(path).map_err(|_| IguanaError::io("Error creating the trace file.")));
+    let mut tracer = Tracer::new(BufWriter::new(file), options.trace_format.unwrap_or(TraceFormat::Text));
+    for filter in options.trace_filters.iter() {
+        if !tracer.add_filter(program, filter) {
+            logkit::error_message(format!("Invalid trace filter '{}', expected a line, a range like 10-20 or a label.", filter).as_str());
+            std::process::exit(EXIT_USAGE);
+        }
+    }
+    tracer.start();
+    tracer
+}
+
+/// Splits the arguments into the positional ones and the `--option value` ones.
+fn parse_options(args: &[String]) -> Result<(Vec<String>, Options), String> {
+    let mut positional = Vec::new();
+    let mut options = Options::default();
+    let mut fill: Option<String> = None;
+    let mut seed: Option<u64> = None;
+
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "-D" {
+            let value = iter.next().ok_or("Missing symbol for the '-D' option, e.g. '-D DEBUG' or '-D LEVEL=2'.".to_string())?;
+            options.symbols.push(parse_symbol(value)?);
+            continue;
+        }
+        if !arg.starts_with("--") {
+            positional.push(arg.clone());
+            continue;
+        }
+        let mut value = || iter.next().ok_or(format!("Missing value for the '{}' option.", arg));
+        match arg.as_str() {
+            "--fill" => fill = Some(value()?.clone()),
+            "--seed" => {
+                let value = value()?;
+                seed = Some(value.parse::<u64>().map_err(|_| format!("Invalid seed '{}', expected a non-negative integer.", value))?);
+            },
+            "--watch" => options.watches.push((value()?.clone(), WatchKind::Write)),
+            "--watch-access" => options.watches.push((value()?.clone(), WatchKind::Access)),
+            "--history" => {
+                let value = value()?;
+                options.history_length = Some(value.parse::<usize>().map_err(|_| format!("Invalid history length '{}', expected a non-negative integer.", value))?);
+            },
+            "--trace" => options.trace_path = Some(value()?.clone()),
+            "--trace-format" => {
+                let value = value()?;
+                options.trace_format = Some(TraceFormat::from_str(value).ok_or(format!("Invalid trace format '{}', expected 'text', 'jsonl' or 'csv'.", value))?);
+            },
+            "--trace-filter" => options.trace_filters.push(value()?.clone()),
+            "--coverage" => options.coverage_path = Some(value()?.clone()),
+            "--folded" => options.folded_path = Some(value()?.clone()),
+            "--max-steps" => {
+                let value = value()?;
+                let max_steps = value.parse::<u64>().map_err(|_| format!("Invalid step limit '{}', expected a non-negative integer.", value))?;
+                options.limits = options.limits.with_max_steps(max_steps);
+            },
+            "--timeout" => {
+                let value = value()?;
+                let timeout = parse_duration(value).ok_or(format!("Invalid timeout '{}', expected seconds like 2 or 0.5, or milliseconds like 500ms.", value))?;
+                options.limits = options.limits.with_timeout(timeout);
+            },
+            "--detect-loops" => options.detect_loops = true,
+            "--check-returns" => options.check_returns = true,
+            "--check-uninit" => {
+                options.uninitialized_read_check = match value()?.as_str() {
+                    "warn" => UninitializedReadCheck::Warn,
+                    "fatal" => UninitializedReadCheck::Fatal,
+                    other => return Err(format!("Invalid check '{}', expected 'warn' or 'fatal'.", other)),
+                };
+            },
+            _ => return Err(format!("Unknown option '{}'.", arg)),
+        }
+    }
+
+    options.memory_fill = match (fill.as_deref(), seed) {
+        (None, seed) | (Some("random"), seed) => MemoryFill::Random(seed),
+        (Some(_), Some(_)) => return Err("The '--seed' option only applies to the random fill.".to_string()),
+        (Some("zero"), None) => MemoryFill::Zero,
+        (Some(pattern), None) => match parse_word(pattern) {
+            Some(value) => MemoryFill::Pattern(value),
+            None => return Err(format!("Invalid fill '{}', expected 'zero', 'random' or a 16-bit value like 0xDEAD.", pattern)),
+        },
+    };
+
+    Ok((positional, options))
+}
+
+/// `NAME`, which is 1, or `NAME=VALUE`.
+fn parse_symbol(text: &str) -> Result<(String, i16), String> {
+    let (name, value) = match text.split_once('=') {
+        Some((name, value)) => (name, parse_word(value).ok_or(format!("Invalid value '{}' for the symbol '{}', expected a 16-bit value like 2 or 0x10.", value, name))?),
+        None => (text, 1),
+    };
+    if !tokenizer::is_valid_name(name) {
+        return Err(format!("Invalid symbol name '{}', names must start with a letter or '_' and contain only letters, digits or '_'.", name));
+    }
+    Ok((name.to_string(), value))
+}
+
+/// Accepts decimal, 0x hexadecimal and 0b binary words, unsigned words above 32767 wrap around like in the memory.
+fn parse_word(text: &str) -> Option<i16> {
+    let value = if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = text.strip_prefix("0b").or_else(|| text.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        text.parse::<i64>().ok()?
+    };
+    match value {
+        -32768..=65535 => Some(value as u16 as i16),
+        _ => None,
+    }
+}
+
+/// Seconds, e.g. `2` or `0.5`, or milliseconds with the `ms` suffix, e.g. `500ms`.
+fn parse_duration(text: &str) -> Option<Duration> {
+    if let Some(milliseconds) = text.strip_suffix("ms") {
+        return milliseconds.parse::<u64>().ok().map(Duration::from_millis);
+    }
+    let seconds = text.strip_suffix('s').unwrap_or(text).parse::<f64>().ok()?;
+    Duration::try_from_secs_f64(seconds).ok()
+}
+
+fn exit_on_error<T>(result: Result<T, IguanaError>) -> T {
+    match result {
+        Ok(value) => value,
+        Err(error) => {
+            logkit::iguana_error_message(&error);
+            std::process::exit(error.exit_code());
         }
     }
-}
\ No newline at end of file
+}